@@ -1,5 +1,5 @@
-use core::cell::RefCell;
 use crate::trap::{self, TrapContext};
+use core::ops::Range;
 
 const USER_STACK_SIZE: usize = 4096 * 2;
 const KERNEL_STACK_SIZE: usize = 4096 * 2;
@@ -37,16 +37,20 @@ impl UserStack {
 static KERNEL_STACK: KernelStack = KernelStack { data: [0; KERNEL_STACK_SIZE] };
 static USER_STACK: UserStack = UserStack { data: [0; USER_STACK_SIZE] };
 
-pub struct AppManager {
-    inner: RefCell<AppManagerInner>,
+// 用户程序可以访问的内存：程序所在的区域和用户栈
+pub fn user_ranges() -> [Range<usize>; 2] {
+    let stack = USER_STACK.data.as_ptr() as usize;
+    [APP_BASE_ADDRESS..APP_BASE_ADDRESS + APP_SIZE_LIMIT, stack..stack + USER_STACK_SIZE]
 }
 
-unsafe impl Sync for AppManager {}
+pub struct AppManager {
+    inner: spin::Mutex<AppManagerInner>,
+}
 
 impl AppManager {
     pub fn new() -> AppManager {
         AppManager {
-            inner: RefCell::new({
+            inner: spin::Mutex::new({
                 extern "C" { fn _num_app(); }
                 let num_app_ptr = _num_app as usize as *const usize;
                 let num_app = unsafe { num_app_ptr.read_volatile() };
@@ -65,7 +69,7 @@ impl AppManager {
     }
 
     pub fn print_app_info(&self) {
-        let inner = self.inner.borrow();
+        let inner = self.inner.lock();
         println!("[kernel] num_app = {}", inner.num_app);
         for i in 0..inner.num_app {
             println!("[kernel] app_{} [{:#x}, {:#x})", i, inner.app_start[i], inner.app_start[i + 1]);
@@ -74,13 +78,13 @@ impl AppManager {
 
     pub fn run_next_app(&self) -> ! {
         {
-            let inner = self.inner.borrow();
+            let inner = self.inner.lock();
             let current_app = inner.get_current_app_index();
             unsafe {
                 inner.load_app(current_app);
             }
         }
-        self.inner.borrow_mut().move_to_next_app();
+        self.inner.lock().move_to_next_app();
         unsafe {
             let ctx = KERNEL_STACK.push_context(
                 trap::TrapContext::app_init_context(APP_BASE_ADDRESS, USER_STACK.get_sp())
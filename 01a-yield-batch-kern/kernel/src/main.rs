@@ -9,6 +9,7 @@ mod sbi;
 mod app;
 mod trap;
 mod syscall;
+mod uaccess;
 
 use core::panic::PanicInfo;
 
@@ -1,19 +1,22 @@
-use core::cell::RefCell;
+use core::ops::Range;
 
 const MAX_APP_NUM: usize = 16;
 const APP_BASE_ADDRESS: usize = 0x80400000;
 const APP_SIZE_LIMIT: usize = 0x20000;
 
-pub struct AppManager {
-    inner: RefCell<AppManagerInner>,
+// 用户程序可以访问的内存：程序所在的区域和用户栈
+pub fn user_ranges() -> [Range<usize>; 2] {
+    [APP_BASE_ADDRESS..APP_BASE_ADDRESS + APP_SIZE_LIMIT, crate::executor::user_stack_range()]
 }
 
-unsafe impl Sync for AppManager {}
+pub struct AppManager {
+    inner: spin::Mutex<AppManagerInner>,
+}
 
 impl AppManager {
     pub fn new() -> AppManager {
         AppManager {
-            inner: RefCell::new({
+            inner: spin::Mutex::new({
                 extern "C" { fn _num_app(); }
                 let num_app_ptr = _num_app as usize as *const usize;
                 let num_app = unsafe { num_app_ptr.read_volatile() };
@@ -32,7 +35,7 @@ impl AppManager {
     }
 
     pub fn print_app_info(&self) {
-        let inner = self.inner.borrow();
+        let inner = self.inner.lock();
         println!("[kernel] num_app = {}", inner.num_app);
         for i in 0..inner.num_app {
             println!("[kernel] app_{} [{:#x}, {:#x})", i, inner.app_start[i], inner.app_start[i + 1]);
@@ -40,7 +43,7 @@ impl AppManager {
     } 
 
     pub fn prepare_next_app(&self) -> usize {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = self.inner.lock();
         let current_app = inner.get_current_app_index();
         unsafe {
             inner.load_app(current_app);
@@ -23,6 +23,12 @@ struct UserStack([u8; USER_STACK_SIZE]);
 
 static mut USER_STACK: UserStack = UserStack([0; USER_STACK_SIZE]);
 
+// 用户栈所在的内存区域
+pub fn user_stack_range() -> core::ops::Range<usize> {
+    let start = unsafe { &USER_STACK as *const _ as usize };
+    start..start + USER_STACK_SIZE
+}
+
 impl Runtime {
     pub fn new_user() -> Self {
         let context: UserContext = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
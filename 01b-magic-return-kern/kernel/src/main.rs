@@ -8,6 +8,7 @@ mod console;
 mod sbi;
 mod app;
 mod syscall;
+mod uaccess;
 mod executor;
 
 use core::panic::PanicInfo;
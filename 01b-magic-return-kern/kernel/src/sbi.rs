@@ -1,5 +1,7 @@
 #![allow(unused)]
 
+use core::sync::atomic::{AtomicU8, Ordering};
+
 pub const EXTENSION_BASE: usize = 0x10;
 pub const EXTENSION_TIMER: usize = 0x54494D45;
 pub const EXTENSION_IPI: usize = 0x735049;
@@ -15,6 +17,20 @@ const FUNCTION_BASE_GET_MVENDORID: usize = 0x4;
 const FUNCTION_BASE_GET_MARCHID: usize = 0x5;
 const FUNCTION_BASE_GET_MIMPID: usize = 0x6;
 
+const FUNCTION_TIMER_SET_TIMER: usize = 0x0;
+
+const FUNCTION_IPI_SEND_IPI: usize = 0x0;
+
+const FUNCTION_RFENCE_REMOTE_FENCE_I: usize = 0x0;
+const FUNCTION_RFENCE_REMOTE_SFENCE_VMA: usize = 0x1;
+const FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 0x2;
+
+const FUNCTION_HSM_HART_START: usize = 0x0;
+const FUNCTION_HSM_HART_STOP: usize = 0x1;
+const FUNCTION_HSM_HART_GET_STATUS: usize = 0x2;
+
+const FUNCTION_SRST_SYSTEM_RESET: usize = 0x0;
+
 #[repr(C)]
 pub struct SbiRet {
     /// Error number
@@ -23,6 +39,40 @@ pub struct SbiRet {
     pub value: usize,
 }
 
+// SBI调用返回的错误
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    // 规范中没有定义的错误码
+    Unknown(isize),
+}
+
+impl SbiRet {
+    // error为0表示成功，这时value是返回值
+    pub fn into_result(self) -> Result<usize, SbiError> {
+        let error = match self.error as isize {
+            0 => return Ok(self.value),
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            code => SbiError::Unknown(code),
+        };
+        Err(error)
+    }
+}
+
 #[inline(always)]
 fn sbi_call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
     let (error, value);
@@ -63,6 +113,41 @@ pub fn probe_extension(extension_id: usize) -> usize {
     sbi_call(EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION, extension_id, 0, 0).value
 }
 
+// 扩展是否存在。只实现了旧版接口的固件没有Base扩展，这个调用返回错误，当作扩展不存在
+pub fn has_extension(extension_id: usize) -> bool {
+    match sbi_call(EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION, extension_id, 0, 0).into_result() {
+        Ok(value) => value != 0,
+        Err(_) => false,
+    }
+}
+
+// 探测一次扩展后记住结果，每次设置时钟时不用再询问固件
+struct ProbeCache {
+    extension_id: usize,
+    state: AtomicU8, // 0：还没有探测，1：不存在，2：存在
+}
+
+impl ProbeCache {
+    const fn new(extension_id: usize) -> Self {
+        ProbeCache { extension_id, state: AtomicU8::new(0) }
+    }
+    fn available(&self) -> bool {
+        match self.state.load(Ordering::Relaxed) {
+            0 => {
+                let ans = has_extension(self.extension_id);
+                self.state.store(if ans { 2 } else { 1 }, Ordering::Relaxed);
+                ans
+            },
+            state => state == 2,
+        }
+    }
+}
+
+static HAS_TIMER: ProbeCache = ProbeCache::new(EXTENSION_TIMER);
+static HAS_IPI: ProbeCache = ProbeCache::new(EXTENSION_IPI);
+static HAS_RFENCE: ProbeCache = ProbeCache::new(EXTENSION_RFENCE);
+static HAS_SRST: ProbeCache = ProbeCache::new(EXTENSION_SRST);
+
 #[inline]
 pub fn get_mvendorid() -> usize {
     sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_MVENDORID, 0, 0, 0).value
@@ -78,6 +163,28 @@ pub fn get_mimpid() -> usize {
     sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_MIMPID, 0, 0, 0).value
 }
 
+// RFENCE扩展的调用最多有5个参数
+#[inline(always)]
+fn sbi_call_5(extension: usize, function: usize, args: [usize; 5]) -> SbiRet {
+    let (error, value);
+    match () {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        () => unsafe { asm!(
+            "ecall", 
+            in("a0") args[0], in("a1") args[1], in("a2") args[2],
+            in("a3") args[3], in("a4") args[4],
+            in("a6") function, in("a7") extension,
+            lateout("a0") error, lateout("a1") value,
+        ) },
+        #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+        () => {
+            drop((extension, function, args));
+            unimplemented!("not RISC-V instruction set architecture")
+        }
+    };
+    SbiRet { error, value }
+}
+
 #[inline(always)]
 fn sbi_call_legacy(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let ret;
@@ -116,11 +223,153 @@ pub fn console_getchar() -> usize {
     sbi_call_legacy(SBI_CONSOLE_GETCHAR, 0, 0, 0)
 }
 
+// 关机。固件支持SRST扩展时用它关机，否则使用旧版接口
 pub fn shutdown() -> ! {
+    if HAS_SRST.available() {
+        let err = system_reset(ResetType::Shutdown, ResetReason::NoReason);
+        println!("[kernel] SBI system reset failed: {:?}, fallback to legacy shutdown", err);
+    }
     sbi_call_legacy(SBI_SHUTDOWN, 0, 0, 0);
     unreachable!()
 }
 
-pub fn set_timer(time: usize) {
-    sbi_call_legacy(SBI_SET_TIMER, time, 0, 0);
+// 在time寄存器达到time时产生时钟中断，同时清除正在等待处理的时钟中断
+pub fn set_timer(time: u64) {
+    // 时间总是64位的：RV64下一个寄存器就能放下，RV32下分成低32位和高32位两个寄存器传递
+    #[cfg(target_pointer_width = "64")]
+    let (time_lo, time_hi) = (time as usize, 0);
+    #[cfg(target_pointer_width = "32")]
+    let (time_lo, time_hi) = (time as usize, (time >> 32) as usize);
+    if HAS_TIMER.available() {
+        let _ = sbi_call(EXTENSION_TIMER, FUNCTION_TIMER_SET_TIMER, time_lo, time_hi, 0);
+    } else {
+        sbi_call_legacy(SBI_SET_TIMER, time_lo, time_hi, 0);
+    }
+}
+
+// 以下的hart_mask和hart_mask_base表示一组处理核：编号为hart_mask_base + i的核，
+// 当hart_mask的第i位为1时属于这一组。hart_mask_base为usize::MAX时表示所有的核
+
+// 旧版接口用指向位图的指针表示一组处理核，空指针表示所有的核。位图只有一个字，超出范围的核无法表示
+fn legacy_hart_mask(hart_mask: usize, hart_mask_base: usize) -> Result<Option<usize>, SbiError> {
+    if hart_mask_base == usize::MAX {
+        return Ok(None)
+    }
+    let bits = core::mem::size_of::<usize>() * 8;
+    if hart_mask_base >= bits || (hart_mask_base != 0 && hart_mask >> (bits - hart_mask_base) != 0) {
+        return Err(SbiError::InvalidParam)
+    }
+    Ok(Some(hart_mask << hart_mask_base))
+}
+
+// 调用旧版接口。mask指向内核栈上的位图，调用返回前一直有效
+fn legacy_hart_call(which: usize, hart_mask: usize, hart_mask_base: usize, arg1: usize, arg2: usize) -> Result<(), SbiError> {
+    let mask = legacy_hart_mask(hart_mask, hart_mask_base)?;
+    let mask_ptr = mask.as_ref().map(|m| m as *const usize as usize).unwrap_or(0);
+    sbi_call_legacy(which, mask_ptr, arg1, arg2);
+    Ok(())
+}
+
+// 向一组处理核发送核间中断，它们的sip.SSIP被置位
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    if HAS_IPI.available() {
+        sbi_call(EXTENSION_IPI, FUNCTION_IPI_SEND_IPI, hart_mask, hart_mask_base, 0).into_result().map(drop)
+    } else {
+        legacy_hart_call(SBI_SEND_IPI, hart_mask, hart_mask_base, 0, 0)
+    }
+}
+
+// 让一组处理核执行fence.i
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> Result<(), SbiError> {
+    if HAS_RFENCE.available() {
+        sbi_call(EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_FENCE_I, hart_mask, hart_mask_base, 0).into_result().map(drop)
+    } else {
+        legacy_hart_call(SBI_REMOTE_FENCE_I, hart_mask, hart_mask_base, 0, 0)
+    }
+}
+
+// 让一组处理核刷新[start, start + size)范围内所有地址空间的页表缓存。
+// start和size都为0，或者size为usize::MAX时，刷新全部
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize) -> Result<(), SbiError> {
+    if HAS_RFENCE.available() {
+        sbi_call_5(EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_SFENCE_VMA, [hart_mask, hart_mask_base, start, size, 0])
+            .into_result().map(drop)
+    } else {
+        legacy_hart_call(SBI_REMOTE_SFENCE_VMA, hart_mask, hart_mask_base, start, size)
+    }
+}
+
+// 和remote_sfence_vma相同，但只刷新地址空间编号为asid的项
+pub fn remote_sfence_vma_asid(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize, asid: usize) -> Result<(), SbiError> {
+    if HAS_RFENCE.available() {
+        sbi_call_5(EXTENSION_RFENCE, FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID, [hart_mask, hart_mask_base, start, size, asid])
+            .into_result().map(drop)
+    } else {
+        let mask = legacy_hart_mask(hart_mask, hart_mask_base)?;
+        let mask_ptr = mask.as_ref().map(|m| m as *const usize as usize).unwrap_or(0);
+        // 旧版接口有4个参数，不使用a6，也不返回错误
+        sbi_call_5(SBI_REMOTE_SFENCE_VMA_ASID, 0, [mask_ptr, start, size, asid, 0]);
+        Ok(())
+    }
+}
+
+// 处理核的状态
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+// 启动一个停止的处理核。它从start_addr开始在S态运行，分页关闭，a0是它的编号，a1是opaque。
+// HSM扩展没有旧版接口，固件不支持时返回NotSupported
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), SbiError> {
+    sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_START, hartid, start_addr, opaque).into_result().map(drop)
+}
+
+// 停止当前的处理核，成功时不会返回
+pub fn hart_stop() -> SbiError {
+    match sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_STOP, 0, 0, 0).into_result() {
+        Ok(_) => SbiError::Failed, // 不应该返回
+        Err(e) => e,
+    }
+}
+
+pub fn hart_get_status(hartid: usize) -> Result<HartState, SbiError> {
+    let state = sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_GET_STATUS, hartid, 0, 0).into_result()?;
+    match state {
+        0 => Ok(HartState::Started),
+        1 => Ok(HartState::Stopped),
+        2 => Ok(HartState::StartPending),
+        3 => Ok(HartState::StopPending),
+        4 => Ok(HartState::Suspended),
+        5 => Ok(HartState::SuspendPending),
+        6 => Ok(HartState::ResumePending),
+        _ => Err(SbiError::Failed),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+// 关机或者重启，成功时不会返回
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    match sbi_call(EXTENSION_SRST, FUNCTION_SRST_SYSTEM_RESET, reset_type as usize, reason as usize, 0).into_result() {
+        Ok(_) => SbiError::Failed, // 不应该返回
+        Err(e) => e,
+    }
 }
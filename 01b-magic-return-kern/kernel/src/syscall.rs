@@ -1,3 +1,5 @@
+use crate::uaccess::{BadAddress, UserSlice};
+
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
@@ -16,51 +18,86 @@ pub struct SyscallResult {
     pub extra: usize,
 }
 
+// 所有内核和用户库共用的错误码表
+mod error_code {
+    #![allow(dead_code)] // 这个内核只返回其中一部分错误
+    include!("../../../syscall_error_code.rs");
+}
+
+// 系统调用的错误。出错时返回给用户的code是错误码，extra是错误的附加信息；成功时code为0。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SyscallError {
+    // 不支持的模块或者功能
+    NotSupported,
+    // 不支持的文件描述符，附加信息是这个描述符
+    BadFd(usize),
+    // 用户传来的地址不合法，附加信息是出错的地址
+    BadAddress(usize),
+    // 参数不合法，比如字符串不是UTF-8编码
+    InvalidArgument,
+}
+
+impl From<SyscallError> for SyscallResult {
+    fn from(src: SyscallError) -> SyscallResult {
+        let (code, extra) = match src {
+            SyscallError::NotSupported => (error_code::NOT_SUPPORTED, 0),
+            SyscallError::BadFd(fd) => (error_code::BAD_FD, fd),
+            SyscallError::BadAddress(addr) => (error_code::BAD_ADDRESS, addr),
+            SyscallError::InvalidArgument => (error_code::INVALID_ARGUMENT, 0),
+        };
+        SyscallResult { code, extra }
+    }
+}
+
+impl From<BadAddress> for SyscallError {
+    fn from(src: BadAddress) -> SyscallError {
+        SyscallError::BadAddress(src.0)
+    }
+}
+
+// 用户的请求有误时返回错误，而不是让内核崩溃
 pub fn syscall(module: usize, function: usize, args: [usize; 6]) -> SyscallOperation {
-    match module {
+    let ans = match module {
         MODULE_PROCESS => do_process(function, args),
         MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]]),
-        _ => panic!("Unknown syscall, module: {}, function: {}, args: {:?}", module, function, args),
-    }
+        _ => Err(SyscallError::NotSupported),
+    };
+    ans.unwrap_or_else(|e| SyscallOperation::Return(e.into()))
 }
 
-fn do_process(function: usize, args: [usize; 6]) -> SyscallOperation {
+fn do_process(function: usize, args: [usize; 6]) -> Result<SyscallOperation, SyscallError> {
     match function {
-        FUNCTION_PROCESS_EXIT => SyscallOperation::Terminate(args[0] as i32),
+        FUNCTION_PROCESS_EXIT => Ok(SyscallOperation::Terminate(args[0] as i32)),
         FUNCTION_PROCESS_PANIC => { // [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
             let [line, col, f_buf, f_len, m_buf, m_len] = args;
-            let file_name = if f_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(f_buf as *const u8, f_len) };
-                Some(core::str::from_utf8(slice).unwrap())
-            };
-            let msg = if m_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(m_buf as *const u8, m_len) };
-                Some(core::str::from_utf8(slice).unwrap())
+            // 地址不合法或者不是UTF-8编码时，当作没有提供这一项
+            let user_ranges = crate::app::user_ranges();
+            let read_str = |buf, len| {
+                let slice = UserSlice::new(buf, len);
+                if slice.is_null() { None } else { slice.as_str(&user_ranges) }
             };
-            SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg)
+            let file_name = read_str(f_buf, f_len);
+            let msg = read_str(m_buf, m_len);
+            Ok(SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg))
         },
-        _ => panic!("Unknown syscall PROCESS, function: {}, args: {:?}", function, args),
+        _ => Err(SyscallError::NotSupported),
     }
 }
 
-fn do_test_interface(function: usize, args: [usize; 3]) -> SyscallOperation {
+fn do_test_interface(function: usize, args: [usize; 3]) -> Result<SyscallOperation, SyscallError> {
     match function {
         FUNCTION_TEST_WRITE => { // fd: usize, buffer: &[u8] fd, buffer.as_ptr() as usize, buffer.len()
             const STDOUT: usize = 1;
             let [fd, buf, len] = args;
-            if fd == STDOUT {
-                let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
-                let str = core::str::from_utf8(slice).unwrap();
-                print!("{}", str);
-                SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize })
-            } else {
-                panic!("Unsupported fd {}", fd);
+            if fd != STDOUT {
+                return Err(SyscallError::BadFd(fd));
             }
+            let user_ranges = crate::app::user_ranges();
+            let slice = UserSlice::new(buf, len).as_bytes(&user_ranges)?;
+            let str = core::str::from_utf8(slice).map_err(|_| SyscallError::InvalidArgument)?;
+            print!("{}", str);
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize }))
         },
-        _ => panic!("Unknown syscall TEST_INTERFACE,function: {}, arg: {:?}", function, args),
+        _ => Err(SyscallError::NotSupported),
     }
 }
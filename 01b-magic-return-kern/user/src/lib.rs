@@ -14,7 +14,7 @@ pub mod console {
     
     impl Write for Stdout {
         fn write_str(&mut self, s: &str) -> fmt::Result {
-            write(STDOUT, s.as_bytes());
+            write(STDOUT, s.as_bytes()).map_err(|_| fmt::Error)?;
             Ok(())
         }
     }
@@ -43,9 +43,9 @@ pub mod console {
 fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
     let err = panic_info.message().unwrap().as_str();
     if let Some(location) = panic_info.location() {
-        sys_panic(Some(location.file()), location.line(), location.column(), err);
+        let _ = sys_panic(Some(location.file()), location.line(), location.column(), err);
     } else {
-        sys_panic(None, 0, 0, err);
+        let _ = sys_panic(None, 0, 0, err);
     }
     loop {}
 }
@@ -57,7 +57,7 @@ pub extern "C" fn _start() -> ! {
         fn sbss(); fn ebss();
     } 
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
-    exit(main());
+    let _ = exit(main());
     panic!("unreachable after sys_exit!");
 }
 
@@ -68,9 +68,10 @@ fn main() -> i32 {
 }
 
 use syscall::*;
+pub use syscall::SyscallError;
 
-pub fn write(fd: usize, buf: &[u8]) -> SyscallResult { sys_write(fd, buf) }
-pub fn exit(exit_code: i32) -> SyscallResult { sys_exit(exit_code) }
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, SyscallError> { sys_write(fd, buf) }
+pub fn exit(exit_code: i32) -> Result<usize, SyscallError> { sys_exit(exit_code) }
 
 mod syscall {
     const MODULE_PROCESS: usize = 0x114514;
@@ -85,6 +86,41 @@ mod syscall {
         pub extra: usize,
     }
 
+    // 所有内核和用户库共用的错误码表
+    mod error_code {
+        #![allow(dead_code)] // 这个库只认识对应的内核会返回的错误
+        include!("../../../syscall_error_code.rs");
+    }
+
+    // 系统调用的错误
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum SyscallError {
+        // 不支持的模块或者功能
+        NotSupported,
+        // 不支持的文件描述符
+        BadFd(usize),
+        // 传给内核的地址不合法，包含出错的地址
+        BadAddress(usize),
+        // 参数不合法，比如字符串不是UTF-8编码
+        InvalidArgument,
+        // 这个库不认识的错误码，包含错误码和附加信息
+        Unknown(usize, usize),
+    }
+    
+    impl SyscallResult {
+        // code为0表示成功，这时extra是返回值；否则按错误码得到错误
+        fn into_result(self) -> Result<usize, SyscallError> {
+            match self.code {
+                0 => Ok(self.extra),
+                error_code::NOT_SUPPORTED => Err(SyscallError::NotSupported),
+                error_code::BAD_FD => Err(SyscallError::BadFd(self.extra)),
+                error_code::BAD_ADDRESS => Err(SyscallError::BadAddress(self.extra)),
+                error_code::INVALID_ARGUMENT => Err(SyscallError::InvalidArgument),
+                code => Err(SyscallError::Unknown(code, self.extra)),
+            }
+        }
+    }
+
     fn syscall_1(module: usize, function: usize, arg: usize) -> SyscallResult {
         match () {
             #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
@@ -149,20 +185,20 @@ mod syscall {
         }
     }
 
-    pub fn sys_write(fd: usize, buffer: &[u8]) -> SyscallResult {
-        syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
+    pub fn sys_write(fd: usize, buffer: &[u8]) -> Result<usize, SyscallError> {
+        syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()]).into_result()
     }
 
-    pub fn sys_exit(exit_code: i32) -> SyscallResult {
-        syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_EXIT, exit_code as usize)
+    pub fn sys_exit(exit_code: i32) -> Result<usize, SyscallError> {
+        syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_EXIT, exit_code as usize).into_result()
     }
 
-    pub fn sys_panic(file_name: Option<&str>, line: u32, col: u32, msg: Option<&str>) -> SyscallResult {
+    pub fn sys_panic(file_name: Option<&str>, line: u32, col: u32, msg: Option<&str>) -> Result<usize, SyscallError> {
         let (f_buf, f_len) = file_name.map(|s| (s.as_ptr() as usize, s.len())).unwrap_or((0, 0));
         let (m_buf, m_len) = msg.map(|s| (s.as_ptr() as usize, s.len())).unwrap_or((0, 0));
         syscall_6(
             MODULE_PROCESS, FUNCTION_PROCESS_PANIC, 
             [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
-        )
+        ).into_result()
     }
 }
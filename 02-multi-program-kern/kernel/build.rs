@@ -56,5 +56,14 @@ app_{0}_start:
     .incbin "{2}/{1}.bin"
 app_{0}_end:"#, idx, app, target_dir)?;
     }
+
+    // 程序的名字，按顺序排列，每个以零结尾
+    writeln!(f, r#"
+    .section .rodata
+    .global _app_names
+_app_names:"#)?;
+    for app in apps.iter() {
+        writeln!(f, r#"    .string "{}""#, app)?;
+    }
     Ok(())
 }
@@ -0,0 +1,173 @@
+//! 文件模块
+//!
+//! 任务通过文件描述符读写文件。每个任务有自己的文件描述符表，表中的文件是共享的：
+//! 复制描述符后，两个描述符指向同一个文件；所有描述符都关闭后，文件才被释放。
+//!
+//! 目前的文件有两种：键盘和屏幕，和管道。管道是一段环形缓冲区，读端没有数据时让出处理核，
+//! 直到有数据写入，或者写端全部关闭、读到结尾
+
+use crate::sbi::{console_putchar, console_getchar};
+use crate::task::suspend_current_and_run_next;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FileError {
+    // 文件不支持这种访问，比如向管道的读端写入
+    NotPermitted,
+    // 管道的读端已经全部关闭，写入的数据不会再被读取
+    BrokenPipe,
+}
+
+// 文件可能被多个任务共享
+pub trait File: Send + Sync {
+    // 读到buf中，返回读到的字节数；返回0表示已经读到结尾。暂时没有数据时让出处理核，等到有数据再返回
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FileError>;
+    // 写入buf中的所有数据，返回写入的字节数。暂时写不下时让出处理核，等到写完再返回
+    fn write(&self, buf: &[u8]) -> Result<usize, FileError>;
+}
+
+// 从键盘读取的标准输入
+pub struct Stdin;
+
+impl File for Stdin {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let mut len = 0;
+        loop {
+            // 读出已经输入的所有字符，至少等到一个字符
+            while len < buf.len() {
+                match console_getchar() {
+                    usize::MAX => break,
+                    c => {
+                        buf[len] = c as u8;
+                        len += 1;
+                    },
+                }
+            }
+            if len != 0 {
+                return Ok(len)
+            }
+            suspend_current_and_run_next();
+        }
+    }
+    fn write(&self, _buf: &[u8]) -> Result<usize, FileError> {
+        Err(FileError::NotPermitted)
+    }
+}
+
+// 输出到屏幕的标准输出和标准错误输出
+pub struct Stdout;
+
+impl File for Stdout {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FileError> {
+        Err(FileError::NotPermitted)
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        // 不是合法UTF-8的字节打印为替换字符
+        for c in String::from_utf8_lossy(buf).chars() {
+            console_putchar(c as usize);
+        }
+        Ok(buf.len())
+    }
+}
+
+const PIPE_BUFFER_SIZE: usize = 32;
+
+// 管道的一端。读端和写端共享同一个缓冲区
+pub struct Pipe {
+    readable: bool,
+    writable: bool,
+    buffer: Arc<spin::Mutex<PipeRingBuffer>>,
+}
+
+struct PipeRingBuffer {
+    data: [u8; PIPE_BUFFER_SIZE],
+    head: usize, // 下一个读出的位置
+    len: usize,
+    // 两端都保存在文件描述符表中，这里只记录它们是否还存在，不影响它们的释放
+    read_end: Weak<Pipe>,
+    write_end: Weak<Pipe>,
+}
+
+impl PipeRingBuffer {
+    fn read_bytes(&mut self, buf: &mut [u8]) -> usize {
+        let n = core::cmp::min(buf.len(), self.len);
+        for byte in &mut buf[..n] {
+            *byte = self.data[self.head];
+            self.head = (self.head + 1) % PIPE_BUFFER_SIZE;
+        }
+        self.len -= n;
+        n
+    }
+    fn write_bytes(&mut self, buf: &[u8]) -> usize {
+        let n = core::cmp::min(buf.len(), PIPE_BUFFER_SIZE - self.len);
+        for &byte in &buf[..n] {
+            self.data[(self.head + self.len) % PIPE_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+        n
+    }
+}
+
+// 创建管道，返回读端和写端
+pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
+    let buffer = Arc::new(spin::Mutex::new(PipeRingBuffer {
+        data: [0; PIPE_BUFFER_SIZE],
+        head: 0,
+        len: 0,
+        read_end: Weak::new(),
+        write_end: Weak::new(),
+    }));
+    let read_end = Arc::new(Pipe { readable: true, writable: false, buffer: buffer.clone() });
+    let write_end = Arc::new(Pipe { readable: false, writable: true, buffer: buffer.clone() });
+    let mut inner = buffer.lock();
+    inner.read_end = Arc::downgrade(&read_end);
+    inner.write_end = Arc::downgrade(&write_end);
+    drop(inner);
+    (read_end, write_end)
+}
+
+impl File for Pipe {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FileError> {
+        if !self.readable {
+            return Err(FileError::NotPermitted)
+        }
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            let mut inner = self.buffer.lock();
+            let n = inner.read_bytes(buf);
+            if n != 0 {
+                return Ok(n)
+            }
+            if inner.write_end.upgrade().is_none() {
+                return Ok(0) // 没有数据，也不会再有数据写入
+            }
+            drop(inner); // 切换到别的任务前释放缓冲区，写端才能写入
+            suspend_current_and_run_next();
+        }
+    }
+    fn write(&self, buf: &[u8]) -> Result<usize, FileError> {
+        if !self.writable {
+            return Err(FileError::NotPermitted)
+        }
+        let mut written = 0;
+        while written < buf.len() {
+            let mut inner = self.buffer.lock();
+            if inner.read_end.upgrade().is_none() {
+                // 已经写入的数据留在缓冲区中，返回写入的字节数；一个字节都没有写入时报告错误
+                return if written == 0 { Err(FileError::BrokenPipe) } else { Ok(written) }
+            }
+            written += inner.write_bytes(&buf[written..]);
+            drop(inner);
+            if written < buf.len() {
+                suspend_current_and_run_next(); // 缓冲区满了，等读端读出
+            }
+        }
+        Ok(written)
+    }
+}
@@ -1,5 +1,6 @@
 use crate::trap::TrapContext;
 use crate::task::TaskContext;
+use core::ops::Range;
 
 const USER_STACK_SIZE: usize = 4096 * 2;
 const KERNEL_STACK_SIZE: usize = 4096 * 2;
@@ -57,11 +58,39 @@ fn get_base_i(app_id: usize) -> usize {
     APP_BASE_ADDRESS + app_id * APP_SIZE_LIMIT
 }
 
+// 程序可以访问的内存：程序所在的区域和它的用户栈
+pub fn user_ranges(app_id: usize) -> [Range<usize>; 2] {
+    let stack = USER_STACK[app_id].data.as_ptr() as usize;
+    [get_base_i(app_id)..get_base_i(app_id) + APP_SIZE_LIMIT, stack..stack + USER_STACK_SIZE]
+}
+
 pub fn get_num_app() -> usize {
     extern "C" { fn _num_app(); }
     unsafe { (_num_app as usize as *const usize).read_volatile() }
 }
 
+// 第app_id个程序的名字。build.rs把名字依次放在_app_names处，每个以零结尾
+pub fn get_app_name(app_id: usize) -> &'static str {
+    extern "C" { fn _app_names(); }
+    let mut ptr = _app_names as usize as *const u8;
+    for _ in 0..app_id {
+        while unsafe { ptr.read_volatile() } != 0 {
+            ptr = unsafe { ptr.add(1) };
+        }
+        ptr = unsafe { ptr.add(1) };
+    }
+    let mut len = 0;
+    while unsafe { ptr.add(len).read_volatile() } != 0 {
+        len += 1;
+    }
+    // note(unsafe): 名字来自程序的文件名，是合法的UTF-8
+    unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len)) }
+}
+
+pub fn find_app(name: &str) -> Option<usize> {
+    (0..get_num_app()).find(|app_id| get_app_name(*app_id) == name)
+}
+
 pub fn load_apps() {
     extern "C" { fn _num_app(); }
     let num_app_ptr = _num_app as usize as *const usize;
@@ -81,7 +110,7 @@ pub fn load_apps() {
         let dst = unsafe {
             core::slice::from_raw_parts_mut(base_i as *mut u8, src.len())
         };
-        println!("[kernel] app #{}: {:#x}..={:#x}", i, base_i, base_i+src.len());
+        println!("[kernel] app #{} {}: {:#x}..={:#x}", i, get_app_name(i), base_i, base_i+src.len());
         dst.copy_from_slice(src);
     }
 }
@@ -1,15 +1,21 @@
 #![feature(naked_functions, asm, global_asm)]
 #![feature(panic_info_message)]
+#![feature(alloc_error_handler)]
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 #[macro_use]
 mod console;
 mod sbi;
 mod loader;
 mod trap;
 mod syscall;
+mod uaccess;
 mod task;
+mod mm;
+mod fs;
 
 use core::panic::PanicInfo;
 
@@ -24,13 +30,28 @@ pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     println!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
     println!(".data [{:#x}, {:#x})", sdata as usize, edata as usize);
     println!(".bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
+    mm::init();
     trap::init();
     loader::load_apps();
+    for (writer, reader) in PIPELINES {
+        match (loader::find_app(writer), loader::find_app(reader)) {
+            (Some(w), Some(r)) => {
+                println!("[kernel] Pipe: {} | {}", writer, reader);
+                task::TASK_MANAGER.connect_pipe(w, r);
+            },
+            _ => println!("[kernel] Skip pipe {} | {}: app not found", writer, reader),
+        }
+    }
     task::TASK_MANAGER.run_first_task();
     println!("After run_first_task");
     sbi::shutdown()
 }
 
+// 开机时用管道连接的程序，前一个程序的输出作为后一个程序的输入，就像命令行中的`write_a | count`
+const PIPELINES: &[(&str, &str)] = &[
+    ("02-01-write_a", "02-04-count"),
+];
+
 #[cfg_attr(not(test), panic_handler)]
 #[allow(unused)]
 fn panic(info: &PanicInfo) -> ! {
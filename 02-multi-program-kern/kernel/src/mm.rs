@@ -0,0 +1,28 @@
+//! 内存管理模块
+//!
+//! 内核堆存放文件描述符表和管道等内核数据
+
+use alloc::alloc::Layout;
+use buddy_system_allocator::LockedHeap;
+
+const KERNEL_HEAP_SIZE: usize = 64 * 1024;
+
+static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
+
+// 全局的堆分配器
+#[global_allocator]
+static HEAP: LockedHeap<32> = LockedHeap::empty();
+
+#[cfg_attr(not(test), alloc_error_handler)]
+#[allow(unused)]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("alloc error for layout {:?}", layout)
+}
+
+pub fn init() {
+    unsafe {
+        HEAP.lock().init(
+            HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE
+        )
+    }
+}
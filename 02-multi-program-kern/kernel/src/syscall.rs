@@ -1,9 +1,17 @@
+use crate::uaccess::{BadAddress, UserSlice};
+use crate::fs::FileError;
+use crate::task::TASK_MANAGER;
+
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
 
 const MODULE_TEST_INTERFACE: usize = 0x233666;
 const FUNCTION_TEST_WRITE: usize = 0x666233;
+const FUNCTION_TEST_READ: usize = 0x666666;
+const FUNCTION_TEST_CLOSE: usize = 0x666999;
+const FUNCTION_TEST_DUP: usize = 0x666888;
+const FUNCTION_TEST_PIPE: usize = 0x666777;
 
 const MODULE_TASK: usize = 0x7777777;
 const FUNCTION_TASK_YIELD: usize = 0x9999999;
@@ -20,59 +28,131 @@ pub struct SyscallResult {
     pub extra: usize,
 }
 
+// 所有内核和用户库共用的错误码表
+mod error_code {
+    #![allow(dead_code)] // 这个内核只返回其中一部分错误
+    include!("../../../syscall_error_code.rs");
+}
+
+// 系统调用的错误。出错时返回给用户的code是错误码，extra是错误的附加信息；成功时code为0。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SyscallError {
+    // 不支持的模块或者功能
+    NotSupported,
+    // 不支持的文件描述符，附加信息是这个描述符
+    BadFd(usize),
+    // 用户传来的地址不合法，附加信息是出错的地址
+    BadAddress(usize),
+    // 向读端已经全部关闭的管道写入
+    BrokenPipe,
+}
+
+impl From<SyscallError> for SyscallResult {
+    fn from(src: SyscallError) -> SyscallResult {
+        let (code, extra) = match src {
+            SyscallError::NotSupported => (error_code::NOT_SUPPORTED, 0),
+            SyscallError::BadFd(fd) => (error_code::BAD_FD, fd),
+            SyscallError::BadAddress(addr) => (error_code::BAD_ADDRESS, addr),
+            SyscallError::BrokenPipe => (error_code::BROKEN_PIPE, 0),
+        };
+        SyscallResult { code, extra }
+    }
+}
+
+impl From<BadAddress> for SyscallError {
+    fn from(src: BadAddress) -> SyscallError {
+        SyscallError::BadAddress(src.0)
+    }
+}
+
+// 用户的请求有误时返回错误，而不是让内核崩溃
 pub fn syscall(module: usize, function: usize, args: [usize; 6]) -> SyscallOperation {
-    match module {
+    let ans = match module {
         MODULE_PROCESS => do_process(function, args),
         MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]]),
         MODULE_TASK => do_task(function),
-        _ => panic!("Unknown syscall, module: {}, function: {}, args: {:?}", module, function, args),
-    }
+        _ => Err(SyscallError::NotSupported),
+    };
+    ans.unwrap_or_else(|e| SyscallOperation::Return(e.into()))
 }
 
-fn do_process(function: usize, args: [usize; 6]) -> SyscallOperation {
+fn do_process(function: usize, args: [usize; 6]) -> Result<SyscallOperation, SyscallError> {
     match function {
-        FUNCTION_PROCESS_EXIT => SyscallOperation::Terminate(args[0] as i32),
+        FUNCTION_PROCESS_EXIT => Ok(SyscallOperation::Terminate(args[0] as i32)),
         FUNCTION_PROCESS_PANIC => { // [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
             let [line, col, f_buf, f_len, m_buf, m_len] = args;
-            let file_name = if f_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(f_buf as *const u8, f_len) };
-                Some(core::str::from_utf8(slice).unwrap())
-            };
-            let msg = if m_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(m_buf as *const u8, m_len) };
-                Some(core::str::from_utf8(slice).unwrap())
+            // 地址不合法或者不是UTF-8编码时，当作没有提供这一项
+            let user_ranges = crate::loader::user_ranges(TASK_MANAGER.current_task_id());
+            let read_str = |buf, len| {
+                let slice = UserSlice::new(buf, len);
+                if slice.is_null() { None } else { slice.as_str(&user_ranges) }
             };
-            SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg)
+            let file_name = read_str(f_buf, f_len);
+            let msg = read_str(m_buf, m_len);
+            Ok(SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg))
         },
-        _ => panic!("Unknown syscall PROCESS, function: {}, args: {:?}", function, args),
+        _ => Err(SyscallError::NotSupported),
     }
 }
 
-fn do_test_interface(function: usize, args: [usize; 3]) -> SyscallOperation {
+fn do_test_interface(function: usize, args: [usize; 3]) -> Result<SyscallOperation, SyscallError> {
     match function {
         FUNCTION_TEST_WRITE => { // fd: usize, buffer: &[u8] fd, buffer.as_ptr() as usize, buffer.len()
-            const STDOUT: usize = 1;
             let [fd, buf, len] = args;
-            if fd == STDOUT {
-                let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
-                let str = core::str::from_utf8(slice).unwrap();
-                print!("{}", str);
-                SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize })
-            } else {
-                panic!("Unsupported fd {}", fd);
+            let file = TASK_MANAGER.current_file(fd).ok_or(SyscallError::BadFd(fd))?;
+            let user_ranges = crate::loader::user_ranges(TASK_MANAGER.current_task_id());
+            let slice = UserSlice::new(buf, len).as_bytes(&user_ranges)?;
+            let n = file.write(slice).map_err(|e| file_error(e, fd))?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: n }))
+        },
+        FUNCTION_TEST_READ => { // fd: usize, buffer: &mut [u8]，没有数据时等待，返回读到的字节数，0表示读到结尾
+            let [fd, buf, len] = args;
+            let file = TASK_MANAGER.current_file(fd).ok_or(SyscallError::BadFd(fd))?;
+            let user_ranges = crate::loader::user_ranges(TASK_MANAGER.current_task_id());
+            let slice = UserSlice::new(buf, len).as_bytes_mut(&user_ranges)?;
+            let n = file.read(slice).map_err(|e| file_error(e, fd))?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: n }))
+        },
+        FUNCTION_TEST_CLOSE => { // fd: usize
+            let fd = args[0];
+            TASK_MANAGER.close_fd(fd).ok_or(SyscallError::BadFd(fd))?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: 0 }))
+        },
+        FUNCTION_TEST_DUP => { // fd: usize，返回指向同一个文件的最小空闲描述符
+            let fd = args[0];
+            let file = TASK_MANAGER.current_file(fd).ok_or(SyscallError::BadFd(fd))?;
+            let new_fd = TASK_MANAGER.alloc_fd(file);
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: new_fd }))
+        },
+        FUNCTION_TEST_PIPE => { // fds: *mut [usize; 2]，写入读端和写端的描述符
+            let ptr = args[0];
+            if ptr == 0 || ptr % core::mem::align_of::<[usize; 2]>() != 0 {
+                return Err(SyscallError::BadAddress(ptr));
             }
+            // 先检查地址，再创建管道，出错时不会留下用户拿不到的描述符
+            let user_ranges = crate::loader::user_ranges(TASK_MANAGER.current_task_id());
+            let fds = UserSlice::new(ptr, core::mem::size_of::<[usize; 2]>()).as_bytes_mut(&user_ranges)?;
+            let (read_end, write_end) = crate::fs::make_pipe();
+            let read_fd = TASK_MANAGER.alloc_fd(read_end);
+            let write_fd = TASK_MANAGER.alloc_fd(write_end);
+            // note(unsafe): 地址检查过，并且按照[usize; 2]对齐
+            unsafe { *(fds.as_mut_ptr() as *mut [usize; 2]) = [read_fd, write_fd] };
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: 0 }))
         },
-        _ => panic!("Unknown syscall TEST_INTERFACE,function: {}, arg: {:?}", function, args),
+        _ => Err(SyscallError::NotSupported),
+    }
+}
+
+fn file_error(src: FileError, fd: usize) -> SyscallError {
+    match src {
+        FileError::NotPermitted => SyscallError::BadFd(fd),
+        FileError::BrokenPipe => SyscallError::BrokenPipe,
     }
 }
 
-fn do_task(function: usize) -> SyscallOperation {
+fn do_task(function: usize) -> Result<SyscallOperation, SyscallError> {
     match function {
-        FUNCTION_TASK_YIELD => SyscallOperation::Yield,
-        _ => panic!("Unknown syscall TASK, function: {}", function),
+        FUNCTION_TASK_YIELD => Ok(SyscallOperation::Yield),
+        _ => Err(SyscallError::NotSupported),
     }
 }
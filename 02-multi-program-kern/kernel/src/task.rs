@@ -1,12 +1,12 @@
-use core::cell::RefCell;
+use alloc::{sync::Arc, vec, vec::Vec};
 use crate::loader::{init_app_ctx, get_num_app};
+use crate::fs::{self, File};
 
-const MAX_APP_NUM: usize = 16;
-
-#[derive(Copy, Clone)]
 pub struct TaskControlBlock {
     pub task_cx_ptr: usize, // Option<*mut TaskContext>,
     pub task_status: TaskStatus,
+    // 文件描述符表，描述符是表中的下标；关闭的描述符为None，之后可以再次分配
+    pub fd_table: Vec<Option<Arc<dyn File>>>,
 }
 
 impl TaskControlBlock {
@@ -17,7 +17,6 @@ impl TaskControlBlock {
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskStatus {
-    Uninitialized,
     Ready,
     Running,
     Finished,
@@ -25,20 +24,18 @@ pub enum TaskStatus {
 
 pub struct TaskManager {
     num_app: usize,
-    inner: RefCell<TaskManagerInner>,
+    inner: spin::Mutex<TaskManagerInner>,
 }
 
 struct TaskManagerInner {
-    tasks: [TaskControlBlock; MAX_APP_NUM],
+    tasks: Vec<TaskControlBlock>,
     current_task: usize,
 }
 
-unsafe impl Sync for TaskManager {}
-
 impl TaskManager {
     pub fn run_first_task(&self) {
-        self.inner.borrow_mut().tasks[0].task_status = TaskStatus::Running;
-        let next_task_ctx = self.inner.borrow().tasks[0].task_cx_ptr;
+        self.inner.lock().tasks[0].task_status = TaskStatus::Running;
+        let next_task_ctx = self.inner.lock().tasks[0].task_cx_ptr;
         let _unused = 0;
         unsafe {
             switch_task(
@@ -49,19 +46,67 @@ impl TaskManager {
     }
 
     fn mark_current_suspended(&self) {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = self.inner.lock();
         let current = inner.current_task;
         inner.tasks[current].task_status = TaskStatus::Ready;
     }
 
+    // 任务结束时关闭它的所有文件，管道的另一端由此知道这一端已经关闭
     fn mark_current_finished(&self) {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = self.inner.lock();
         let current = inner.current_task;
         inner.tasks[current].task_status = TaskStatus::Finished;
+        inner.tasks[current].fd_table.clear();
+    }
+
+    // 当前任务的编号，和程序的编号相同
+    pub fn current_task_id(&self) -> usize {
+        self.inner.lock().current_task
+    }
+
+    // 当前任务的描述符fd对应的文件。返回文件的副本，读写文件时可能切换任务，不能一直借用任务管理器
+    pub fn current_file(&self, fd: usize) -> Option<Arc<dyn File>> {
+        let inner = self.inner.lock();
+        inner.tasks[inner.current_task].fd_table.get(fd)?.clone()
+    }
+
+    // 把文件放到当前任务最小的空闲描述符上，返回这个描述符
+    pub fn alloc_fd(&self, file: Arc<dyn File>) -> usize {
+        let mut inner = self.inner.lock();
+        let current = inner.current_task;
+        let fd_table = &mut inner.tasks[current].fd_table;
+        match fd_table.iter().position(|file| file.is_none()) {
+            Some(fd) => {
+                fd_table[fd] = Some(file);
+                fd
+            },
+            None => {
+                fd_table.push(Some(file));
+                fd_table.len() - 1
+            },
+        }
+    }
+
+    // 关闭当前任务的描述符fd，描述符没有打开时返回None
+    pub fn close_fd(&self, fd: usize) -> Option<()> {
+        let mut inner = self.inner.lock();
+        let current = inner.current_task;
+        inner.tasks[current].fd_table.get_mut(fd)?.take().map(|_| ())
+    }
+
+    // 用管道把两个程序连接起来：writer的标准输出写入管道，reader的标准输入从管道读取。
+    // 在运行第一个任务之前调用
+    pub fn connect_pipe(&self, writer: usize, reader: usize) {
+        const STDIN: usize = 0;
+        const STDOUT: usize = 1;
+        let (read_end, write_end) = fs::make_pipe();
+        let mut inner = self.inner.lock();
+        inner.tasks[writer].fd_table[STDOUT] = Some(write_end);
+        inner.tasks[reader].fd_table[STDIN] = Some(read_end);
     }
 
     fn find_next_task(&self) -> Option<usize> {
-        let inner = self.inner.borrow();
+        let inner = self.inner.lock();
         let current = inner.current_task;
         (current + 1..current + self.num_app + 1)
             .map(|id| id % self.num_app)
@@ -72,8 +117,13 @@ impl TaskManager {
 
     fn run_next_task(&self) {
         if let Some(next) = self.find_next_task() {
-            let mut inner = self.inner.borrow_mut();
+            let mut inner = self.inner.lock();
             let current = inner.current_task;
+            if next == current {
+                // 没有别的就绪任务，继续运行当前任务。切换到自己会读到还没有保存的任务上下文
+                inner.tasks[current].task_status = TaskStatus::Running;
+                return;
+            }
             inner.tasks[next].task_status = TaskStatus::Running;
             inner.current_task = next;
             let current_task_ctx2 = inner.tasks[current].get_task_ctx_mut2();
@@ -105,17 +155,19 @@ pub fn exit_current_and_run_next() {
 lazy_static::lazy_static! {
     pub static ref TASK_MANAGER: TaskManager = {
         let num_app = get_num_app();
-        let mut tasks = [
-            TaskControlBlock { task_cx_ptr: 0, task_status: TaskStatus::Uninitialized };
-            MAX_APP_NUM
-        ];
+        let mut tasks = Vec::with_capacity(num_app);
         for i in 0..num_app {
-            tasks[i].task_cx_ptr = init_app_ctx(i) as *const _ as usize;
-            tasks[i].task_status = TaskStatus::Ready;
+            // 每个任务开始时打开标准输入、标准输出和标准错误输出，描述符分别是0、1、2
+            let (stdin, stdout): (Arc<dyn File>, Arc<dyn File>) = (Arc::new(fs::Stdin), Arc::new(fs::Stdout));
+            tasks.push(TaskControlBlock {
+                task_cx_ptr: init_app_ctx(i) as *const _ as usize,
+                task_status: TaskStatus::Ready,
+                fd_table: vec![Some(stdin), Some(stdout.clone()), Some(stdout)],
+            });
         }
         TaskManager {
             num_app,
-            inner: RefCell::new(TaskManagerInner {
+            inner: spin::Mutex::new(TaskManagerInner {
                 tasks,
                 current_task: 0,
             }),
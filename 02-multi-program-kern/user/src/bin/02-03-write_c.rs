@@ -14,7 +14,7 @@ fn main() -> i32 {
     for i in 0..HEIGHT {
         for _ in 0..WIDTH { print!("C"); }
         println!(" [{}/{}]", i + 1, HEIGHT);
-        multi_program_user::do_yield();
+        let _ = multi_program_user::do_yield();
     }
     println!("Test write_c OK!");
     0
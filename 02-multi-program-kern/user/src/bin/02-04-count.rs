@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate multi_program_user;
+
+use multi_program_user::read;
+
+const STDIN: usize = 0;
+
+// 从标准输入读到结尾，统计字节数、行数和字母A的个数。
+// 内核用管道把write_a的输出接到这里时，统计的是write_a的输出
+#[no_mangle]
+fn main() -> i32 {
+    let mut buf = [0u8; 16];
+    let (mut bytes, mut lines, mut count_a) = (0, 0, 0);
+    loop {
+        let n = match read(STDIN, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                println!("Count: failed to read stdin: {:?}", e);
+                return -1;
+            },
+        };
+        bytes += n;
+        lines += buf[..n].iter().filter(|b| **b == b'\n').count();
+        count_a += buf[..n].iter().filter(|b| **b == b'A').count();
+    }
+    println!("Count: {} bytes, {} lines, {} 'A's", bytes, lines, count_a);
+    println!("Test count OK!");
+    0
+}
@@ -1,6 +1,8 @@
 #[cfg(not(test))]
 global_asm!(include_str!("link_app.S"));
 
+use core::ops::Range;
+
 const APP_BASE_ADDRESS: usize = 0x80400000;
 const APP_SIZE_LIMIT: usize = 0x20000;
 
@@ -8,6 +10,13 @@ fn get_base_addr(app_id: usize) -> usize {
     APP_BASE_ADDRESS + app_id * APP_SIZE_LIMIT
 }
 
+// 用户程序可以访问的内存：所有程序所在的区域
+pub fn user_ranges() -> [Range<usize>; 1] {
+    extern "C" { fn _num_app(); }
+    let num_app = unsafe { (_num_app as usize as *const usize).read_volatile() };
+    [APP_BASE_ADDRESS..get_base_addr(num_app)]
+}
+
 pub fn load_apps() {
     extern "C" { fn _num_app(); }
     let num_app_ptr = _num_app as usize as *const usize;
@@ -9,6 +9,7 @@ mod console;
 mod sbi;
 mod trap;
 mod syscall;
+mod uaccess;
 mod loader;
 mod task;
 
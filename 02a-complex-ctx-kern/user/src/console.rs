@@ -6,7 +6,7 @@ const STDOUT: usize = 1;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        super::syscall::sys_write(STDOUT, s.as_bytes());
+        super::syscall::sys_write(STDOUT, s.as_bytes()).map_err(|_| fmt::Error)?;
         Ok(())
     }
 }
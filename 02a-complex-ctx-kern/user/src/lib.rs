@@ -14,14 +14,16 @@ mod heap;
 mod task;
 mod execute;
 
+pub use syscall::SyscallError;
+
 #[cfg_attr(not(test), panic_handler)]
 #[allow(unused)]
 fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
     let err = panic_info.message().unwrap().as_str();
     if let Some(location) = panic_info.location() {
-        syscall::sys_panic(Some(location.file()), location.line(), location.column(), err);
+        let _ = syscall::sys_panic(Some(location.file()), location.line(), location.column(), err);
     } else {
-        syscall::sys_panic(None, 0, 0, err);
+        let _ = syscall::sys_panic(None, 0, 0, err);
     }
     loop {}
 }
@@ -35,7 +37,7 @@ pub extern "C" fn _start() -> ! {
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
     heap::init_heap();
     let exit_code = execute::execute_main(main());
-    syscall::sys_exit(exit_code);
+    let _ = syscall::sys_exit(exit_code);
     panic!("unreachable after sys_exit!");
 }
 
@@ -34,17 +34,20 @@ fn insert_app_data(target_dir: &str) -> Result<()> {
         .collect();
     apps.sort();
 
+    // 每个程序的名字和它在内核镜像中的位置，按名字创建任务时用到
     writeln!(f, r#"
-    .align 3
     .section .data
-    .global _num_app
-_num_app:
+    .p2align 3
+    .global _app_meta
+_app_meta:
     .quad {}"#, apps.len())?;
 
-    for i in 0..apps.len() {
-        writeln!(f, r#"    .quad app_{}_start"#, i)?;
+    for (i, name) in apps.iter().enumerate() {
+        writeln!(f, r#"    .quad {}"#, name.len())?;
+        writeln!(f, r#"    .asciz "{}""#, name)?;
+        writeln!(f, r#"    .p2align 3"#)?; // 名字后面的数据需要对齐
+        writeln!(f, r#"    .quad app_{0}_start, app_{0}_end"#, i)?;
     }
-    writeln!(f, r#"    .quad app_{}_end"#, apps.len() - 1)?;
 
     for (idx, app) in apps.iter().enumerate() {
         println!("app_{}: {}", idx, app);
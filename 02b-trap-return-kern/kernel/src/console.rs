@@ -1,4 +1,6 @@
-use crate::sbi::console_putchar;
+use crate::sbi::{console_putchar, console_getchar};
+use crate::task::WaitQueue;
+use alloc::collections::VecDeque;
 use core::fmt::{self, Write};
 
 struct Stdout;
@@ -29,3 +31,39 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
 }
+
+lazy_static::lazy_static! {
+    // 已经读到、还没有被任务取走的键盘输入
+    static ref INPUT: spin::Mutex<VecDeque<u8>> = spin::Mutex::new(VecDeque::new());
+}
+
+// 等待键盘输入的任务
+pub static INPUT_QUEUE: WaitQueue = WaitQueue::new();
+
+// 读出SBI中所有等待的输入，有新的输入时唤醒等待的任务
+pub fn poll_input() {
+    let mut input = INPUT.lock();
+    let old_len = input.len();
+    loop {
+        let c = console_getchar();
+        if c == usize::MAX { // 没有输入时返回-1
+            break;
+        }
+        input.push_back(c as u8);
+    }
+    let has_new = input.len() != old_len;
+    core::mem::drop(input);
+    if has_new {
+        INPUT_QUEUE.wake_all();
+    }
+}
+
+// 取出最多dst.len()个已经输入的字节，返回取出的字节数
+pub fn take_input(dst: &mut [u8]) -> usize {
+    let mut input = INPUT.lock();
+    let n = core::cmp::min(dst.len(), input.len());
+    for (i, byte) in input.drain(..n).enumerate() {
+        dst[i] = byte;
+    }
+    n
+}
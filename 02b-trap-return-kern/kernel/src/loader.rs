@@ -1,99 +1,99 @@
 use crate::trap::TrapContext;
 use crate::task::TaskContext;
+use crate::mm::{FrameBox, FrameAllocError};
+use core::ops::Range;
 
-const USER_STACK_SIZE: usize = 4096 * 2;
-const KERNEL_STACK_SIZE: usize = 4096 * 2;
-const MAX_APP_NUM: usize = 16;
-const APP_BASE_ADDRESS: usize = 0x80400000;
+const USER_STACK_PAGES: usize = 2;
+const KERNEL_STACK_PAGES: usize = 2;
 const APP_SIZE_LIMIT: usize = 0x20000;
+const PAGE_SIZE: usize = 4096;
 
-#[repr(align(4096))]
-#[derive(Clone, Copy)] // 在const assume_init稳定后，移除这里的Copy定义
-struct KernelStack {
-    data: [u8; KERNEL_STACK_SIZE],
+// 内核镜像中的一个程序
+#[derive(Copy, Clone)]
+pub struct App {
+    name: &'static str,
+    image: &'static [u8],
 }
 
-#[repr(align(4096))]
-#[derive(Clone, Copy)] // 同上
-struct UserStack {
-    data: [u8; USER_STACK_SIZE],
+impl App {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
 }
 
-static KERNEL_STACK: [KernelStack; MAX_APP_NUM] = [
-    KernelStack { data: [0xcc; KERNEL_STACK_SIZE], };
-    MAX_APP_NUM
-];
-/*unsafe {
-    core::mem::MaybeUninit::uninit().assume_init()
-};*/
-
-static USER_STACK: [UserStack; MAX_APP_NUM] = [
-    UserStack { data: [0xdd; USER_STACK_SIZE], };
-    MAX_APP_NUM
-];
-
-impl KernelStack {
-    fn get_sp(&self) -> usize {
-        self.data.as_ptr() as usize + KERNEL_STACK_SIZE
-    }
-    pub fn push_context(&self, trap_cx: TrapContext, task_cx: TaskContext) -> &'static mut TaskContext {
-        unsafe {
-            let trap_cx_ptr = (self.get_sp() - core::mem::size_of::<TrapContext>()) as *mut TrapContext;
-            *trap_cx_ptr = trap_cx;
-            let task_cx_ptr = (trap_cx_ptr as usize - core::mem::size_of::<TaskContext>()) as *mut TaskContext;
-            *task_cx_ptr = task_cx;
-            task_cx_ptr.as_mut().unwrap()
-        }
-    }
+// 遍历build.rs生成的程序表，得到每个程序的名字和内容
+pub fn apps() -> impl Iterator<Item = App> {
+    extern "C" { fn _app_meta(); }
+    let num_app_ptr = _app_meta as usize as *const usize;
+    let num_app = unsafe { num_app_ptr.read_volatile() };
+    let mut cur = unsafe { num_app_ptr.add(1) };
+    (0..num_app).map(move |_| unsafe {
+        let name_len = cur.read_volatile();
+        let name_ptr = cur.add(1) as *const u8;
+        let name = core::str::from_utf8_unchecked(core::slice::from_raw_parts(name_ptr, name_len));
+        // 跳过名字和结尾的零，名字后面的数据对齐到usize
+        let word = core::mem::size_of::<usize>();
+        cur = ((name_ptr as usize + name_len + 1 + word - 1) & !(word - 1)) as *const usize;
+        let (start, end) = (cur.read_volatile(), cur.add(1).read_volatile());
+        cur = cur.add(2);
+        App { name, image: core::slice::from_raw_parts(start as *const u8, end - start) }
+    })
 }
 
-impl UserStack {
-    fn get_sp(&self) -> usize {
-        self.data.as_ptr() as usize + USER_STACK_SIZE
-    }
+pub fn find_app(name: &str) -> Option<App> {
+    apps().find(|app| app.name == name)
 }
 
-fn get_base_i(app_id: usize) -> usize {
-    APP_BASE_ADDRESS + app_id * APP_SIZE_LIMIT
+#[derive(Debug)]
+pub enum LoadError {
+    // 程序超过了每个程序能占用的大小
+    TooLarge(usize),
+    FrameAlloc(FrameAllocError),
 }
 
-pub fn get_ptr(app_id: usize, user_ptr: usize) -> usize {
-    user_ptr - app_id * APP_SIZE_LIMIT
+impl From<FrameAllocError> for LoadError {
+    fn from(src: FrameAllocError) -> LoadError {
+        LoadError::FrameAlloc(src)
+    }
 }
 
-pub fn get_num_app() -> usize {
-    extern "C" { fn _num_app(); }
-    unsafe { (_num_app as usize as *const usize).read_volatile() }
+// 一个任务占用的内存：内核栈、程序镜像和用户栈。任务回收时，这些页帧一起还给页帧分配器
+pub struct TaskMemory {
+    kernel_stack: FrameBox,
+    image: FrameBox,
+    user_stack: FrameBox,
 }
 
-pub fn load_apps() {
-    extern "C" { fn _num_app(); }
-    let num_app_ptr = _num_app as usize as *const usize;
-    let num_app = get_num_app();
-    let app_start = unsafe {
-        core::slice::from_raw_parts(num_app_ptr.add(1), num_app + 1)
-    };
-    unsafe { asm!("fence.i"); }
-    for i in 0..num_app {
-        let base_i = get_base_i(i);
+impl TaskMemory {
+    // 分配任务的内存，把程序复制到新分配的页帧中
+    pub fn try_load(app: &App) -> Result<TaskMemory, LoadError> {
+        if app.image.len() > APP_SIZE_LIMIT {
+            return Err(LoadError::TooLarge(app.image.len()))
+        }
+        let kernel_stack = FrameBox::try_new(KERNEL_STACK_PAGES)?;
+        let image = FrameBox::try_new(APP_SIZE_LIMIT / PAGE_SIZE)?;
+        let user_stack = FrameBox::try_new(USER_STACK_PAGES)?;
         // todo: relocation
-        (base_i..base_i + APP_SIZE_LIMIT).for_each(|addr| unsafe {
-            (addr as *mut u8).write_volatile(0)
-        });
-        let src = unsafe {
-            core::slice::from_raw_parts(app_start[i] as *const u8, app_start[i + 1] - app_start[i])
-        };
         let dst = unsafe {
-            core::slice::from_raw_parts_mut(base_i as *mut u8, src.len())
+            core::slice::from_raw_parts_mut(image.start() as *mut u8, app.image.len())
         };
-        println!("[kernel] app #{}: {:#x}..={:#x}", i, base_i, base_i+src.len());
-        dst.copy_from_slice(src);
+        dst.copy_from_slice(app.image);
+        unsafe { asm!("fence.i"); }
+        Ok(TaskMemory { kernel_stack, image, user_stack })
+    }
+    // 程序可以访问的内存：程序所在的区域，和它的用户栈
+    pub fn user_ranges(&self) -> [Range<usize>; 2] {
+        [self.image.start()..self.image.end(), self.user_stack.start()..self.user_stack.end()]
+    }
+    // 在内核栈上放好程序的初始上下文，返回切换到这个任务时用到的任务上下文地址
+    pub fn init_context(&self, task_id: usize) -> usize {
+        let trap_cx = TrapContext::app_init_context(self.image.start(), task_id, self.user_stack.end());
+        unsafe {
+            let trap_cx_ptr = (self.kernel_stack.end() - core::mem::size_of::<TrapContext>()) as *mut TrapContext;
+            *trap_cx_ptr = trap_cx;
+            let task_cx_ptr = (trap_cx_ptr as usize - core::mem::size_of::<TaskContext>()) as *mut TaskContext;
+            *task_cx_ptr = TaskContext::goto_restore();
+            task_cx_ptr as usize
+        }
     }
-}
-
-pub fn init_app_ctx(app_id: usize) -> &'static TaskContext {
-    KERNEL_STACK[app_id].push_context(
-        TrapContext::app_init_context(get_base_i(app_id), app_id, USER_STACK[app_id].get_sp()),
-        TaskContext::goto_restore(),
-    )
 }
@@ -1,8 +1,11 @@
 #![feature(naked_functions, asm, global_asm)]
 #![feature(panic_info_message)]
+#![feature(alloc_error_handler)]
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 #[macro_use]
 mod console;
 mod sbi;
@@ -10,9 +13,15 @@ mod loader;
 mod trap;
 mod syscall;
 mod task;
+mod uaccess;
+mod timer;
+mod mm;
+mod sched;
 
 use core::panic::PanicInfo;
 
+const INIT_APP: &str = "02b-00-init";
+
 pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     extern "C" {
         fn stext(); fn etext(); fn srodata(); fn erodata();
@@ -25,7 +34,13 @@ pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     println!(".data [{:#x}, {:#x})", sdata as usize, edata as usize);
     println!(".bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
     trap::set_app_trap();
-    loader::load_apps();
+    mm::init();
+    for app in loader::apps() {
+        println!("[kernel] App: {}", app.name());
+    }
+    // 只创建第一个任务，其余的程序由它按名字创建
+    task::TASK_MANAGER.spawn(INIT_APP, None).expect("spawn init task");
+    timer::init();
     task::TASK_MANAGER.run_first_task();
     println!("After run_first_task");
     sbi::shutdown()
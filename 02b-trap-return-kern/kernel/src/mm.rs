@@ -0,0 +1,99 @@
+//! 内存管理模块
+//!
+//! 内核堆存放任务控制块等内核数据；页帧分配器管理内核镜像之后的物理内存，
+//! 任务的内核栈、程序镜像和用户栈都从页帧分配器中分配，任务回收时还回去。
+//! 堆的空闲空间不够时，从页帧分配器中取页帧加入堆，这些页帧不再还回去
+
+use alloc::alloc::Layout;
+use buddy_system_allocator::{LockedHeap, FrameAllocator};
+
+const KERNEL_HEAP_SIZE: usize = 64 * 1024;
+// 创建任务等会使用堆的操作之前，堆中至少要留出的空闲空间
+const KERNEL_HEAP_RESERVE: usize = 16 * 1024;
+const PAGE_SIZE: usize = 4096;
+// 物理内存的结束地址，暂时对qemu默认的128M内存写死
+const MEMORY_END: usize = 0x88000000;
+
+static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
+
+// 全局的堆分配器
+#[global_allocator]
+static HEAP: LockedHeap<32> = LockedHeap::empty();
+
+#[cfg_attr(not(test), alloc_error_handler)]
+#[allow(unused)]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("alloc error for layout {:?}", layout)
+}
+
+lazy_static::lazy_static! {
+    // 页帧分配器，分配的单位是页号
+    static ref FRAME_ALLOCATOR: spin::Mutex<FrameAllocator<32>> = spin::Mutex::new(FrameAllocator::new());
+}
+
+pub fn init() {
+    unsafe {
+        HEAP.lock().init(
+            HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE
+        )
+    }
+    extern "C" { fn ekernel(); }
+    let start = (ekernel as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+    let end = MEMORY_END / PAGE_SIZE;
+    FRAME_ALLOCATOR.lock().add_frame(start, end);
+    println!("[kernel] Frame allocator: [{:#x}, {:#x})", start * PAGE_SIZE, end * PAGE_SIZE);
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameAllocError;
+
+// 确保堆中留有足够的空闲空间，不够时从页帧分配器中取页帧扩大堆。
+// 堆分配失败时内核只能panic，所以由用户请求触发、会使用堆的操作先调用这里，失败时返回错误给用户。
+// 两个锁分开获取：页帧分配器自身也使用堆，不能在持有堆的锁时分配页帧
+pub fn try_reserve_heap() -> Result<(), FrameAllocError> {
+    let free = {
+        let heap = HEAP.lock();
+        heap.stats_total_bytes() - heap.stats_alloc_actual()
+    };
+    if free >= KERNEL_HEAP_RESERVE {
+        return Ok(())
+    }
+    let count = KERNEL_HEAP_RESERVE / PAGE_SIZE;
+    let ppn = FRAME_ALLOCATOR.lock().alloc(count).ok_or(FrameAllocError)?;
+    // note(unsafe): 页帧刚刚分配出来，交给堆之后不再还给页帧分配器
+    unsafe { HEAP.lock().add_to_heap(ppn * PAGE_SIZE, (ppn + count) * PAGE_SIZE) };
+    Ok(())
+}
+
+// 一段连续的页帧，离开作用域时还给页帧分配器
+#[derive(Debug)]
+pub struct FrameBox {
+    ppn: usize,
+    count: usize,
+}
+
+impl FrameBox {
+    // 分配count个连续的页帧，内容清零
+    pub fn try_new(count: usize) -> Result<FrameBox, FrameAllocError> {
+        let ppn = FRAME_ALLOCATOR.lock().alloc(count).ok_or(FrameAllocError)?;
+        let ans = FrameBox { ppn, count };
+        // note(unsafe): 页帧刚刚分配出来，只有这里在使用
+        unsafe { core::ptr::write_bytes(ans.start() as *mut u8, 0, ans.size()) };
+        Ok(ans)
+    }
+    pub fn start(&self) -> usize {
+        self.ppn * PAGE_SIZE
+    }
+    pub fn end(&self) -> usize {
+        self.start() + self.size()
+    }
+    pub fn size(&self) -> usize {
+        self.count * PAGE_SIZE
+    }
+}
+
+impl Drop for FrameBox {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR.lock().dealloc(self.ppn, self.count);
+    }
+}
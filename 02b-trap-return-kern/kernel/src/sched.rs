@@ -0,0 +1,146 @@
+//! 调度器模块
+//!
+//! 任务管理器只负责记录任务的状态和切换任务，选择下一个运行的任务交给调度器。
+//! 编译时用cargo的特性选择调度策略：sched-rr（轮转，默认）、sched-priority（固定优先级）和sched-stride（步长调度）
+
+#![allow(unused)] // 只有选中的调度器会被用到
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+// 调度器只保存就绪的任务。正在运行的任务不在就绪队列中，它让出或者被抢占后重新加入
+pub trait Scheduler {
+    // 任务变为就绪，加入就绪队列
+    fn add(&mut self, task_id: usize);
+    // 取出下一个运行的任务
+    fn pick_next(&mut self) -> Option<usize>;
+    // 时钟中断时调用，返回是否要抢占正在运行的任务
+    fn on_tick(&mut self, current: usize) -> bool;
+    // 任务结束，调度器忘记关于它的所有信息
+    fn remove(&mut self, task_id: usize);
+    // 设置任务的优先级，数字越大越优先。不使用优先级的调度器忽略它
+    fn set_priority(&mut self, task_id: usize, priority: usize);
+}
+
+#[cfg(feature = "sched-stride")]
+pub type DefaultScheduler = StrideScheduler;
+#[cfg(all(feature = "sched-priority", not(feature = "sched-stride")))]
+pub type DefaultScheduler = PriorityScheduler;
+#[cfg(not(any(feature = "sched-priority", feature = "sched-stride")))]
+pub type DefaultScheduler = RoundRobinScheduler;
+
+// 没有设置过优先级的任务使用的优先级
+pub const DEFAULT_PRIORITY: usize = 16;
+
+// 轮转调度：就绪的任务排成一队，每个时间片轮到队首的任务
+#[derive(Default)]
+pub struct RoundRobinScheduler {
+    ready: VecDeque<usize>,
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn add(&mut self, task_id: usize) {
+        self.ready.push_back(task_id);
+    }
+    fn pick_next(&mut self) -> Option<usize> {
+        self.ready.pop_front()
+    }
+    fn on_tick(&mut self, _current: usize) -> bool {
+        !self.ready.is_empty() // 没有别的就绪任务时，不需要切换
+    }
+    fn remove(&mut self, task_id: usize) {
+        self.ready.retain(|id| *id != task_id);
+    }
+    fn set_priority(&mut self, _task_id: usize, _priority: usize) {}
+}
+
+// 固定优先级调度：总是运行优先级最高的就绪任务，优先级相同的任务之间轮转。
+// 高优先级的任务一直就绪时，低优先级的任务得不到运行
+#[derive(Default)]
+pub struct PriorityScheduler {
+    ready: VecDeque<usize>,
+    priority: BTreeMap<usize, usize>,
+}
+
+impl Scheduler for PriorityScheduler {
+    fn add(&mut self, task_id: usize) {
+        self.priority.entry(task_id).or_insert(DEFAULT_PRIORITY);
+        self.ready.push_back(task_id);
+    }
+    fn pick_next(&mut self) -> Option<usize> {
+        // max_by_key遇到相等的元素时返回最后一个，这里要最先加入的那个，所以反过来用min_by_key
+        let (index, _) = self.ready.iter().enumerate()
+            .min_by_key(|(_, id)| core::cmp::Reverse(self.priority[*id]))?;
+        self.ready.remove(index)
+    }
+    fn on_tick(&mut self, current: usize) -> bool {
+        // 有优先级不低于当前任务的就绪任务时才切换
+        let current_priority = self.priority[&current];
+        self.ready.iter().any(|id| self.priority[id] >= current_priority)
+    }
+    fn remove(&mut self, task_id: usize) {
+        self.ready.retain(|id| *id != task_id);
+        self.priority.remove(&task_id);
+    }
+    fn set_priority(&mut self, task_id: usize, priority: usize) {
+        self.priority.insert(task_id, priority);
+    }
+}
+
+// 步长调度：每个任务有一个行程值，每次运行后增加和优先级成反比的步长，总是运行行程最小的就绪任务。
+// 长期来看，每个任务得到的时间片数和它的优先级成正比
+#[derive(Default)]
+pub struct StrideScheduler {
+    ready: BTreeMap<usize, StrideInfo>, // 任务编号 -> 行程和优先级
+    running: BTreeMap<usize, StrideInfo>, // 不在就绪队列中的任务，保留它们的行程和优先级
+}
+
+#[derive(Copy, Clone)]
+struct StrideInfo {
+    pass: usize,
+    priority: usize,
+}
+
+const BIG_STRIDE: usize = 0x10000;
+
+impl StrideInfo {
+    fn stride(&self) -> usize {
+        core::cmp::max(BIG_STRIDE / self.priority, 1) // 优先级特别大时，步长至少为1
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    fn add(&mut self, task_id: usize) {
+        // 新任务从当前最小的行程开始，不会因为行程为零而长时间独占处理核
+        let info = self.running.remove(&task_id).unwrap_or_else(|| StrideInfo {
+            pass: self.ready.values().map(|info| info.pass).min().unwrap_or(0),
+            priority: DEFAULT_PRIORITY,
+        });
+        self.ready.insert(task_id, info);
+    }
+    fn pick_next(&mut self) -> Option<usize> {
+        let (&task_id, _) = self.ready.iter().min_by_key(|(_, info)| info.pass)?;
+        let mut info = self.ready.remove(&task_id).unwrap();
+        info.pass += info.stride();
+        self.running.insert(task_id, info);
+        Some(task_id)
+    }
+    fn on_tick(&mut self, current: usize) -> bool {
+        // 选中时已经计入了这个时间片。有行程更小的就绪任务时切换，否则继续运行，再计入一个时间片
+        let min_ready = self.ready.values().map(|info| info.pass).min();
+        let info = self.running.get_mut(&current).unwrap();
+        if min_ready.map_or(false, |pass| pass < info.pass) {
+            return true
+        }
+        info.pass += info.stride();
+        false
+    }
+    fn remove(&mut self, task_id: usize) {
+        self.ready.remove(&task_id);
+        self.running.remove(&task_id);
+    }
+    fn set_priority(&mut self, task_id: usize, priority: usize) {
+        if let Some(info) = self.ready.get_mut(&task_id).or(self.running.get_mut(&task_id)) {
+            info.priority = priority;
+        }
+    }
+}
@@ -1,17 +1,30 @@
+use crate::uaccess::{BadAddress, UserSlice};
+use crate::task::{TASK_MANAGER, ExitStatus, SpawnError};
+use crate::loader::LoadError;
+use alloc::string::String;
+
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+const FUNCTION_PROCESS_WAIT: usize = 0x19198100;
+const FUNCTION_PROCESS_SPAWN: usize = 0x5201314;
+const FUNCTION_PROCESS_GETPID: usize = 0x1008611;
+const FUNCTION_PROCESS_GETPPID: usize = 0x1008612;
 
 const MODULE_TEST_INTERFACE: usize = 0x233666;
 const FUNCTION_TEST_WRITE: usize = 0x666233;
+const FUNCTION_TEST_READ: usize = 0x666666;
 
 const MODULE_TASK: usize = 0x7777777;
 const FUNCTION_TASK_YIELD: usize = 0x9999999;
+const FUNCTION_TASK_GET_TIME: usize = 0x8888888;
+const FUNCTION_TASK_SET_PRIORITY: usize = 0x6666666;
+const FUNCTION_TASK_SLEEP: usize = 0x5555555;
 
 pub enum SyscallOperation {
     Return(SyscallResult),
     Terminate(i32),
-    UserPanic(Option<&'static str>, u32, u32, Option<&'static str>),
+    UserPanic(Option<String>, u32, u32, Option<String>),
     Yield,
 }
 
@@ -20,61 +33,229 @@ pub struct SyscallResult {
     pub extra: usize,
 }
 
-pub fn syscall(module: usize, function: usize, args: [usize; 6], app_id: usize) -> SyscallOperation {
-    println!("[KERNEL] SYSCALL {:x} {:x} {:x?}", module, function, args);
-    match module {
-        MODULE_PROCESS => do_process(function, args, app_id),
-        MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]]),
-        MODULE_TASK => do_task(function),
-        _ => panic!("Unknown syscall, module: {}, function: {}, args: {:?}", module, function, args),
+// 所有内核和用户库共用的错误码表
+mod error_code {
+    #![allow(dead_code)] // 这个内核只返回其中一部分错误
+    include!("../../../syscall_error_code.rs");
+}
+
+// 系统调用的错误。出错时返回给用户的code是错误码，extra是错误的附加信息；成功时code为0。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SyscallError {
+    // 不支持的模块或者功能
+    NotSupported,
+    // 不支持的文件描述符，附加信息是这个描述符
+    BadFd(usize),
+    // 用户传来的地址不合法，附加信息是出错的地址
+    BadAddress(usize),
+    // 参数不合法，比如字符串不是UTF-8编码
+    InvalidArgument,
+    // 内存不足
+    OutOfMemory,
+    // 没有这个名字的程序，或者没有可以等待的子任务
+    NotFound,
+}
+
+impl From<SyscallError> for SyscallResult {
+    fn from(src: SyscallError) -> SyscallResult {
+        let (code, extra) = match src {
+            SyscallError::NotSupported => (error_code::NOT_SUPPORTED, 0),
+            SyscallError::BadFd(fd) => (error_code::BAD_FD, fd),
+            SyscallError::BadAddress(addr) => (error_code::BAD_ADDRESS, addr),
+            SyscallError::InvalidArgument => (error_code::INVALID_ARGUMENT, 0),
+            SyscallError::OutOfMemory => (error_code::OUT_OF_MEMORY, 0),
+            SyscallError::NotFound => (error_code::NOT_FOUND, 0),
+        };
+        SyscallResult { code, extra }
+    }
+}
+
+impl From<BadAddress> for SyscallError {
+    fn from(src: BadAddress) -> SyscallError {
+        SyscallError::BadAddress(src.0)
     }
 }
 
-fn do_process(function: usize, args: [usize; 6], app_id: usize) -> SyscallOperation {
+// 用户的请求有误时返回错误，而不是让内核崩溃
+pub fn syscall(module: usize, function: usize, args: [usize; 6], task_id: usize) -> SyscallOperation {
+    println!("[KERNEL] SYSCALL {:x} {:x} {:x?}", module, function, args);
+    let ans = match module {
+        MODULE_PROCESS => do_process(function, args, task_id),
+        MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]], task_id),
+        MODULE_TASK => do_task(function, args[0]),
+        _ => Err(SyscallError::NotSupported),
+    };
+    ans.unwrap_or_else(|e| SyscallOperation::Return(e.into()))
+}
+
+fn do_process(function: usize, args: [usize; 6], task_id: usize) -> Result<SyscallOperation, SyscallError> {
     match function {
-        FUNCTION_PROCESS_EXIT => SyscallOperation::Terminate(args[0] as i32),
+        FUNCTION_PROCESS_EXIT => Ok(SyscallOperation::Terminate(args[0] as i32)),
         FUNCTION_PROCESS_PANIC => { // [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
             let [line, col, f_buf, f_len, m_buf, m_len] = args;
-            let file_name = unsafe { user_buffer(app_id, f_buf, f_len) }
-                .map(|s| core::str::from_utf8(s).unwrap());
-            let msg = unsafe {  user_buffer(app_id, m_buf, m_len) }
-                .map(|s| core::str::from_utf8(s).unwrap());
-            SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg)
+            // 地址不合法或者不是UTF-8时，当作没有提供这一项
+            let read_str = |buf, len| {
+                let slice = UserSlice::new(buf, len);
+                if slice.is_null() { None } else { slice.read_string(task_id) }
+            };
+            let file_name = read_str(f_buf, f_len);
+            let msg = read_str(m_buf, m_len);
+            Ok(SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg))
+        },
+        FUNCTION_PROCESS_SPAWN => { // name: &str，按名字创建子任务，返回子任务的编号
+            // 地址不合法时返回BadAddress，名字不是UTF-8时才是InvalidArgument
+            let bytes = UserSlice::new(args[0], args[1]).read_bytes(task_id)?;
+            let name = String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+            match TASK_MANAGER.spawn(&name, Some(task_id)) {
+                Ok(child) => Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: child })),
+                Err(SpawnError::NotFound) => Err(SyscallError::NotFound),
+                Err(SpawnError::OutOfMemory) => Err(SyscallError::OutOfMemory),
+                Err(SpawnError::Load(e)) => {
+                    println!("[kernel] Failed to spawn {}: {:?}", name, e);
+                    match e {
+                        // 程序本身超过了每个程序能占用的大小，再怎么重试也不能创建
+                        LoadError::TooLarge(_) => Err(SyscallError::InvalidArgument),
+                        LoadError::FrameAlloc(_) => Err(SyscallError::OutOfMemory),
+                    }
+                },
+            }
         },
-        _ => panic!("Unknown syscall PROCESS, function: {}, args: {:?}", function, args),
+        FUNCTION_PROCESS_GETPID => Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: task_id })),
+        FUNCTION_PROCESS_GETPPID => { // 没有父任务时返回0
+            let parent = TASK_MANAGER.current_parent_id().unwrap_or(0);
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: parent }))
+        },
+        FUNCTION_PROCESS_WAIT => { // pid: usize（usize::MAX表示任意子任务）, info: *mut [usize; 4]，返回结束的子任务编号
+            let [pid, info] = [args[0], args[1]];
+            let child = if pid == usize::MAX { None } else { Some(pid) };
+            let info = UserSlice::new(info, core::mem::size_of::<[usize; 4]>());
+            if !info.is_null() {
+                info.validate(task_id)?;
+            }
+            let (child, status) = loop {
+                match TASK_MANAGER.collect_child(child) {
+                    Ok(Some(ans)) => break ans,
+                    Ok(None) => crate::task::EXIT_QUEUE.wait(),
+                    Err(()) => return Err(SyscallError::NotFound),
+                }
+            };
+            // 结束信息：[种类, 退出码, 行, 列]，种类0是正常退出，1是panic，2是被内核结束
+            let words = match status {
+                ExitStatus::Exited(code) => [0, code as isize as usize, 0, 0],
+                ExitStatus::Panicked { line, col } => [1, 0, line as usize, col as usize],
+                ExitStatus::Killed => [2, 0, 0, 0],
+            };
+            if !info.is_null() {
+                let mut bytes = [0u8; core::mem::size_of::<[usize; 4]>()];
+                for (chunk, word) in bytes.chunks_mut(core::mem::size_of::<usize>()).zip(words.iter()) {
+                    chunk.copy_from_slice(&word.to_ne_bytes());
+                }
+                info.write_at(task_id, 0, &bytes)?;
+            }
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: child }))
+        },
+        _ => Err(SyscallError::NotSupported),
     }
 }
 
-fn do_test_interface(function: usize, args: [usize; 3]) -> SyscallOperation {
+fn do_test_interface(function: usize, args: [usize; 3], task_id: usize) -> Result<SyscallOperation, SyscallError> {
     match function {
         FUNCTION_TEST_WRITE => { // fd: usize, buffer: &[u8] fd, buffer.as_ptr() as usize, buffer.len()
             const STDOUT: usize = 1;
             let [fd, buf, len] = args;
-            if fd == STDOUT {
-                let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
-                let str = core::str::from_utf8(slice).unwrap();
-                print!("{}", str);
-                SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize })
-            } else {
-                panic!("Unsupported fd {}", fd);
+            if fd != STDOUT {
+                return Err(SyscallError::BadFd(fd));
             }
+            print_user_str(task_id, UserSlice::new(buf, len), len)?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize }))
         },
-        _ => panic!("Unknown syscall TEST_INTERFACE,function: {}, arg: {:?}", function, args),
+        FUNCTION_TEST_READ => { // fd: usize, buffer: &mut [u8]，没有输入时阻塞，返回读到的字节数
+            const STDIN: usize = 0;
+            let [fd, buf, len] = args;
+            if fd != STDIN {
+                return Err(SyscallError::BadFd(fd));
+            }
+            let slice = UserSlice::new(buf, len);
+            slice.validate(task_id)?;
+            if len == 0 {
+                return Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: 0 }));
+            }
+            let mut kernel_buf = [0u8; 64];
+            let want = core::cmp::min(len, kernel_buf.len());
+            let n = loop {
+                let n = crate::console::take_input(&mut kernel_buf[..want]);
+                if n != 0 {
+                    break n;
+                }
+                crate::console::INPUT_QUEUE.wait();
+            };
+            slice.write_at(task_id, 0, &kernel_buf[..n])?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: n }))
+        },
+        _ => Err(SyscallError::NotSupported),
     }
 }
 
-fn do_task(function: usize) -> SyscallOperation {
+fn do_task(function: usize, arg: usize) -> Result<SyscallOperation, SyscallError> {
     match function {
-        FUNCTION_TASK_YIELD => SyscallOperation::Yield,
-        _ => panic!("Unknown syscall TASK, function: {}", function),
+        FUNCTION_TASK_YIELD => Ok(SyscallOperation::Yield),
+        FUNCTION_TASK_GET_TIME => { // 返回开机以来经过的毫秒数
+            let ms = crate::timer::get_time_ms();
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: ms }))
+        },
+        FUNCTION_TASK_SET_PRIORITY => { // priority: usize，数字越大越优先
+            if arg == 0 {
+                return Err(SyscallError::InvalidArgument);
+            }
+            crate::task::TASK_MANAGER.set_current_priority(arg);
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: 0 }))
+        },
+        FUNCTION_TASK_SLEEP => { // ms: usize，阻塞到经过这么多毫秒
+            let deadline = crate::timer::get_time_ms().saturating_add(arg);
+            while crate::timer::get_time_ms() < deadline {
+                crate::timer::TICK_QUEUE.wait();
+            }
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: 0 }))
+        },
+        _ => Err(SyscallError::NotSupported),
     }
 }
 
-unsafe fn user_buffer<'a>(app_id: usize, user_ptr: usize, len: usize) -> Option<&'a [u8]> {
-    if user_ptr == 0 {
-        None
-    } else {
-        let kernel_ptr = crate::loader::get_ptr(app_id, user_ptr);
-        Some(core::slice::from_raw_parts(kernel_ptr as *const u8, len))
+// 分段复制用户的字符串并打印，不需要在内核中分配内存。不是合法UTF-8的字节打印为替换字符
+fn print_user_str(task_id: usize, slice: UserSlice, len: usize) -> Result<(), BadAddress> {
+    slice.validate(task_id)?;
+    let mut buf = [0u8; 64];
+    let (mut offset, mut kept) = (0, 0); // kept: 上一段末尾被截断的字符，已经移到buf的开头
+    while offset < len {
+        let n = core::cmp::min(buf.len() - kept, len - offset);
+        slice.read_at(task_id, offset, &mut buf[kept..kept + n])?;
+        offset += n;
+        let (mut start, end) = (0, kept + n);
+        kept = 0;
+        while start < end {
+            let (valid, error_len) = match core::str::from_utf8(&buf[start..end]) {
+                Ok(_) => (end, None),
+                Err(e) => (start + e.valid_up_to(), Some(e.error_len())),
+            };
+            // note(unsafe): from_utf8检查过，[start, valid)是合法的UTF-8
+            print!("{}", unsafe { core::str::from_utf8_unchecked(&buf[start..valid]) });
+            start = match error_len {
+                None => end,
+                Some(Some(bad)) => {
+                    print!("\u{FFFD}");
+                    valid + bad
+                },
+                Some(None) if offset < len => { // 字符被截断了，留给下一段
+                    buf.copy_within(valid..end, 0);
+                    kept = end - valid;
+                    end
+                },
+                Some(None) => {
+                    print!("\u{FFFD}");
+                    end
+                },
+            };
+        }
     }
+    Ok(())
 }
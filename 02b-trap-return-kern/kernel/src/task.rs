@@ -1,12 +1,18 @@
-use core::cell::RefCell;
-use crate::loader::{init_app_ctx, get_num_app};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::loader::{self, TaskMemory, LoadError};
+use crate::sched::{Scheduler, DefaultScheduler};
 
-const MAX_APP_NUM: usize = 16;
-
-#[derive(Copy, Clone)]
 pub struct TaskControlBlock {
     pub task_cx_ptr: usize, // Option<*mut TaskContext>,
     pub task_status: TaskStatus,
+    name: &'static str,
+    // 任务结束后，内存先于任务控制块回收
+    memory: Option<TaskMemory>,
+    // 创建这个任务的任务；父任务结束后，变为None
+    parent: Option<usize>,
+    children: Vec<usize>,
+    exit_status: Option<ExitStatus>,
 }
 
 impl TaskControlBlock {
@@ -17,28 +23,86 @@ impl TaskControlBlock {
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskStatus {
-    Uninitialized,
     Ready,
     Running,
+    // 等待某个事件，不会被调度，直到被唤醒
+    Blocked,
     Finished,
 }
 
+// 任务是怎样结束的，父任务等待子任务时得到
+#[derive(Copy, Clone, Debug)]
+pub enum ExitStatus {
+    // 任务调用了exit，包含退出码
+    Exited(i32),
+    // 用户程序panic了，包含出错的行和列
+    Panicked { line: u32, col: u32 },
+    // 任务出错，被内核结束
+    Killed,
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    // 内核镜像中没有这个名字的程序
+    NotFound,
+    // 内核堆不够，不能再创建任务控制块
+    OutOfMemory,
+    Load(LoadError),
+}
+
+impl From<LoadError> for SpawnError {
+    fn from(src: LoadError) -> SpawnError {
+        SpawnError::Load(src)
+    }
+}
+
 pub struct TaskManager {
-    num_app: usize,
-    inner: RefCell<TaskManagerInner>,
+    inner: spin::Mutex<TaskManagerInner>,
 }
 
+// 任务控制块放在堆上，任务的数量只受内存大小限制。
+// 父子任务组成一棵树：结束的任务留下任务控制块，直到父任务等待它；没有父任务的任务结束后直接回收
 struct TaskManagerInner {
-    tasks: [TaskControlBlock; MAX_APP_NUM],
+    tasks: BTreeMap<usize, TaskControlBlock>,
     current_task: usize,
+    next_task_id: usize,
+    scheduler: DefaultScheduler,
 }
 
-unsafe impl Sync for TaskManager {}
-
 impl TaskManager {
+    // 按名字找到程序，创建一个就绪的任务，返回任务编号。任务编号从1开始，0表示没有任务
+    pub fn spawn(&self, name: &str, parent: Option<usize>) -> Result<usize, SpawnError> {
+        let app = loader::find_app(name).ok_or(SpawnError::NotFound)?;
+        crate::mm::try_reserve_heap().map_err(|_| SpawnError::OutOfMemory)?;
+        let memory = TaskMemory::try_load(&app)?;
+        let mut inner = self.inner.lock();
+        let task_id = inner.next_task_id;
+        inner.next_task_id += 1;
+        let task_cx_ptr = memory.init_context(task_id);
+        inner.tasks.insert(task_id, TaskControlBlock {
+            task_cx_ptr,
+            task_status: TaskStatus::Ready,
+            name: app.name(),
+            memory: Some(memory),
+            parent,
+            children: Vec::new(),
+            exit_status: None,
+        });
+        if let Some(parent) = parent {
+            inner.tasks.get_mut(&parent).unwrap().children.push(task_id);
+        }
+        inner.scheduler.add(task_id);
+        Ok(task_id)
+    }
+
     pub fn run_first_task(&self) {
-        self.inner.borrow_mut().tasks[0].task_status = TaskStatus::Running;
-        let next_task_ctx = self.inner.borrow().tasks[0].task_cx_ptr;
+        let mut inner = self.inner.lock();
+        let first = inner.scheduler.pick_next().expect("no task to run");
+        inner.current_task = first;
+        let task = inner.tasks.get_mut(&first).unwrap();
+        task.task_status = TaskStatus::Running;
+        let next_task_ctx = task.task_cx_ptr;
+        core::mem::drop(inner);
         let _unused = 0;
         unsafe {
             switch_task(
@@ -48,36 +112,136 @@ impl TaskManager {
         }
     }
 
+    pub fn current_task_id(&self) -> usize {
+        self.inner.lock().current_task
+    }
+
+    // 任务可以访问的用户内存
+    pub fn user_ranges(&self, task_id: usize) -> Option<[core::ops::Range<usize>; 2]> {
+        self.inner.lock().tasks.get(&task_id)?.memory.as_ref().map(|memory| memory.user_ranges())
+    }
+
+    pub fn current_parent_id(&self) -> Option<usize> {
+        let inner = self.inner.lock();
+        inner.tasks[&inner.current_task].parent
+    }
+
+    // 时钟中断时询问调度器，返回是否要切换到别的任务
+    pub fn on_tick(&self) -> bool {
+        let mut inner = self.inner.lock();
+        let current = inner.current_task;
+        inner.scheduler.on_tick(current)
+    }
+
+    pub fn set_current_priority(&self, priority: usize) {
+        let mut inner = self.inner.lock();
+        let current = inner.current_task;
+        inner.scheduler.set_priority(current, priority);
+    }
+
     fn mark_current_suspended(&self) {
-        let mut inner = self.inner.borrow_mut();
+        let mut inner = self.inner.lock();
         let current = inner.current_task;
-        inner.tasks[current].task_status = TaskStatus::Ready;
+        inner.tasks.get_mut(&current).unwrap().task_status = TaskStatus::Ready;
+        inner.scheduler.add(current);
     }
 
-    fn mark_current_finished(&self) {
-        let mut inner = self.inner.borrow_mut();
+    fn mark_current_blocked(&self) {
+        let mut inner = self.inner.lock();
         let current = inner.current_task;
-        inner.tasks[current].task_status = TaskStatus::Finished;
+        inner.tasks.get_mut(&current).unwrap().task_status = TaskStatus::Blocked;
     }
 
-    fn find_next_task(&self) -> Option<usize> {
-        let inner = self.inner.borrow();
+    fn mark_current_finished(&self, status: ExitStatus) {
+        let mut inner = self.inner.lock();
         let current = inner.current_task;
-        (current + 1..current + self.num_app + 1)
-            .map(|id| id % self.num_app)
-            .find(|id| {
-                inner.tasks[*id].task_status == TaskStatus::Ready
-            })
+        let task = inner.tasks.get_mut(&current).unwrap();
+        task.task_status = TaskStatus::Finished;
+        task.exit_status = Some(status);
+        println!("[kernel] Task #{} ({}) finished: {:?}", current, task.name, status);
+        let children = core::mem::take(&mut task.children);
+        inner.scheduler.remove(current);
+        // 子任务不再有父任务，结束后直接回收
+        for child in children {
+            inner.tasks.get_mut(&child).unwrap().parent = None;
+        }
+    }
+
+    // 唤醒阻塞的任务，让它重新参与调度
+    fn wake(&self, task_id: usize) {
+        let mut inner = self.inner.lock();
+        match inner.tasks.get_mut(&task_id) {
+            Some(task) if task.task_status == TaskStatus::Blocked => {
+                task.task_status = TaskStatus::Ready;
+                inner.scheduler.add(task_id);
+            },
+            _ => {},
+        }
+    }
+
+    // 找到当前任务一个已经结束的子任务，回收它，返回它的编号和结束状态。child为None时可以是任意一个子任务。
+    // 符合条件的子任务都还没有结束时返回Ok(None)；没有符合条件的子任务时返回Err(())
+    pub fn collect_child(&self, child: Option<usize>) -> Result<Option<(usize, ExitStatus)>, ()> {
+        let mut inner = self.inner.lock();
+        let current = inner.current_task;
+        let children = &inner.tasks[&current].children;
+        let candidates: Vec<usize> = children.iter().copied().filter(|id| child.map_or(true, |c| c == *id)).collect();
+        if candidates.is_empty() {
+            return Err(())
+        }
+        let exited = candidates.into_iter().find(|id| inner.tasks[id].task_status == TaskStatus::Finished);
+        let id = match exited {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        inner.tasks.get_mut(&current).unwrap().children.retain(|c| *c != id);
+        let task = inner.tasks.remove(&id).unwrap();
+        Ok(Some((id, task.exit_status.unwrap())))
+    }
+
+    fn has_blocked_tasks(&self) -> bool {
+        self.inner.lock().tasks.values().any(|task| task.task_status == TaskStatus::Blocked)
+    }
+
+    // 回收已经结束的任务。当前任务还在用自己的内核栈，等切换走以后再回收。
+    // 有父任务的任务只回收内存，任务控制块留给父任务等待
+    fn reap_finished(&self) {
+        let mut inner = self.inner.lock();
+        let current = inner.current_task;
+        let finished: Vec<usize> = inner.tasks.iter()
+            .filter(|(id, task)| **id != current && task.task_status == TaskStatus::Finished)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished {
+            let task = inner.tasks.get_mut(&id).unwrap();
+            task.memory = None;
+            if task.parent.is_none() {
+                inner.tasks.remove(&id);
+            }
+        }
     }
 
     fn run_next_task(&self) {
-        if let Some(next) = self.find_next_task() {
-            let mut inner = self.inner.borrow_mut();
+        self.reap_finished();
+        let next = loop {
+            let next = self.inner.lock().scheduler.pick_next();
+            match next {
+                Some(next) => break Some(next),
+                None if self.has_blocked_tasks() => idle(),
+                None => break None,
+            }
+        };
+        if let Some(next) = next {
+            let mut inner = self.inner.lock();
             let current = inner.current_task;
-            inner.tasks[next].task_status = TaskStatus::Running;
+            inner.tasks.get_mut(&next).unwrap().task_status = TaskStatus::Running;
+            if next == current {
+                return; // 只剩下当前任务可以运行，继续运行它，不需要切换
+            }
             inner.current_task = next;
-            let current_task_ctx2 = inner.tasks[current].get_task_ctx_mut2();
-            let next_task_ctx = inner.tasks[next].task_cx_ptr;
+            let next_task_ctx = inner.tasks[&next].task_cx_ptr;
+            // 切换时立即写入这个地址，这期间任务表不会变化
+            let current_task_ctx2 = inner.tasks.get_mut(&current).unwrap().get_task_ctx_mut2();
             core::mem::drop(inner);
             unsafe {
                 switch_task(
@@ -97,29 +261,73 @@ pub fn suspend_current_and_run_next() {
     TASK_MANAGER.run_next_task();
 }
 
-pub fn exit_current_and_run_next() {
-    TASK_MANAGER.mark_current_finished();
+pub fn block_current_and_run_next() {
+    TASK_MANAGER.mark_current_blocked();
     TASK_MANAGER.run_next_task();
 }
 
-lazy_static::lazy_static! {
-    pub static ref TASK_MANAGER: TaskManager = {
-        let num_app = get_num_app();
-        let mut tasks = [
-            TaskControlBlock { task_cx_ptr: 0, task_status: TaskStatus::Uninitialized };
-            MAX_APP_NUM
-        ];
-        for i in 0..num_app {
-            tasks[i].task_cx_ptr = init_app_ctx(i) as *const _ as usize;
-            tasks[i].task_status = TaskStatus::Ready;
+pub fn exit_current_and_run_next(status: ExitStatus) {
+    TASK_MANAGER.mark_current_finished(status);
+    EXIT_QUEUE.wake_all();
+    TASK_MANAGER.run_next_task();
+}
+
+// 内核结束出错的任务
+pub fn kill_current_and_run_next() {
+    exit_current_and_run_next(ExitStatus::Killed)
+}
+
+// 没有可以运行的任务，但是还有阻塞的任务，等待中断把它们唤醒。
+// 内核态没有打开sstatus.SIE，中断不会陷入内核，wfi在中断等待处理时返回，这里自己处理时钟中断
+fn idle() {
+    unsafe { asm!("wfi") };
+    if riscv::register::sip::read().stimer() {
+        crate::timer::tick();
+    }
+}
+
+// 阻塞的任务排成的队列。任务在队列上等待某个事件，事件发生时由唤醒者唤醒
+pub struct WaitQueue {
+    waiters: spin::Mutex<Vec<usize>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters: spin::Mutex::new(Vec::new()) }
+    }
+    // 当前任务阻塞在这个队列上，被唤醒后返回。被唤醒不代表等待的条件已经满足，调用者需要重新检查
+    pub fn wait(&self) {
+        self.waiters.lock().push(TASK_MANAGER.current_task_id());
+        block_current_and_run_next();
+    }
+    #[allow(unused)]
+    pub fn wake_one(&self) {
+        let mut waiters = self.waiters.lock();
+        if !waiters.is_empty() {
+            let task_id = waiters.remove(0);
+            core::mem::drop(waiters);
+            TASK_MANAGER.wake(task_id);
         }
-        TaskManager {
-            num_app,
-            inner: RefCell::new(TaskManagerInner {
-                tasks,
-                current_task: 0,
-            }),
+    }
+    pub fn wake_all(&self) {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for task_id in waiters {
+            TASK_MANAGER.wake(task_id);
         }
+    }
+}
+
+// 等待子任务结束的队列，任何任务结束时唤醒全部等待者
+pub static EXIT_QUEUE: WaitQueue = WaitQueue::new();
+
+lazy_static::lazy_static! {
+    pub static ref TASK_MANAGER: TaskManager = TaskManager {
+        inner: spin::Mutex::new(TaskManagerInner {
+            tasks: BTreeMap::new(),
+            current_task: 0,
+            next_task_id: 1,
+            scheduler: DefaultScheduler::default(),
+        }),
     };
 }
 
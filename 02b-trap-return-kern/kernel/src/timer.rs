@@ -0,0 +1,68 @@
+//! 时钟中断模块
+//!
+//! 每个时间片结束时产生一次时钟中断，内核借此切换到下一个任务，不让一直不让出的程序占住处理核。
+//! 时钟中断也用来唤醒睡眠的任务，和查看有没有新的键盘输入
+
+use riscv::register::{sie, time};
+use crate::{sbi, console, task::WaitQueue};
+
+// time寄存器每秒增加的次数，和qemu的virt平台一致
+pub const CLOCK_FREQ: u64 = 10_000_000;
+// 每秒的时间片数，时间片越短，切换越频繁。编译时由环境变量TICKS_PER_SEC设置，没有设置时为100
+const TICKS_PER_SEC: u64 = match option_env!("TICKS_PER_SEC") {
+    Some(s) => parse_ticks_per_sec(s),
+    None => 100,
+};
+
+// 编译时解析十进制的时间片数，不合法时编译失败
+const fn parse_ticks_per_sec(s: &str) -> u64 {
+    let bytes = s.as_bytes();
+    if bytes.is_empty() {
+        panic!("TICKS_PER_SEC is empty")
+    }
+    let mut ans: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = bytes[i];
+        if digit < b'0' || digit > b'9' {
+            panic!("TICKS_PER_SEC is not a decimal number")
+        }
+        ans = ans * 10 + (digit - b'0') as u64;
+        if ans > CLOCK_FREQ {
+            panic!("TICKS_PER_SEC is larger than the clock frequency")
+        }
+        i += 1;
+    }
+    if ans == 0 {
+        panic!("TICKS_PER_SEC must not be zero")
+    }
+    ans
+}
+const MSEC_PER_SEC: u64 = 1000;
+
+// 打开时钟中断，设置第一个时间片。用户态的中断总是会陷入内核，内核态没有打开sstatus.SIE，不会被时钟中断打断
+pub fn init() {
+    unsafe { sie::set_stimer() };
+    set_next_trigger();
+}
+
+// 每次时钟中断唤醒的队列，睡眠的任务在这里等待，醒来后检查时间是否已经到了
+pub static TICK_QUEUE: WaitQueue = WaitQueue::new();
+
+// 处理一次时钟中断：设置下一个时间片，读取键盘输入，唤醒睡眠的任务
+pub fn tick() {
+    set_next_trigger();
+    console::poll_input();
+    TICK_QUEUE.wake_all();
+}
+
+// 当前时间片结束后再产生一次时钟中断，同时清除正在等待处理的时钟中断
+pub fn set_next_trigger() {
+    // RV32下time寄存器的高32位在timeh中，需要读出完整的64位时间
+    sbi::set_timer(time::read64() + CLOCK_FREQ / TICKS_PER_SEC);
+}
+
+// 开机以来经过的毫秒数
+pub fn get_time_ms() -> usize {
+    (time::read64() / (CLOCK_FREQ / MSEC_PER_SEC)) as usize
+}
@@ -1,6 +1,6 @@
 use riscv::register::{
     sstatus::{self, Sstatus, SPP},
-    scause::{self, Trap, Exception}, stval,
+    scause::{self, Trap, Exception, Interrupt}, stval,
 };
 use crate::syscall::{syscall, SyscallOperation};
 
@@ -42,13 +42,13 @@ pub struct TrapContext {
 }
 
 impl TrapContext {
-    pub fn app_init_context(entry: usize, app_id: usize, sp: usize) -> Self {
+    pub fn app_init_context(entry: usize, task_id: usize, sp: usize) -> Self {
         unsafe { sstatus::set_spp(SPP::User) };
         let mut ctx: TrapContext = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
         ctx.sstatus = sstatus::read();
         ctx.sepc = entry;
         ctx.sp = sp;
-        ctx.tp = app_id;
+        ctx.tp = task_id;
         ctx
     }
 }
@@ -58,8 +58,8 @@ extern "C" fn rust_trap_handler(ctx: &mut TrapContext) {
     let stval = stval::read();
     match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
-            let app_id = ctx.tp;
-            match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5], app_id) {
+            let task_id = crate::task::TASK_MANAGER.current_task_id();
+            match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5], task_id) {
                 SyscallOperation::Return(ans) => {
                     ctx.a0 = ans.code;
                     ctx.a1 = ans.extra;
@@ -67,13 +67,13 @@ extern "C" fn rust_trap_handler(ctx: &mut TrapContext) {
                 }
                 SyscallOperation::Terminate(code) => {
                     println!("[Kernel] Process returned with code {}", code);
-                    crate::task::exit_current_and_run_next()
+                    crate::task::exit_current_and_run_next(crate::task::ExitStatus::Exited(code))
                 }
                 SyscallOperation::UserPanic(file, line, col, msg) => {
-                    let file = file.unwrap_or("<no file>");
-                    let msg = msg.unwrap_or("<no message>");
+                    let file = file.as_deref().unwrap_or("<no file>");
+                    let msg = msg.as_deref().unwrap_or("<no message>");
                     println!("[Kernel] User process panicked at '{}', {}:{}:{}", msg, file, line, col);
-                    crate::task::exit_current_and_run_next()
+                    crate::task::exit_current_and_run_next(crate::task::ExitStatus::Panicked { line, col })
                 }
                 SyscallOperation::Yield => {
                     // println!("[Kernel] Task yielded.");
@@ -81,6 +81,13 @@ extern "C" fn rust_trap_handler(ctx: &mut TrapContext) {
                 }
             }
         }
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            // 时间片用完了，由调度器决定是否切换到别的任务
+            crate::timer::tick();
+            if crate::task::TASK_MANAGER.on_tick() {
+                crate::task::suspend_current_and_run_next()
+            }
+        }
         Trap::Exception(Exception::StoreFault) |
         Trap::Exception(Exception::StorePageFault) => {
             panic!("[kernel] PageFault in application, core dumped.");
@@ -4,16 +4,22 @@ use riscv::register::{
 };
 
 unsafe extern "C" fn kernel_trap_handler(ctx: &mut KernelTrapContext) {
-    println!("{:x?}", ctx);
     let scause = scause::read();
     let stval = stval::read();
     match scause.cause() {
         Trap::Exception(Exception::LoadFault) |
         Trap::Exception(Exception::StoreFault) => {
+            // 复制用户内存时出错，跳到修复地址，由系统调用返回错误
+            if let Some(fixup) = crate::uaccess::search_exception_table(sepc::read()) {
+                sepc::write(fixup);
+                return;
+            }
+            println!("{:x?}", ctx);
             println!("[kernel] User provided illegal address {:#x}, kill this process", stval);
-            sepc::write(crate::task::exit_current_and_run_next as usize);
+            sepc::write(crate::task::kill_current_and_run_next as usize);
         },
         _ => {
+            println!("{:x?}", ctx);
             panic!("Kernel trap {:?}, stval = {:#x}!", scause.cause(), stval);
         }
     }
@@ -0,0 +1,124 @@
+//! 用户内存访问模块
+//!
+//! 用户传来的指针先检查是否落在这个程序可以访问的内存里，然后由汇编写成的复制函数读出。
+//! 复制函数中读写内存的指令登记在异常修复表（.ex_table段）中，复制时产生访问异常，
+//! kernel_trap会跳到修复地址，系统调用返回EFAULT，而不是结束整个程序
+
+use crate::task::TASK_MANAGER;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// 用户传来的地址不合法，包含出错的用户地址
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BadAddress(pub usize);
+
+// 用户内存中的一段字节
+#[derive(Copy, Clone, Debug)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+    // 用户传来空指针，通常表示没有这个参数
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+    // 检查这段内存完整地落在程序可以访问的某个区域中
+    pub fn validate(&self, task_id: usize) -> Result<(), BadAddress> {
+        let end = self.addr.checked_add(self.len).ok_or(BadAddress(self.addr))?;
+        let ranges = TASK_MANAGER.user_ranges(task_id).ok_or(BadAddress(self.addr))?;
+        if ranges.iter().any(|range| range.start <= self.addr && end <= range.end) {
+            Ok(())
+        } else {
+            Err(BadAddress(self.addr))
+        }
+    }
+    // 检查后把这段内存复制为字符串。地址不合法或者不是UTF-8时返回None。
+    // 复制到内核的堆上，任务回收后仍然可以使用
+    pub fn read_string(&self, task_id: usize) -> Option<String> {
+        String::from_utf8(self.read_bytes(task_id).ok()?).ok()
+    }
+    // 检查后把整段内存复制到内核的堆上
+    pub fn read_bytes(&self, task_id: usize) -> Result<Vec<u8>, BadAddress> {
+        self.validate(task_id)?;
+        let mut bytes = vec![0u8; self.len];
+        self.read_at(task_id, 0, &mut bytes)?;
+        Ok(bytes)
+    }
+    // 从offset开始，复制dst.len()个字节到dst
+    pub fn read_at(&self, task_id: usize, offset: usize, dst: &mut [u8]) -> Result<(), BadAddress> {
+        self.validate(task_id)?;
+        match offset.checked_add(dst.len()) {
+            Some(end) if end <= self.len => {},
+            _ => return Err(BadAddress(self.addr.wrapping_add(offset))),
+        }
+        let src = self.addr + offset;
+        // note(unsafe): 范围检查过了；复制时出错会由异常修复表处理
+        let left = unsafe { copy_user_bytes(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+        if left != 0 {
+            return Err(BadAddress(src + dst.len() - left))
+        }
+        Ok(())
+    }
+    // 从offset开始，把src复制到这段内存中
+    pub fn write_at(&self, task_id: usize, offset: usize, src: &[u8]) -> Result<(), BadAddress> {
+        self.validate(task_id)?;
+        match offset.checked_add(src.len()) {
+            Some(end) if end <= self.len => {},
+            _ => return Err(BadAddress(self.addr.wrapping_add(offset))),
+        }
+        let dst = self.addr + offset;
+        // note(unsafe): 范围检查过了；复制时出错会由异常修复表处理
+        let left = unsafe { copy_user_bytes(dst as *mut u8, src.as_ptr(), src.len()) };
+        if left != 0 {
+            return Err(BadAddress(dst + src.len() - left))
+        }
+        Ok(())
+    }
+}
+
+// 异常修复表的一项：出错的指令地址，和出错后跳转到的地址
+#[repr(C)]
+struct ExceptionEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+// 查找出错的指令在不在异常修复表中，返回修复地址
+pub fn search_exception_table(insn: usize) -> Option<usize> {
+    extern "C" { fn sex_table(); fn eex_table(); }
+    let (start, end) = (sex_table as usize, eex_table as usize);
+    let len = (end - start) / core::mem::size_of::<ExceptionEntry>();
+    // note(unsafe): 表由链接脚本放在只读数据段中
+    let table = unsafe { core::slice::from_raw_parts(start as *const ExceptionEntry, len) };
+    table.iter().find(|entry| entry.insn == insn).map(|entry| entry.fixup)
+}
+
+// 按字节复制len个字节，返回没有复制的字节数；全部复制完成时返回0。
+// 读写内存的指令都登记在异常修复表中，出错时跳到3，这时a2正好是剩下的字节数
+#[naked]
+#[link_section = ".text"]
+unsafe extern "C" fn copy_user_bytes(_dst: *mut u8, _src: *const u8, _len: usize) -> usize {
+    asm!( // a0:目的地址, a1:源地址, a2:剩下的字节数
+        "beqz   a2, 3f",
+        "1: lb  t0, 0(a1)",
+        "2: sb  t0, 0(a0)",
+        "addi   a0, a0, 1
+        addi    a1, a1, 1
+        addi    a2, a2, -1
+        bnez    a2, 1b",
+        "3: mv  a0, a2
+        ret",
+        ".pushsection .ex_table, \"a\"
+        .p2align 3
+        .quad   1b, 3b
+        .quad   2b, 3b
+        .popsection",
+        options(noreturn)
+    )
+}
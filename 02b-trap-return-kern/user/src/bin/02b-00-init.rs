@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate trap_return_user;
+
+use trap_return_user::{spawn, waitpid, getpid, ExitStatus};
+
+// 依次创建的测试程序
+const TESTS: &[&str] = &[
+    "02b-01-test-user",
+    "02b-02-normal-user",
+    "02b-03-busy-loop",
+    "02b-04-prio-high",
+    "02b-05-prio-low",
+    "02b-06-sleep",
+];
+
+// 第一个任务：创建所有测试程序，等待它们结束，收集结果
+#[no_mangle]
+fn main() -> i32 {
+    println!("[init] Init task #{}", getpid().unwrap());
+    let mut spawned = 0;
+    for name in TESTS {
+        match spawn(name) {
+            Ok(pid) => {
+                println!("[init] Spawned {} as #{}", name, pid);
+                spawned += 1;
+            },
+            Err(e) => println!("[init] Failed to spawn {}: {:?}", name, e),
+        }
+    }
+    let mut failed = 0;
+    for _ in 0..spawned {
+        let (pid, status) = waitpid(None).unwrap();
+        if status != ExitStatus::Exited(0) {
+            failed += 1;
+        }
+        println!("[init] Task #{} finished: {:?}", pid, status);
+    }
+    println!("[init] {} tests, {} failed", spawned, failed);
+    failed
+}
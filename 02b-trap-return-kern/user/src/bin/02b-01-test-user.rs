@@ -8,9 +8,10 @@ extern crate trap_return_user;
 #[no_mangle]
 fn main() -> i32 {
     println!("Test user!");
-    // pub fn write(fd: usize, buf: &[u8]) -> SyscallResult { sys_write(fd, buf) }
+    // 内核检查出地址不合法，返回错误而不是结束程序
     let illegal_buffer = unsafe { core::slice::from_raw_parts(0x233333666666 as *const _, 10) };
-    trap_return_user::write(1, illegal_buffer); // trigger EILL
+    let ans = trap_return_user::write(1, illegal_buffer);
+    println!("Write to illegal buffer: {:?}", ans);
     println!("After test user!");
     0
 }
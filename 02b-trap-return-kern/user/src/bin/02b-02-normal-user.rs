@@ -7,6 +7,7 @@ extern crate trap_return_user;
 
 #[no_mangle]
 fn main() -> i32 {
-    println!("Test user 2");
+    let (pid, ppid) = (trap_return_user::getpid().unwrap(), trap_return_user::getppid().unwrap());
+    println!("Test user 2, pid = {}, parent = {}", pid, ppid);
     0
 }
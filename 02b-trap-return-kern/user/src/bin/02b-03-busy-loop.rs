@@ -0,0 +1,20 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate trap_return_user;
+
+#[no_mangle]
+fn main() -> i32 {
+    println!("Busy loop start");
+    let start = trap_return_user::get_time().unwrap();
+    // 一直不让出处理核，由时钟中断切换到别的任务
+    let mut ans: usize = 1;
+    for i in 0..50_000_000usize {
+        ans = ans.wrapping_mul(3).wrapping_add(i);
+    }
+    let end = trap_return_user::get_time().unwrap();
+    println!("Busy loop end, ans = {:#x}, time = {}ms", ans, end - start);
+    0
+}
@@ -0,0 +1,22 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate trap_return_user;
+
+const PRIORITY: usize = 32;
+
+// 和其它计算任务同时运行，比较不同调度策略下完成的先后和用时
+#[no_mangle]
+fn main() -> i32 {
+    trap_return_user::set_priority(PRIORITY).unwrap();
+    let start = trap_return_user::get_time().unwrap();
+    let mut ans: usize = 1;
+    for i in 0..20_000_000usize {
+        ans = ans.wrapping_mul(3).wrapping_add(i);
+    }
+    let end = trap_return_user::get_time().unwrap();
+    println!("Priority {} done, ans = {:#x}, time = {}ms", PRIORITY, ans, end - start);
+    0
+}
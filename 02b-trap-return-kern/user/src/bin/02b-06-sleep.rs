@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate trap_return_user;
+
+// 睡眠时任务阻塞，不占用处理核，别的任务可以运行
+#[no_mangle]
+fn main() -> i32 {
+    let start = trap_return_user::get_time().unwrap();
+    trap_return_user::sleep(500).unwrap();
+    let end = trap_return_user::get_time().unwrap();
+    println!("Slept for {}ms", end - start);
+    0
+}
@@ -14,7 +14,7 @@ pub mod console {
     
     impl Write for Stdout {
         fn write_str(&mut self, s: &str) -> fmt::Result {
-            write(STDOUT, s.as_bytes());
+            write(STDOUT, s.as_bytes()).map_err(|_| fmt::Error)?;
             Ok(())
         }
     }
@@ -43,9 +43,9 @@ pub mod console {
 fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
     let err = panic_info.message().unwrap().as_str();
     if let Some(location) = panic_info.location() {
-        sys_panic(Some(location.file()), location.line(), location.column(), err);
+        let _ = sys_panic(Some(location.file()), location.line(), location.column(), err);
     } else {
-        sys_panic(None, 0, 0, err);
+        let _ = sys_panic(None, 0, 0, err);
     }
     loop {}
 }
@@ -57,7 +57,7 @@ pub extern "C" fn _start() -> ! {
         fn sbss(); fn ebss();
     } 
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
-    exit(main());
+    let _ = exit(main());
     panic!("unreachable after sys_exit!");
 }
 
@@ -68,27 +68,111 @@ fn main() -> i32 {
 }
 
 use syscall::*;
+pub use syscall::SyscallError;
 
-pub fn write(fd: usize, buf: &[u8]) -> SyscallResult { sys_write(fd, buf) }
-pub fn exit(exit_code: i32) -> SyscallResult { sys_exit(exit_code) }
-pub fn do_yield() -> SyscallResult { sys_yield() }
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, SyscallError> { sys_write(fd, buf) }
+// 没有输入时阻塞，返回读到的字节数
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, SyscallError> { sys_read(fd, buf) }
+pub fn exit(exit_code: i32) -> Result<usize, SyscallError> { sys_exit(exit_code) }
+pub fn do_yield() -> Result<usize, SyscallError> { sys_yield() }
+// 开机以来经过的毫秒数
+pub fn get_time() -> Result<usize, SyscallError> { sys_get_time() }
+// 设置当前任务的优先级，数字越大越优先，不能为0。轮转调度的内核忽略优先级
+pub fn set_priority(priority: usize) -> Result<usize, SyscallError> { sys_set_priority(priority) }
+// 睡眠至少ms毫秒
+pub fn sleep(ms: usize) -> Result<usize, SyscallError> { sys_sleep(ms) }
+// 按名字创建子任务，返回子任务的编号
+pub fn spawn(name: &str) -> Result<usize, SyscallError> { sys_spawn(name) }
+pub fn getpid() -> Result<usize, SyscallError> { sys_getpid() }
+// 父任务的编号，没有父任务时返回0
+pub fn getppid() -> Result<usize, SyscallError> { sys_getppid() }
+
+// 子任务是怎样结束的
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExitStatus {
+    Exited(i32),
+    Panicked { line: u32, col: u32 },
+    // 任务出错，被内核结束
+    Killed,
+}
+
+// 等待子任务结束，pid为None时等待任意一个子任务。返回结束的子任务编号和它的结束状态
+pub fn waitpid(pid: Option<usize>) -> Result<(usize, ExitStatus), SyscallError> {
+    let mut info = [0usize; 4];
+    let child = sys_waitpid(pid.unwrap_or(usize::MAX), &mut info)?;
+    let status = match info {
+        [0, code, _, _] => ExitStatus::Exited(code as i32),
+        [1, _, line, col] => ExitStatus::Panicked { line: line as u32, col: col as u32 },
+        _ => ExitStatus::Killed,
+    };
+    Ok((child, status))
+}
 
 mod syscall {
     const MODULE_PROCESS: usize = 0x114514;
     const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
     const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+    const FUNCTION_PROCESS_WAIT: usize = 0x19198100;
+    const FUNCTION_PROCESS_SPAWN: usize = 0x5201314;
+    const FUNCTION_PROCESS_GETPID: usize = 0x1008611;
+    const FUNCTION_PROCESS_GETPPID: usize = 0x1008612;
 
     const MODULE_TEST_INTERFACE: usize = 0x233666;
     const FUNCTION_TEST_WRITE: usize = 0x666233;
+    const FUNCTION_TEST_READ: usize = 0x666666;
 
     const MODULE_TASK: usize = 0x7777777;
     const FUNCTION_TASK_YIELD: usize = 0x9999999;
+    const FUNCTION_TASK_GET_TIME: usize = 0x8888888;
+    const FUNCTION_TASK_SET_PRIORITY: usize = 0x6666666;
+    const FUNCTION_TASK_SLEEP: usize = 0x5555555;
 
     pub struct SyscallResult {
         pub code: usize,
         pub extra: usize,
     }
 
+    // 所有内核和用户库共用的错误码表
+    mod error_code {
+        #![allow(dead_code)] // 这个库只认识对应的内核会返回的错误
+        include!("../../../syscall_error_code.rs");
+    }
+
+    // 系统调用的错误
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub enum SyscallError {
+        // 不支持的模块或者功能
+        NotSupported,
+        // 不支持的文件描述符
+        BadFd(usize),
+        // 传给内核的地址不合法，包含出错的地址
+        BadAddress(usize),
+        // 参数不合法，比如字符串不是UTF-8编码
+        InvalidArgument,
+        // 内存不足
+        OutOfMemory,
+        // 没有这个名字的程序，或者没有可以等待的子任务
+        NotFound,
+        // 这个库不认识的错误码，包含错误码和附加信息
+        Unknown(usize, usize),
+    }
+    
+    impl SyscallResult {
+        // code为0表示成功，这时extra是返回值；否则按错误码得到错误
+        fn into_result(self) -> Result<usize, SyscallError> {
+            match self.code {
+                0 => Ok(self.extra),
+                error_code::NOT_SUPPORTED => Err(SyscallError::NotSupported),
+                error_code::BAD_FD => Err(SyscallError::BadFd(self.extra)),
+                error_code::BAD_ADDRESS => Err(SyscallError::BadAddress(self.extra)),
+                error_code::INVALID_ARGUMENT => Err(SyscallError::InvalidArgument),
+                error_code::OUT_OF_MEMORY => Err(SyscallError::OutOfMemory),
+                error_code::NOT_FOUND => Err(SyscallError::NotFound),
+                code => Err(SyscallError::Unknown(code, self.extra)),
+            }
+        }
+    }
+
     fn syscall_0(module: usize, function: usize) -> SyscallResult {
         match () {
             #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
@@ -173,24 +257,57 @@ mod syscall {
         }
     }
 
-    pub fn sys_write(fd: usize, buffer: &[u8]) -> SyscallResult {
-        syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
+    pub fn sys_write(fd: usize, buffer: &[u8]) -> Result<usize, SyscallError> {
+        syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()]).into_result()
+    }
+
+    pub fn sys_read(fd: usize, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_READ, [fd, buffer.as_mut_ptr() as usize, buffer.len()]).into_result()
+    }
+
+    pub fn sys_yield() -> Result<usize, SyscallError> {
+        syscall_0(MODULE_TASK, FUNCTION_TASK_YIELD).into_result()
+    }
+
+    pub fn sys_get_time() -> Result<usize, SyscallError> {
+        syscall_0(MODULE_TASK, FUNCTION_TASK_GET_TIME).into_result()
+    }
+
+    pub fn sys_set_priority(priority: usize) -> Result<usize, SyscallError> {
+        syscall_1(MODULE_TASK, FUNCTION_TASK_SET_PRIORITY, priority).into_result()
+    }
+
+    pub fn sys_sleep(ms: usize) -> Result<usize, SyscallError> {
+        syscall_1(MODULE_TASK, FUNCTION_TASK_SLEEP, ms).into_result()
+    }
+
+    pub fn sys_spawn(name: &str) -> Result<usize, SyscallError> {
+        syscall_3(MODULE_PROCESS, FUNCTION_PROCESS_SPAWN, [name.as_ptr() as usize, name.len(), 0]).into_result()
+    }
+
+    pub fn sys_getpid() -> Result<usize, SyscallError> {
+        syscall_0(MODULE_PROCESS, FUNCTION_PROCESS_GETPID).into_result()
+    }
+
+    pub fn sys_getppid() -> Result<usize, SyscallError> {
+        syscall_0(MODULE_PROCESS, FUNCTION_PROCESS_GETPPID).into_result()
     }
 
-    pub fn sys_yield() -> SyscallResult {
-        syscall_0(MODULE_TASK, FUNCTION_TASK_YIELD)
+    // info: 子任务的结束信息，[种类, 退出码, 行, 列]
+    pub fn sys_waitpid(pid: usize, info: &mut [usize; 4]) -> Result<usize, SyscallError> {
+        syscall_3(MODULE_PROCESS, FUNCTION_PROCESS_WAIT, [pid, info.as_mut_ptr() as usize, 0]).into_result()
     }
 
-    pub fn sys_exit(exit_code: i32) -> SyscallResult {
-        syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_EXIT, exit_code as usize)
+    pub fn sys_exit(exit_code: i32) -> Result<usize, SyscallError> {
+        syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_EXIT, exit_code as usize).into_result()
     }
 
-    pub fn sys_panic(file_name: Option<&str>, line: u32, col: u32, msg: Option<&str>) -> SyscallResult {
+    pub fn sys_panic(file_name: Option<&str>, line: u32, col: u32, msg: Option<&str>) -> Result<usize, SyscallError> {
         let (f_buf, f_len) = file_name.map(|s| (s.as_ptr() as usize, s.len())).unwrap_or((0, 0));
         let (m_buf, m_len) = msg.map(|s| (s.as_ptr() as usize, s.len())).unwrap_or((0, 0));
         syscall_6(
             MODULE_PROCESS, FUNCTION_PROCESS_PANIC, 
             [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
-        )
+        ).into_result()
     }
 }
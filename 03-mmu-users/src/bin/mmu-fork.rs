@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate mmu_user;
+
+static mut COUNTER: usize = 100;
+
+#[no_mangle]
+fn main() -> i32 {
+    // 父子进程各自修改自己的那份数据，写时复制后互不影响
+    match mmu_user::fork() {
+        Ok(0) => {
+            unsafe { COUNTER += 1 };
+            println!("Hello from child, counter = {}", unsafe { COUNTER });
+        },
+        Ok(pid) => {
+            unsafe { COUNTER += 10 };
+            println!("Hello from parent, child pid = {}, counter = {}", pid, unsafe { COUNTER });
+        },
+        Err(e) => {
+            println!("Fork failed: {:?}", e);
+            return -1;
+        },
+    }
+    0
+}
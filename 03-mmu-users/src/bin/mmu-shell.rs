@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+#[macro_use]
+extern crate mmu_user;
+
+use mmu_user::{read, app_name, run, ExitStatus, SyscallError};
+
+const STDIN: usize = 0;
+const LINE_LEN: usize = 128;
+// 命令行程序自己的名字，列出程序时跳过它
+const SHELL_NAME: &str = "mmu-shell";
+
+// 命令行：列出内核中的程序，按名字运行它们并报告结束的方式
+#[no_mangle]
+fn main() -> i32 {
+    println!("mmu-shell: type a program name to run it, 'ls' to list programs, 'exit' to quit");
+    let mut line = [0u8; LINE_LEN];
+    loop {
+        print!(">> ");
+        let len = match read_line(&mut line) {
+            Ok(len) => len,
+            Err(e) => {
+                println!("Failed to read input: {:?}", e);
+                return -1;
+            },
+        };
+        let cmd = match core::str::from_utf8(&line[..len]) {
+            Ok(cmd) => cmd.trim(),
+            Err(_) => {
+                println!("Input is not valid UTF-8");
+                continue;
+            },
+        };
+        match cmd {
+            "" => {},
+            "exit" => return 0,
+            "ls" | "help" => list_apps(),
+            SHELL_NAME => println!("Already running {}", SHELL_NAME),
+            name => match run(name) {
+                Ok((pid, ExitStatus::Exited(code))) => println!("[{}] {} exited with code {}", pid, name, code),
+                Ok((pid, ExitStatus::Panicked { line, col })) => println!("[{}] {} panicked at {}:{}", pid, name, line, col),
+                Ok((pid, ExitStatus::Killed)) => println!("[{}] {} was killed by the kernel", pid, name),
+                Err(SyscallError::NotFound) => println!("{}: program not found", name),
+                Err(e) => println!("Failed to run {}: {:?}", name, e),
+            },
+        }
+    }
+}
+
+// 读入一行，不包含结尾的换行。超过缓冲区的部分丢弃
+fn read_line(buf: &mut [u8]) -> Result<usize, SyscallError> {
+    let mut len = 0;
+    let mut byte = [0u8; 1];
+    loop {
+        if read(STDIN, &mut byte)? == 0 {
+            return Ok(len);
+        }
+        match byte[0] {
+            b'\n' => return Ok(len),
+            b if len < buf.len() => {
+                buf[len] = b;
+                len += 1;
+            },
+            _ => {},
+        }
+    }
+}
+
+fn list_apps() {
+    let mut name = [0u8; LINE_LEN];
+    for index in 0.. {
+        let len = match app_name(index, &mut name) {
+            Ok(len) => core::cmp::min(len, name.len()),
+            Err(_) => break, // 没有更多的程序了
+        };
+        match core::str::from_utf8(&name[..len]) {
+            Ok(SHELL_NAME) => {},
+            Ok(name) => println!("{}", name),
+            Err(_) => println!("<invalid name>"),
+        }
+    }
+}
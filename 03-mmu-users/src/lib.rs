@@ -13,9 +13,9 @@ mod syscall;
 fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
     let err = panic_info.message().unwrap().as_str();
     if let Some(location) = panic_info.location() {
-        sys_panic(Some(location.file()), location.line(), location.column(), err);
+        let _ = sys_panic(Some(location.file()), location.line(), location.column(), err);
     } else {
-        sys_panic(None, 0, 0, err);
+        let _ = sys_panic(None, 0, 0, err);
     }
     loop {}
 }
@@ -27,7 +27,7 @@ pub extern "C" fn _start() -> ! {
         fn sbss(); fn ebss();
     } 
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
-    exit(main());
+    let _ = exit(main());
     panic!("unreachable after sys_exit!");
 }
 
@@ -38,6 +38,34 @@ fn main() -> i32 {
 }
 
 use syscall::*;
+pub use syscall::SyscallError;
 
-pub fn write(fd: usize, buf: &[u8]) -> SyscallResult { sys_write(fd, buf) }
-pub fn exit(exit_code: i32) -> SyscallResult { sys_exit(exit_code) }
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, SyscallError> { sys_write(fd, buf) }
+// 读取用户的输入。没有输入时等待用户输入一行，返回读到的字节数
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, SyscallError> { sys_read(fd, buf) }
+pub fn exit(exit_code: i32) -> Result<usize, SyscallError> { sys_exit(exit_code) }
+// 复制当前进程。父进程得到子进程的编号，子进程得到0
+pub fn fork() -> Result<usize, SyscallError> { sys_fork() }
+// 第index个程序的名字写入buf，返回名字的长度；buf不够长时只写入名字的开头
+pub fn app_name(index: usize, buf: &mut [u8]) -> Result<usize, SyscallError> { sys_app_name(index, buf) }
+
+// 进程结束的方式
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExitStatus {
+    Exited(i32),
+    Panicked { line: u32, col: u32 },
+    // 进程出错，被内核结束
+    Killed,
+}
+
+// 按名字运行程序，等待它结束。返回子进程的编号和它结束的方式
+pub fn run(name: &str) -> Result<(usize, ExitStatus), SyscallError> {
+    let mut info = [0usize; 4];
+    let pid = sys_run(name, &mut info)?;
+    let status = match info {
+        [0, code, _, _] => ExitStatus::Exited(code as i32),
+        [1, _, line, col] => ExitStatus::Panicked { line: line as u32, col: col as u32 },
+        _ => ExitStatus::Killed,
+    };
+    Ok((pid, status))
+}
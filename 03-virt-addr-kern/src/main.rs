@@ -13,6 +13,7 @@ mod console;
 mod sbi;
 mod app;
 mod syscall;
+mod uaccess;
 mod executor;
 mod mm;
 
@@ -75,11 +76,12 @@ pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
 fn execute(user_stack: usize) -> ! {
     app::APP_MANAGER.print_app_info();
     let mut rt = executor::Runtime::new_user(app::APP_MANAGER.prepare_next_app(), user_stack);
+    let user_ranges = [app::app_range(), user_stack - 0x1000..user_stack]; // 用户栈只有一页
     loop {
         match Pin::new(&mut rt).resume(()) {
             GeneratorState::Yielded(KernelTrap::Syscall()) => {
                 let ctx = rt.context_mut();
-                match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5]) {
+                match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5], &user_ranges) {
                     SyscallOperation::Return(ans) => {
                         ctx.a0 = ans.code;
                         ctx.a1 = ans.extra;
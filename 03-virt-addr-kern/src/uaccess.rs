@@ -0,0 +1,52 @@
+//! 用户内存访问模块
+//!
+//! 这个内核中，用户程序和内核使用相同的地址。用户传来的指针先检查是否完整地落在程序可以访问的内存里，
+//! 检查过的内存不会产生访问异常，可以直接当作切片使用
+
+use core::ops::Range;
+
+// 用户传来的地址不合法，包含出错的用户地址
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BadAddress(pub usize);
+
+// 用户内存中的一段字节
+#[derive(Copy, Clone, Debug)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+    // 用户传来空指针，通常表示没有这个参数
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+    // 检查这段内存完整地落在ranges的某个区域中。长度为0时不会访问内存，总是合法
+    pub fn validate(&self, ranges: &[Range<usize>]) -> Result<(), BadAddress> {
+        if self.len == 0 {
+            return Ok(())
+        }
+        let end = self.addr.checked_add(self.len).ok_or(BadAddress(self.addr))?;
+        if ranges.iter().any(|range| range.start <= self.addr && end <= range.end) {
+            Ok(())
+        } else {
+            Err(BadAddress(self.addr))
+        }
+    }
+    // 检查后得到这段内存。下一个程序加载到同一个区域之前一直有效
+    pub fn as_bytes(&self, ranges: &[Range<usize>]) -> Result<&'static [u8], BadAddress> {
+        self.validate(ranges)?;
+        if self.len == 0 {
+            return Ok(&[])
+        }
+        // note(unsafe): 这段内存在程序可以访问的区域中
+        Ok(unsafe { core::slice::from_raw_parts(self.addr as *const u8, self.len) })
+    }
+    // 检查后把这段内存当作字符串。地址不合法或者不是UTF-8时返回None
+    pub fn as_str(&self, ranges: &[Range<usize>]) -> Option<&'static str> {
+        core::str::from_utf8(self.as_bytes(ranges).ok()?).ok()
+    }
+}
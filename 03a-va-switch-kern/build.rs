@@ -6,6 +6,7 @@ use std::path::PathBuf;
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=src/linker64.ld");
+    println!("cargo:rerun-if-changed=src/linker32.ld");
 
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
@@ -13,15 +14,25 @@ fn main() {
         .unwrap()
         .write_all(include_bytes!("src/linker64.ld"))
         .unwrap();
+    fs::File::create(out_dir.join("linker32.ld"))
+        .unwrap()
+        .write_all(include_bytes!("src/linker32.ld"))
+        .unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
 
     println!("cargo:rerun-if-changed=../03-mmu-users/src/");
-    let target_dir = "target/riscv64imac-unknown-none-elf/debug/";
+    let target = env::var("TARGET").unwrap();
+    let target_dir = format!("target/{}/debug/", target);
         // .to_string_lossy().replace("\\", "\\\\"); // 转义
-    insert_app_data(&target_dir).unwrap();
+    // RV32下，usize只有4个字节
+    let (word, align) = match env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap().as_str() {
+        "32" => (".word", 2),
+        _ => (".quad", 3),
+    };
+    insert_app_data(&target_dir, word, align).unwrap();
 }
 
-fn insert_app_data(target_dir: &str) -> Result<()> {
+fn insert_app_data(target_dir: &str, word: &str, align: usize) -> Result<()> {
     let mut f = File::create("src/link_apps.S").unwrap();
     let mut apps: Vec<_> = read_dir("../03-mmu-users/src/bin")
         .unwrap()
@@ -35,19 +46,19 @@ fn insert_app_data(target_dir: &str) -> Result<()> {
     apps.sort();
 
     writeln!(f, r#"
-    .align 3
     .section .data
+    .p2align {}
     .global _app_meta
 _app_meta:
-    .quad {}
-    "#, apps.len())?;
+    {} {}
+    "#, align, word, apps.len())?;
 
     for (i, name_with_ext) in apps.iter().enumerate() {
-        writeln!(f, r#"    .quad {}"#, name_with_ext.len())?;
+        writeln!(f, r#"    {} {}"#, word, name_with_ext.len())?;
         writeln!(f, r#"    .asciz "{}""#, name_with_ext)?;
-        writeln!(f, r#"    .quad app_{}_start"#, i)?;
+        writeln!(f, r#"    .p2align {}"#, align)?; // 名字后面的数据需要对齐
+        writeln!(f, r#"    {0} app_{1}_start, app_{1}_end"#, word, i)?; // 每个程序的开始和结束地址
     }
-    writeln!(f, r#"    .quad app_{}_end"#, apps.len() - 1)?;
 
     for (idx, app) in apps.iter().enumerate() {
         println!("app_{}: {}", idx, app);
@@ -1,4 +1,6 @@
-use crate::sbi::console_putchar;
+use crate::sbi::{console_putchar, console_getchar};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
 use core::fmt::{self, Write};
 
 struct Stdout;
@@ -12,7 +14,11 @@ impl Write for Stdout {
     }
 }
 
+// 多个处理核同时打印时，一次打印的内容不会和别的处理核的交错在一起
+static PRINT_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
 pub fn print(args: fmt::Arguments) {
+    let _guard = PRINT_LOCK.lock();
     Stdout.write_fmt(args).unwrap();
 }
 
@@ -29,3 +35,59 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
 }
+
+// 一行最多的字节数，超过后不再接收输入，直到换行
+const MAX_LINE_LEN: usize = 256;
+
+lazy_static::lazy_static! {
+    // 已经输入完整、还没有被读走的行
+    static ref PENDING: spin::Mutex<VecDeque<u8>> = spin::Mutex::new(VecDeque::new());
+}
+
+// 从键盘读入一行，输入时回显，退格删除上一个字符。读到换行时返回这一行，包含结尾的换行符
+fn read_line() -> Vec<u8> {
+    let mut line = Vec::new();
+    loop {
+        let c = console_getchar();
+        match c {
+            usize::MAX => continue, // 还没有输入
+            0x0d | 0x0a => { // 回车或者换行
+                console_putchar(b'\n' as usize);
+                line.push(b'\n');
+                return line;
+            },
+            0x08 | 0x7f => { // 退格。删除一个完整的UTF-8字符，屏幕上用空格盖住它
+                if line.is_empty() {
+                    continue;
+                }
+                while let Some(b) = line.pop() {
+                    if b & 0xc0 != 0x80 {
+                        break;
+                    }
+                }
+                for b in b"\x08 \x08" {
+                    console_putchar(*b as usize);
+                }
+            },
+            c if line.len() < MAX_LINE_LEN - 1 => {
+                console_putchar(c);
+                line.push(c as u8);
+            },
+            _ => {}, // 这一行满了
+        }
+    }
+}
+
+// 读出最多dst.len()个字节的输入，返回读到的字节数。没有读完的行留给下次读取；
+// 没有剩下的输入时，等待用户输入新的一行
+pub fn read(dst: &mut [u8]) -> usize {
+    let mut pending = PENDING.lock();
+    if pending.is_empty() {
+        pending.extend(read_line());
+    }
+    let n = core::cmp::min(dst.len(), pending.len());
+    for (i, byte) in pending.drain(..n).enumerate() {
+        dst[i] = byte;
+    }
+    n
+}
@@ -7,60 +7,91 @@ use core::{
     pin::Pin,
     ops::{Generator, GeneratorState},
 };
-// use crate::mm;
+use crate::{mm, process::{Process, ProcessError}};
+
+// 跳板页映射在每个地址空间的最高一页，下面一页是进程的陷入上下文，都没有用户态权限。
+// 进程的地址空间只映射用户的内存和这两页，不映射内核。内核的线性映射不会到达这里，见mm::LINEAR_MAP_PHYS
+pub const TRAMPOLINE: usize = usize::MAX - 0xfff;
+pub const TRAP_CONTEXT: usize = TRAMPOLINE - 0x1000;
 
 pub fn init() {
-    let mut addr = from_user_save as usize;
+    let mut addr = trampoline_addr(from_user_save as usize);
     if addr & 0x2 != 0 {
         addr += 0x2; // 必须对齐到4个字节
     }
     unsafe { stvec::write(addr, TrapMode::Direct) };
 }
 
+// 跳板中的函数在跳板页中的地址。函数链接在内核镜像中，切换地址空间前后都要从跳板页运行
+fn trampoline_addr(f: usize) -> usize {
+    extern "C" { fn strampoline(); }
+    TRAMPOLINE + (f - strampoline as usize)
+}
+
+// 运行一个用户进程。恢复运行时由跳板切换到进程的地址空间，陷入内核后切换回来。
+// 用户的上下文保存在进程的陷入上下文页中
 #[repr(C)]
-pub struct Runtime {
-    context: UserContext, 
-    // current_user_stack: Vec<mm::FrameBox>,
+pub struct Runtime<'a> {
+    process: Process<'a>,
 }
 
-impl Runtime {
-    pub fn new_user(first_app_sepc: usize) -> Self {
-        let context: UserContext = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
-        let mut ans = Runtime { context };
-        ans.prepare_next_app(first_app_sepc);
+impl<'a> Runtime<'a> {
+    pub fn new_user(process: Process<'a>) -> Self {
+        let (sepc, user_stack) = (process.entry(), process.user_stack_top());
+        let mut ans = Runtime { process };
+        ans.reset();
+        ans.context_mut().sepc = sepc;
+        ans.context_mut().sp = user_stack;
         ans
     }
 
     fn reset(&mut self) {
-        // self.context.sp = self.user_stack;
         unsafe { sstatus::set_spp(SPP::User) };
-        self.context.sstatus = sstatus::read();
-        self.context.kernel_stack = 0x233333666666; // 将会被resume函数覆盖
+        let context = self.context_mut();
+        context.sstatus = sstatus::read();
+        context.kernel_stack = 0x23336666; // 将会被resume函数覆盖
+        context.kernel_satp = 0x23336666; // 同上
     }
 
     // 在处理异常的时候，使用context_mut得到运行时当前用户的上下文，可以改变上下文的内容
     pub fn context_mut(&mut self) -> &mut UserContext {
-        &mut self.context
+        self.process.trap_context_mut()
+    }
+
+    // 当前运行的进程，处理系统调用时用来访问用户的内存
+    pub fn process(&self) -> &Process<'a> {
+        &self.process
+    }
+
+    pub fn process_mut(&mut self) -> &mut Process<'a> {
+        &mut self.process
     }
 
-    pub fn prepare_next_app(&mut self, new_sepc: usize) {
-        self.reset();
-        self.context.sepc = new_sepc;
+    // 复制运行时，子进程的上下文和当前进程相同
+    pub fn fork(&mut self) -> Result<Runtime<'a>, ProcessError> {
+        let process = self.process.fork()?;
+        Ok(Runtime { process })
     }
 }
 
-impl Generator for Runtime {
+impl Generator for Runtime<'_> {
     type Yield = KernelTrap;
     type Return = ();
     fn resume(mut self: Pin<&mut Self>, _arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
-        unsafe { do_resume(&mut self.context as *mut _) };
+        self.context_mut().kernel_satp = mm::read_satp();
+        let user_satp = self.process.satp();
+        // 陷入后跳板已经切换回内核的地址空间
+        unsafe { do_resume(TRAP_CONTEXT as *mut UserContext, user_satp, trampoline_addr(to_user_restore as usize)) };
         let stval = stval::read();
         let trap = match scause::read().cause() {
             Trap::Exception(Exception::UserEnvCall) => KernelTrap::Syscall(),
             Trap::Exception(Exception::LoadFault) => KernelTrap::LoadAccessFault(stval),
             Trap::Exception(Exception::StoreFault) => KernelTrap::StoreAccessFault(stval),
             Trap::Exception(Exception::IllegalInstruction) => KernelTrap::IllegalInstruction(stval),
-            e => panic!("unhandled exception: {:?}! stval: {:#x?}, ctx: {:#x?}", e, stval, self.context)
+            Trap::Exception(Exception::LoadPageFault) => KernelTrap::PageFault(stval, AccessKind::Load),
+            Trap::Exception(Exception::StorePageFault) => KernelTrap::PageFault(stval, AccessKind::Store),
+            Trap::Exception(Exception::InstructionPageFault) => KernelTrap::PageFault(stval, AccessKind::Execute),
+            e => panic!("unhandled exception: {:?}! stval: {:#x?}, ctx: {:#x?}", e, stval, self.process.trap_context())
         };
         GeneratorState::Yielded(trap)
     }
@@ -72,9 +103,19 @@ pub enum KernelTrap {
     LoadAccessFault(usize),
     StoreAccessFault(usize),
     IllegalInstruction(usize),
+    PageFault(usize, AccessKind), // 出错的地址，访问的方式
 }
 
-#[derive(Debug)]
+// 产生页异常的访问方式
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub enum AccessKind {
+    Load,
+    Store,
+    Execute,
+}
+
+#[derive(Debug, Clone)]
 #[repr(C)]
 pub struct UserContext {
     pub ra: usize, // 0
@@ -111,17 +152,20 @@ pub struct UserContext {
     pub sstatus: Sstatus, // 31
     pub sepc: usize, // 32
     pub kernel_stack: usize, // 33
+    pub kernel_satp: usize, // 34
 }
 
+// 保存内核的被调用者寄存器，跳到跳板中的to_user_restore，切换到进程的地址空间后返回用户态
 #[naked]
 #[link_section = ".text"]
-unsafe extern "C" fn do_resume(_user_context: *mut UserContext) {
+unsafe extern "C" fn do_resume(_user_context: *mut UserContext, _user_satp: usize, _to_user_restore: usize) {
     asm!("j     {from_kernel_save}", from_kernel_save = sym from_kernel_save, options(noreturn))
 }
 
+#[cfg(target_pointer_width = "64")]
 #[naked]
 #[link_section = ".text"]
-unsafe extern "C" fn from_kernel_save(_user_context: *mut UserContext) -> ! {
+unsafe extern "C" fn from_kernel_save(_user_context: *mut UserContext, _user_satp: usize, _to_user_restore: usize) -> ! {
     asm!( // sp:内核栈顶
         "addi   sp, sp, -15*8", // sp:内核栈顶
         // 进入函数之前，已经保存了调用者寄存器，应当保存被调用者寄存器
@@ -140,17 +184,46 @@ unsafe extern "C" fn from_kernel_save(_user_context: *mut UserContext) -> ! {
         sd      s9, 12*8(sp)
         sd      s10, 13*8(sp)
         sd      s11, 14*8(sp)", 
-        // a0:用户上下文
-        "j      {to_user_restore}",
-        to_user_restore = sym to_user_restore,
+        // a0:用户上下文, a1:进程的satp, a2:跳板中的to_user_restore
+        "jr     a2",
         options(noreturn)
     )
 }
 
+#[cfg(target_pointer_width = "32")]
 #[naked]
 #[link_section = ".text"]
-pub unsafe extern "C" fn to_user_restore(_user_context: *mut UserContext) -> ! {
-    asm!( // a0:用户上下文
+unsafe extern "C" fn from_kernel_save(_user_context: *mut UserContext, _user_satp: usize, _to_user_restore: usize) -> ! {
+    asm!( // sp:内核栈顶
+        "addi   sp, sp, -15*4", // sp:内核栈顶
+        // 进入函数之前，已经保存了调用者寄存器，应当保存被调用者寄存器
+        "sw     ra, 0*4(sp)
+        sw      gp, 1*4(sp)
+        sw      tp, 2*4(sp)
+        sw      s0, 3*4(sp)
+        sw      s1, 4*4(sp)
+        sw      s2, 5*4(sp)
+        sw      s3, 6*4(sp)
+        sw      s4, 7*4(sp)
+        sw      s5, 8*4(sp)
+        sw      s6, 9*4(sp)
+        sw      s7, 10*4(sp)
+        sw      s8, 11*4(sp)
+        sw      s9, 12*4(sp)
+        sw      s10, 13*4(sp)
+        sw      s11, 14*4(sp)", 
+        // a0:用户上下文, a1:进程的satp, a2:跳板中的to_user_restore
+        "jr     a2",
+        options(noreturn)
+    )
+}
+
+#[cfg(target_pointer_width = "64")]
+#[naked]
+#[link_section = ".text.trampoline"]
+pub unsafe extern "C" fn to_user_restore(_user_context: *mut UserContext, _user_satp: usize) -> ! {
+    asm!( // a0:用户上下文, a1:进程的satp
+        "csrw   satp, a1", // 切换到进程的地址空间，用户上下文在陷入上下文页中
         "sd     sp, 33*8(a0)", // 内核栈顶放进用户上下文
         "csrw   sscratch, a0", // 新sscratch:用户上下文
         // sscratch:用户上下文
@@ -196,10 +269,62 @@ pub unsafe extern "C" fn to_user_restore(_user_context: *mut UserContext) -> ! {
     )
 }
 
+#[cfg(target_pointer_width = "32")]
+#[naked]
+#[link_section = ".text.trampoline"]
+pub unsafe extern "C" fn to_user_restore(_user_context: *mut UserContext, _user_satp: usize) -> ! {
+    asm!( // a0:用户上下文, a1:进程的satp
+        "csrw   satp, a1", // 切换到进程的地址空间，用户上下文在陷入上下文页中
+        "sw     sp, 33*4(a0)", // 内核栈顶放进用户上下文
+        "csrw   sscratch, a0", // 新sscratch:用户上下文
+        // sscratch:用户上下文
+        "mv     sp, a0", // 新sp:用户上下文
+        "lw     t0, 31*4(sp)
+        lw      t1, 32*4(sp)
+        csrw    sstatus, t0
+        csrw    sepc, t1",
+        "lw     ra, 0*4(sp)
+        lw      gp, 2*4(sp)
+        lw      tp, 3*4(sp)
+        lw      t0, 4*4(sp)
+        lw      t1, 5*4(sp)
+        lw      t2, 6*4(sp)
+        lw      s0, 7*4(sp)
+        lw      s1, 8*4(sp)
+        lw      a0, 9*4(sp)
+        lw      a1, 10*4(sp)
+        lw      a2, 11*4(sp)
+        lw      a3, 12*4(sp)
+        lw      a4, 13*4(sp)
+        lw      a5, 14*4(sp)
+        lw      a6, 15*4(sp)
+        lw      a7, 16*4(sp)
+        lw      s2, 17*4(sp)
+        lw      s3, 18*4(sp)
+        lw      s4, 19*4(sp)
+        lw      s5, 20*4(sp)
+        lw      s6, 21*4(sp)
+        lw      s7, 22*4(sp)
+        lw      s8, 23*4(sp)
+        lw      s9, 24*4(sp)
+        lw     s10, 25*4(sp)
+        lw     s11, 26*4(sp)
+        lw      t3, 27*4(sp)
+        lw      t4, 28*4(sp)
+        lw      t5, 29*4(sp)
+        lw      t6, 30*4(sp)",
+        "lw     sp, 1*4(sp)", // 新sp:用户栈
+        // sp:用户栈, sscratch:用户上下文
+        "sret",
+        options(noreturn)
+    )
+}
+
 // 中断开始
 
+#[cfg(target_pointer_width = "64")]
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
 pub unsafe extern "C" fn from_user_save() -> ! {
     asm!( // sp:用户栈,sscratch:用户上下文
         ".p2align 2",
@@ -241,18 +366,73 @@ pub unsafe extern "C" fn from_user_save() -> ! {
         // sscratch:用户栈,sp:用户上下文
         "csrrw  t2, sscratch, sp", // 新sscratch:用户上下文,t2:用户栈
         "sd     t2, 1*8(sp)", // 保存用户栈
+        "ld     t0, 34*8(sp)", // t0:内核的satp
+        "ld     sp, 33*8(sp)", // sp:内核栈
+        "csrw   satp, t0", // 切换回内核的地址空间，之后不能再访问用户上下文
         "j      {to_kernel_restore}",
         to_kernel_restore = sym to_kernel_restore,
         options(noreturn)
     )
 }
 
+#[cfg(target_pointer_width = "32")]
 #[naked]
-#[link_section = ".text"]
+#[link_section = ".text.trampoline"]
+pub unsafe extern "C" fn from_user_save() -> ! {
+    asm!( // sp:用户栈,sscratch:用户上下文
+        ".p2align 2",
+        "csrrw  sp, sscratch, sp", // 新sscratch:用户栈, 新sp:用户上下文
+        "sw     ra, 0*4(sp)
+        sw      gp, 2*4(sp)
+        sw      tp, 3*4(sp)
+        sw      t0, 4*4(sp)
+        sw      t1, 5*4(sp)
+        sw      t2, 6*4(sp)
+        sw      s0, 7*4(sp)
+        sw      s1, 8*4(sp)
+        sw      a0, 9*4(sp)
+        sw      a1, 10*4(sp)
+        sw      a2, 11*4(sp)
+        sw      a3, 12*4(sp)
+        sw      a4, 13*4(sp)
+        sw      a5, 14*4(sp)
+        sw      a6, 15*4(sp)
+        sw      a7, 16*4(sp)
+        sw      s2, 17*4(sp)
+        sw      s3, 18*4(sp)
+        sw      s4, 19*4(sp)
+        sw      s5, 20*4(sp)
+        sw      s6, 21*4(sp)
+        sw      s7, 22*4(sp)
+        sw      s8, 23*4(sp)
+        sw      s9, 24*4(sp)
+        sw     s10, 25*4(sp)
+        sw     s11, 26*4(sp)
+        sw      t3, 27*4(sp)
+        sw      t4, 28*4(sp)
+        sw      t5, 29*4(sp)
+        sw      t6, 30*4(sp)",
+        "csrr   t0, sstatus
+        sw      t0, 31*4(sp)",
+        "csrr   t1, sepc
+        sw      t1, 32*4(sp)",
+        // sscratch:用户栈,sp:用户上下文
+        "csrrw  t2, sscratch, sp", // 新sscratch:用户上下文,t2:用户栈
+        "sw     t2, 1*4(sp)", // 保存用户栈
+        "lw     t0, 34*4(sp)", // t0:内核的satp
+        "lw     sp, 33*4(sp)", // sp:内核栈
+        "csrw   satp, t0", // 切换回内核的地址空间，之后不能再访问用户上下文
+        "j      {to_kernel_restore}",
+        to_kernel_restore = sym to_kernel_restore,
+        options(noreturn)
+    )
+}
+
+#[cfg(target_pointer_width = "64")]
+#[naked]
+#[link_section = ".text.trampoline"]
 unsafe extern "C" fn to_kernel_restore() -> ! {
-    asm!( // sscratch:用户上下文
-        "csrr   sp, sscratch", // sp:用户上下文
-        "ld     sp, 33*8(sp)", // sp:内核栈
+    asm!( // sp:内核栈
         "ld     ra, 0*8(sp)
         ld      gp, 1*8(sp)
         ld      tp, 2*8(sp)
@@ -273,3 +453,29 @@ unsafe extern "C" fn to_kernel_restore() -> ! {
         options(noreturn)
     )
 }
+
+#[cfg(target_pointer_width = "32")]
+#[naked]
+#[link_section = ".text.trampoline"]
+unsafe extern "C" fn to_kernel_restore() -> ! {
+    asm!( // sp:内核栈
+        "lw     ra, 0*4(sp)
+        lw      gp, 1*4(sp)
+        lw      tp, 2*4(sp)
+        lw      s0, 3*4(sp)
+        lw      s1, 4*4(sp)
+        lw      s2, 5*4(sp)
+        lw      s3, 6*4(sp)
+        lw      s4, 7*4(sp)
+        lw      s5, 8*4(sp)
+        lw      s6, 9*4(sp)
+        lw      s7, 10*4(sp)
+        lw      s8, 11*4(sp)
+        lw      s9, 12*4(sp)
+        lw      s10, 13*4(sp)
+        lw      s11, 14*4(sp)", 
+        "addi   sp, sp, 15*4", // sp:内核栈顶
+        "jr     ra", // 其实就是ret
+        options(noreturn)
+    )
+}
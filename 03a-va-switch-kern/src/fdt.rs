@@ -0,0 +1,268 @@
+//! 设备树解析模块
+//!
+//! 引导程序通过a1寄存器传来扁平设备树（FDT）的物理地址，这里从中读出内存、保留内存、启动参数、
+//! 处理核和外设的信息，内核就不用对平台写死这些参数了
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::ops::Range;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FdtError {
+    // 魔数不对，说明不是设备树
+    BadMagic(u32),
+    // 设备树的版本太新，不兼容
+    UnsupportedVersion(u32),
+    // 数据超出了设备树的范围
+    Truncated,
+    // 结构块中出现了不认识的标记
+    BadToken(u32),
+}
+
+// 从设备树中得到的平台信息
+#[derive(Debug, Default)]
+pub struct MachineInfo<'a> {
+    pub memory: Vec<Range<usize>>,
+    pub reserved_memory: Vec<Range<usize>>,
+    pub bootargs: Option<&'a str>,
+    pub harts: Vec<usize>,
+    pub uart: Option<Range<usize>>,
+    pub clint: Option<Range<usize>>,
+    pub plic: Option<Range<usize>>,
+    pub virtio_mmio: Vec<Range<usize>>,
+}
+
+impl MachineInfo<'_> {
+    // 得到可以交给页帧分配器的内存区间，也就是所有内存去掉保留内存和other_reserved中的部分
+    pub fn free_memory(&self, other_reserved: &[Range<usize>]) -> Vec<Range<usize>> {
+        let mut ans = self.memory.clone();
+        for reserved in self.reserved_memory.iter().chain(other_reserved) {
+            let mut next = Vec::new();
+            for range in ans {
+                if reserved.end <= range.start || range.end <= reserved.start {
+                    next.push(range);
+                    continue;
+                }
+                if range.start < reserved.start {
+                    next.push(range.start..reserved.start);
+                }
+                if reserved.end < range.end {
+                    next.push(reserved.end..range.end);
+                }
+            }
+            ans = next;
+        }
+        ans
+    }
+}
+
+// 从内核能访问的地址得到整个设备树的数据
+//
+// unsafe说明：dtb_va必须是引导程序传来的设备树地址在内核中的映射，而且在返回的生命周期内，这段内存不能被修改
+pub unsafe fn dtb_slice<'a>(dtb_va: usize) -> Result<&'a [u8], FdtError> {
+    let header = core::slice::from_raw_parts(dtb_va as *const u8, 8);
+    let magic = read_u32(header, 0)?;
+    if magic != FDT_MAGIC {
+        return Err(FdtError::BadMagic(magic))
+    }
+    let total_size = read_u32(header, 4)? as usize;
+    Ok(core::slice::from_raw_parts(dtb_va as *const u8, total_size))
+}
+
+// 解析设备树
+pub fn parse(dtb: &[u8]) -> Result<MachineInfo<'_>, FdtError> {
+    let magic = read_u32(dtb, 0)?;
+    if magic != FDT_MAGIC {
+        return Err(FdtError::BadMagic(magic))
+    }
+    let off_dt_struct = read_u32(dtb, 8)? as usize;
+    let off_dt_strings = read_u32(dtb, 12)? as usize;
+    let off_mem_rsvmap = read_u32(dtb, 16)? as usize;
+    let last_comp_version = read_u32(dtb, 24)?;
+    if last_comp_version > FDT_LAST_COMP_VERSION {
+        return Err(FdtError::UnsupportedVersion(last_comp_version))
+    }
+    let strings = dtb.get(off_dt_strings..).ok_or(FdtError::Truncated)?;
+    let mut info = MachineInfo::default();
+    // 内存保留块，由(地址, 大小)组成，以两个零结尾
+    let mut offset = off_mem_rsvmap;
+    loop {
+        let address = read_u64(dtb, offset)? as usize;
+        let size = read_u64(dtb, offset + 8)? as usize;
+        if address == 0 && size == 0 {
+            break;
+        }
+        info.reserved_memory.push(address..address + size);
+        offset += 16;
+    }
+    // 结构块
+    let mut stack: Vec<Node> = Vec::new();
+    let mut offset = off_dt_struct;
+    loop {
+        let token = read_u32(dtb, offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = read_cstr(dtb, offset)?;
+                offset = align_up_4(offset + name.len() + 1);
+                let name = core::str::from_utf8(name).unwrap_or("");
+                stack.push(Node::new(name));
+            },
+            FDT_END_NODE => {
+                let node = stack.pop().ok_or(FdtError::BadToken(token))?;
+                collect_node(&mut info, &node, stack.last());
+            },
+            FDT_PROP => {
+                let len = read_u32(dtb, offset)? as usize;
+                let name_offset = read_u32(dtb, offset + 4)? as usize;
+                let value = dtb.get(offset + 8..offset + 8 + len).ok_or(FdtError::Truncated)?;
+                offset = align_up_4(offset + 8 + len);
+                let name = read_cstr(strings, name_offset)?;
+                if let Some(node) = stack.last_mut() {
+                    node.set_property(name, value);
+                }
+            },
+            FDT_NOP => {},
+            FDT_END => break,
+            _ => return Err(FdtError::BadToken(token)),
+        }
+    }
+    Ok(info)
+}
+
+// 解析过程中的一个节点，只记录内核关心的属性
+struct Node<'a> {
+    name: &'a str,
+    // 子节点reg属性中，地址和大小各占几个单元
+    address_cells: u32,
+    size_cells: u32,
+    device_type: Option<&'a [u8]>,
+    compatible: Option<&'a [u8]>,
+    status: Option<&'a [u8]>,
+    reg: Option<&'a [u8]>,
+    bootargs: Option<&'a [u8]>,
+}
+
+impl<'a> Node<'a> {
+    fn new(name: &'a str) -> Self {
+        // 没有说明时，默认值由设备树规范给出
+        Node {
+            name, address_cells: 2, size_cells: 1,
+            device_type: None, compatible: None, status: None, reg: None, bootargs: None,
+        }
+    }
+    fn set_property(&mut self, name: &[u8], value: &'a [u8]) {
+        match name {
+            b"#address-cells" => self.address_cells = read_u32(value, 0).unwrap_or(2),
+            b"#size-cells" => self.size_cells = read_u32(value, 0).unwrap_or(1),
+            b"device_type" => self.device_type = Some(value),
+            b"compatible" => self.compatible = Some(value),
+            b"status" => self.status = Some(value),
+            b"reg" => self.reg = Some(value),
+            b"bootargs" => self.bootargs = Some(value),
+            _ => {},
+        }
+    }
+    // 去掉单元地址的节点名，比如"memory@80000000"得到"memory"
+    fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or("")
+    }
+    fn is_compatible(&self, name: &str) -> bool {
+        self.compatible.map(|value| string_list(value).any(|s| s == name.as_bytes())).unwrap_or(false)
+    }
+    fn is_disabled(&self) -> bool {
+        self.status.map(|value| string_list(value).any(|s| s == b"disabled")).unwrap_or(false)
+    }
+    fn has_device_type(&self, device_type: &str) -> bool {
+        self.device_type.map(|value| string_list(value).any(|s| s == device_type.as_bytes())).unwrap_or(false)
+    }
+}
+
+// 一个节点结束时，根据它在树中的位置和属性，把内核关心的信息放进info
+fn collect_node<'a>(info: &mut MachineInfo<'a>, node: &Node<'a>, parent: Option<&Node<'a>>) {
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return, // 根节点
+    };
+    if node.is_disabled() {
+        return;
+    }
+    let regs = || node.reg.map(|reg| parse_reg(reg, parent.address_cells, parent.size_cells)).unwrap_or_default();
+    let first_reg = || regs().into_iter().next();
+    if node.has_device_type("memory") {
+        info.memory.extend(regs());
+    } else if parent.base_name() == "reserved-memory" {
+        info.reserved_memory.extend(regs());
+    } else if node.base_name() == "chosen" {
+        let bootargs = node.bootargs.and_then(|value| string_list(value).next());
+        info.bootargs = bootargs.and_then(|s| core::str::from_utf8(s).ok());
+    } else if parent.base_name() == "cpus" && node.has_device_type("cpu") {
+        if let Some(hart) = first_reg() {
+            info.harts.push(hart.start);
+        }
+    } else if node.is_compatible("ns16550a") {
+        info.uart = info.uart.take().or_else(first_reg);
+    } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+        info.clint = info.clint.take().or_else(first_reg);
+    } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+        info.plic = info.plic.take().or_else(first_reg);
+    } else if node.is_compatible("virtio,mmio") {
+        info.virtio_mmio.extend(first_reg());
+    }
+}
+
+// reg属性由若干组(地址, 大小)组成，地址和大小占用的单元数由父节点给出
+fn parse_reg(reg: &[u8], address_cells: u32, size_cells: u32) -> Vec<Range<usize>> {
+    let (address_len, size_len) = (address_cells as usize * 4, size_cells as usize * 4);
+    if address_len + size_len == 0 {
+        return Vec::new();
+    }
+    reg.chunks_exact(address_len + size_len)
+        .map(|chunk| {
+            let address = read_cells(&chunk[..address_len]) as usize;
+            let size = read_cells(&chunk[address_len..]) as usize;
+            address..address + size
+        })
+        .collect()
+}
+
+// 把若干个大端序的32位单元拼成一个数
+fn read_cells(cells: &[u8]) -> u64 {
+    cells.chunks_exact(4)
+        .fold(0, |acc, cell| (acc << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64)
+}
+
+// 以零分隔的字符串列表
+fn string_list(value: &[u8]) -> impl Iterator<Item = &[u8]> {
+    value.split(|&b| b == 0).filter(|s| !s.is_empty())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, FdtError> {
+    let bytes = data.get(offset..offset + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, FdtError> {
+    let bytes = data.get(offset..offset + 8).ok_or(FdtError::Truncated)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+// 读出以零结尾的字符串，不包括结尾的零
+fn read_cstr(data: &[u8], offset: usize) -> Result<&[u8], FdtError> {
+    let rest = data.get(offset..).ok_or(FdtError::Truncated)?;
+    let len = rest.iter().position(|&b| b == 0).ok_or(FdtError::Truncated)?;
+    Ok(&rest[..len])
+}
+
+fn align_up_4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
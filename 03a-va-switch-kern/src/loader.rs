@@ -2,8 +2,8 @@
 global_asm!(include_str!("link_apps.S"));
 
 use crate::mm;
+use crate::process::LazyArea;
 use alloc::vec::Vec;
-use alloc::string::String;
 use alloc::fmt;
 use core::ops;
 
@@ -24,7 +24,10 @@ impl<'a> AppLoader<'a> {
             unsafe { cur = cur.offset(1) };
             let name_slice = unsafe { core::slice::from_raw_parts(cur as *const u8, name_len) };
             let name = alloc::str::from_utf8(name_slice).unwrap();
-            unsafe { cur = (cur as *const u8).offset(name_len as isize) as *const usize };
+            // 跳过名字和结尾的零，名字后面的数据对齐到usize
+            let word = core::mem::size_of::<usize>();
+            let next = (cur as usize + name_len + 1 + word - 1) & !(word - 1);
+            cur = next as *const usize;
             let start = unsafe { cur.read_volatile() }; 
             unsafe { cur = cur.offset(1) };
             let end = unsafe { cur.read_volatile() }; 
@@ -36,8 +39,15 @@ impl<'a> AppLoader<'a> {
     }
 }
 
+impl<'a> ops::Deref for AppLoader<'a> {
+    type Target = [App<'a>];
+    fn deref(&self) -> &[App<'a>] {
+        &self.apps
+    }
+}
+
 #[derive(Clone)]
-struct App<'a> {
+pub struct App<'a> {
     name: &'a str,
     elf_file: &'a [u8],
 }
@@ -51,4 +61,264 @@ impl fmt::Debug for App<'_> {
     }
 }
 
-// todo: parse elf file
+impl App<'_> {
+    pub fn name(&self) -> &str {
+        self.name
+    }
+    // 解析ELF文件，把每个需要加载的段映射到地址空间中，返回程序的入口地址和需要按需分配的区域。
+    // 段的权限由段的设置得到，都包含用户态权限；文件中没有的部分（比如.bss段）填充为零，
+    // 其中文件完全没有覆盖的整页不分配页帧，等到第一次访问时再分配
+    pub fn load<M, A>(&self, addr_space: &mut mm::PagedAddrSpace<M, A>) -> Result<LoadedElf, LoadError>
+    where
+        M: mm::PageMode<Flags = mm::Sv39Flags>,
+        A: mm::FrameAllocator + Clone,
+    {
+        let elf = self.elf_file;
+        let ident = elf.get(..ELF_IDENT_SIZE).ok_or(LoadError::Truncated)?;
+        if ident[..4] != ELF_MAGIC {
+            return Err(LoadError::BadMagic)
+        }
+        if ident[4] != ELF_CLASS || ident[5] != ELF_DATA_LSB {
+            return Err(LoadError::UnsupportedClass(ident[4], ident[5]))
+        }
+        let header = elf.get(..ELF_HEADER_SIZE).ok_or(LoadError::Truncated)?;
+        let e_type = read_u16(header, 16);
+        if e_type != ET_EXEC {
+            return Err(LoadError::NotExecutable(e_type))
+        }
+        let e_machine = read_u16(header, 18);
+        if e_machine != EM_RISCV {
+            return Err(LoadError::UnsupportedMachine(e_machine))
+        }
+        let ElfHeader { entry, ph_offset, ph_entry_size, ph_num } = read_header(header);
+        if ph_entry_size < ELF_PROGRAM_HEADER_SIZE {
+            return Err(LoadError::Truncated)
+        }
+        let mut lazy_areas = Vec::new();
+        for i in 0..ph_num {
+            // 偏移和大小来自文件，溢出时说明文件不完整
+            let start = i.checked_mul(ph_entry_size).and_then(|off| off.checked_add(ph_offset)).ok_or(LoadError::Truncated)?;
+            let end = start.checked_add(ELF_PROGRAM_HEADER_SIZE).ok_or(LoadError::Truncated)?;
+            let ph = elf.get(start..end).ok_or(LoadError::Truncated)?;
+            if read_u32(ph, 0) != PT_LOAD {
+                continue;
+            }
+            let segment = read_segment(ph);
+            lazy_areas.extend(load_segment(elf, &segment, addr_space)?);
+        }
+        Ok(LoadedElf { entry, lazy_areas })
+    }
+}
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELF_IDENT_SIZE: usize = 16;
+const ELF_DATA_LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+// 只加载和内核位数相同的ELF文件：RV64下是ELF64，RV32下是ELF32
+#[cfg(target_pointer_width = "64")]
+const ELF_CLASS: u8 = 2;
+#[cfg(target_pointer_width = "64")]
+const ELF_HEADER_SIZE: usize = 64;
+#[cfg(target_pointer_width = "64")]
+const ELF_PROGRAM_HEADER_SIZE: usize = 56;
+#[cfg(target_pointer_width = "32")]
+const ELF_CLASS: u8 = 1;
+#[cfg(target_pointer_width = "32")]
+const ELF_HEADER_SIZE: usize = 52;
+#[cfg(target_pointer_width = "32")]
+const ELF_PROGRAM_HEADER_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum LoadError {
+    // 不是ELF文件
+    BadMagic,
+    // 只支持小端序、和内核位数相同的ELF文件，参数为文件的类别和字节序
+    UnsupportedClass(u8, u8),
+    // 不是可执行文件
+    NotExecutable(u16),
+    // 不是RISC-V架构的程序
+    UnsupportedMachine(u16),
+    // 文件不完整
+    Truncated,
+    // 段的文件大小超过了内存大小，地址溢出或者超出用户的低地址，参数为段的虚拟地址
+    BadSegment(usize),
+    // 分配页帧失败
+    FrameAlloc(mm::FrameAllocError),
+}
+
+impl From<mm::FrameAllocError> for LoadError {
+    fn from(src: mm::FrameAllocError) -> LoadError {
+        LoadError::FrameAlloc(src)
+    }
+}
+
+// 加载ELF文件的结果
+pub struct LoadedElf {
+    pub entry: usize,
+    pub lazy_areas: Vec<LazyArea>,
+}
+
+// 文件头中用到的字段
+struct ElfHeader {
+    entry: usize,
+    ph_offset: usize,
+    ph_entry_size: usize,
+    ph_num: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+fn read_header(header: &[u8]) -> ElfHeader {
+    ElfHeader {
+        entry: read_u64(header, 24) as usize,
+        ph_offset: read_u64(header, 32) as usize,
+        ph_entry_size: read_u16(header, 54) as usize,
+        ph_num: read_u16(header, 56) as usize,
+    }
+}
+
+#[cfg(target_pointer_width = "32")]
+fn read_header(header: &[u8]) -> ElfHeader {
+    ElfHeader {
+        entry: read_u32(header, 24) as usize,
+        ph_offset: read_u32(header, 28) as usize,
+        ph_entry_size: read_u16(header, 42) as usize,
+        ph_num: read_u16(header, 44) as usize,
+    }
+}
+
+// 程序头中段的设置，ELF64和ELF32的字段顺序不同
+#[cfg(target_pointer_width = "64")]
+fn read_segment(ph: &[u8]) -> Segment {
+    Segment {
+        flags: read_u32(ph, 4),
+        offset: read_u64(ph, 8) as usize,
+        vaddr: read_u64(ph, 16) as usize,
+        file_size: read_u64(ph, 32) as usize,
+        mem_size: read_u64(ph, 40) as usize,
+    }
+}
+
+#[cfg(target_pointer_width = "32")]
+fn read_segment(ph: &[u8]) -> Segment {
+    Segment {
+        flags: read_u32(ph, 24),
+        offset: read_u32(ph, 4) as usize,
+        vaddr: read_u32(ph, 8) as usize,
+        file_size: read_u32(ph, 16) as usize,
+        mem_size: read_u32(ph, 20) as usize,
+    }
+}
+
+// 需要加载的段
+struct Segment {
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+// 加载一个段，返回这个段中需要按需分配的区域
+fn load_segment<M, A>(elf: &[u8], segment: &Segment, addr_space: &mut mm::PagedAddrSpace<M, A>) -> Result<Option<LazyArea>, LoadError>
+where
+    M: mm::PageMode<Flags = mm::Sv39Flags>,
+    A: mm::FrameAllocator + Clone,
+{
+    let vaddr = segment.vaddr;
+    let data_end = segment.offset.checked_add(segment.file_size).ok_or(LoadError::Truncated)?;
+    let data = elf.get(segment.offset..data_end).ok_or(LoadError::Truncated)?;
+    let mem_end = vaddr.checked_add(segment.mem_size).ok_or(LoadError::BadSegment(vaddr))?;
+    if segment.file_size > segment.mem_size {
+        return Err(LoadError::BadSegment(vaddr))
+    }
+    let mut flags = mm::Sv39Flags::U;
+    if segment.flags & PF_R != 0 { flags |= mm::Sv39Flags::R }
+    if segment.flags & PF_W != 0 { flags |= mm::Sv39Flags::W }
+    if segment.flags & PF_X != 0 { flags |= mm::Sv39Flags::X }
+    let page_size = 1 << M::FRAME_SIZE_BITS;
+    let page_start = vaddr & !(page_size - 1);
+    let page_end = mem_end.checked_add(page_size - 1).ok_or(LoadError::BadSegment(vaddr))? & !(page_size - 1);
+    // 段只能在虚拟地址低的一半中；高的一半留给内核，Sv39等模式中间还有不能使用的地址
+    if page_end > 1 << (M::VIRT_ADDR_BITS - 1) {
+        return Err(LoadError::BadSegment(vaddr))
+    }
+    // 文件内容覆盖的页立即分配，剩下的整页按需分配
+    let file_page_end = if segment.file_size == 0 {
+        page_start
+    } else {
+        (vaddr + segment.file_size + page_size - 1) & !(page_size - 1)
+    };
+    // 为还没有映射的页分配页帧；如果两个段共用一页，这一页只映射一次
+    let mut va = page_start;
+    while va < file_page_end {
+        if addr_space.translate(mm::VirtAddr(va)).is_some() {
+            va += page_size;
+            continue;
+        }
+        let run_start = va;
+        while va < file_page_end && addr_space.translate(mm::VirtAddr(va)).is_none() {
+            va += page_size;
+        }
+        let vpn = mm::VirtAddr(run_start).page_number::<M>();
+        addr_space.allocate_map_frames(vpn, (va - run_start) / page_size, flags)?;
+    }
+    // 复制文件中的内容，已经映射的页中剩下的部分填零
+    for_each_page_mut(addr_space, vaddr, segment.file_size, |dst, offset| {
+        dst.copy_from_slice(&data[offset..offset + dst.len()])
+    });
+    for_each_page_mut(addr_space, vaddr + segment.file_size, segment.mem_size - segment.file_size, |dst, _| {
+        dst.iter_mut().for_each(|b| *b = 0)
+    });
+    if file_page_end < page_end {
+        Ok(Some(LazyArea { range: file_page_end..page_end, flags }))
+    } else {
+        Ok(None)
+    }
+}
+
+// 按页访问地址空间中[va, va + len)的内存，没有映射的页跳过。f的参数为这一页中的内存和它相对va的偏移
+fn for_each_page_mut<M, A, F>(addr_space: &mm::PagedAddrSpace<M, A>, va: usize, len: usize, mut f: F)
+where
+    M: mm::PageMode,
+    A: mm::FrameAllocator + Clone,
+    F: FnMut(&mut [u8], usize),
+{
+    let page_size = 1 << M::FRAME_SIZE_BITS;
+    let mut cur = va;
+    while cur < va + len {
+        let next = core::cmp::min((cur & !(page_size - 1)) + page_size, va + len);
+        let pa = match addr_space.translate(mm::VirtAddr(cur)) {
+            Some((pa, _, _)) => pa,
+            None => {
+                cur = next;
+                continue;
+            }
+        };
+        // note(unsafe): 页帧由地址空间分配，内核通过线性映射访问
+        let dst = unsafe { core::slice::from_raw_parts_mut(pa.kernel_virt().0 as *mut u8, next - cur) };
+        f(dst, cur - va);
+        cur = next;
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(target_pointer_width = "64")]
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
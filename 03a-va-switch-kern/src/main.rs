@@ -11,121 +11,387 @@ extern crate alloc;
 #[macro_use]
 mod console;
 mod sbi;
-mod app;
 mod syscall;
 mod executor;
 mod mm;
 mod loader;
+mod fdt;
+mod process;
+mod uaccess;
 
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use core::panic::PanicInfo;
 use executor::KernelTrap;
-use crate::syscall::{syscall, SyscallOperation};
+use process::{ExitStatus, ProcessError};
+use crate::syscall::{syscall, SyscallError, SyscallOperation, SyscallResult};
 use core::pin::Pin;
-use core::ops::{Generator, GeneratorState};
+use core::ops::{Generator, GeneratorState, Range};
+use core::cmp;
 
 pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
-    extern "C" { fn sbss(); fn ebss();/* fn ekernel(); */}
+    extern "C" { fn sbss(); fn ebss(); fn ekernel(); }
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
     println!("[kernel] Hart id = {}, DTB physical address = {:#x}", hartid, dtb_pa);
     mm::heap_init();
     mm::test_frame_alloc();
+    mm::test_buddy_frame_alloc();
 
     /* Test app loader */
     let apps = loader::AppLoader::new();
     println!("{:?}", apps);
 
+    // 从设备树得到物理内存和外设的信息
+    let dtb = unsafe { fdt::dtb_slice(mm::PhysAddr(dtb_pa).kernel_virt().0) }.expect("read device tree");
+    let machine = fdt::parse(dtb).expect("parse device tree");
+    println!("[kernel] Harts: {:?} ({} in total), bootargs: {:?}", machine.harts, machine.harts.len(), machine.bootargs);
+    println!("[kernel] Memory: {:x?}, reserved: {:x?}", machine.memory, machine.reserved_memory);
+    println!("[kernel] UART: {:x?}, CLINT: {:x?}, PLIC: {:x?}, virtio-mmio: {:x?}",
+        machine.uart, machine.clint, machine.plic, machine.virtio_mmio);
+
     // 页帧分配器。对整个物理的地址空间来说，无论有多少个核，页帧分配器只有一个。
-    let from = mm::PhysAddr(0x80420000).page_number::<mm::Sv39>();
-    let to = mm::PhysAddr(0x80800000).page_number::<mm::Sv39>(); // 暂时对qemu写死
-    let frame_alloc = spin::Mutex::new(mm::StackFrameAllocator::new(from, to));
+    // 可用的内存要去掉固件和内核镜像，以及设备树本身
+    let free_memory = machine.free_memory(&[
+        0..mm::VirtAddr(ekernel as usize).kernel_phys().0,
+        dtb_pa..dtb_pa + dtb.len(),
+    ]);
+    let mut aligned_memory = Vec::new();
+    for r in free_memory {
+        let r = (r.start + 0xfff) & !0xfff..r.end & !0xfff; // 对齐到页
+        if r.start >= r.end {
+            continue
+        }
+        // 线性映射覆盖不到的内存，内核访问不到，不交给页帧分配器
+        let linear = mm::LINEAR_MAP_PHYS;
+        let clipped = cmp::max(r.start, linear.start)..cmp::min(r.end, linear.end);
+        if clipped.start >= clipped.end {
+            println!("[kernel] Memory {:x?} is outside the linear map {:x?}, ignored", r, linear);
+            continue
+        }
+        if clipped != r {
+            println!("[kernel] Memory {:x?} is partly outside the linear map {:x?}, only {:x?} is used", r, linear, clipped);
+        }
+        aligned_memory.push(clipped);
+    }
+    let free_memory = aligned_memory;
+    println!("[kernel] Free memory: {:x?}", free_memory);
+    let frame_ranges: Vec<_> = free_memory.iter()
+        .map(|r| mm::PhysAddr(r.start).page_number::<mm::DefaultPageMode>()..mm::PhysAddr(r.end).page_number::<mm::DefaultPageMode>())
+        .collect();
+    // 所有处理核一直使用页帧分配器，把它放到堆上，不再释放
+    let frame_alloc: &'static mm::DefaultFrameAllocator =
+        Box::leak(Box::new(spin::Mutex::new(mm::BuddyFrameAllocator::from_ranges(&frame_ranges))));
     // println!("[kernel-frame] Frame allocator: {:x?}", frame_alloc);
-    let mut kernel_addr_space = mm::PagedAddrSpace::try_new_in(mm::Sv39, &frame_alloc)
+    #[cfg(target_pointer_width = "64")]
+    println!("[kernel] Page modes supported: Sv39 = {}, Sv48 = {}, Sv57 = {}", 
+        mm::probe_page_mode(mm::Sv39, frame_alloc),
+        mm::probe_page_mode(mm::Sv48, frame_alloc),
+        mm::probe_page_mode(mm::Sv57, frame_alloc),
+    );
+    #[cfg(target_pointer_width = "32")]
+    println!("[kernel] Page modes supported: Sv32 = {}", mm::probe_page_mode(mm::Sv32, frame_alloc));
+    let mut kernel_addr_space = mm::PagedAddrSpace::try_new_in(mm::DefaultPageMode, frame_alloc)
         .expect("allocate page to create kernel paged address space");
     // println!("[kernel] Kernel address space: {:x?}", kernel_addr_space);
+    #[cfg(target_pointer_width = "64")]
     mm::test_map_solve();
-    kernel_addr_space.allocate_map(
-        mm::VirtAddr(0x80000000).page_number::<mm::Sv39>(), 
-        mm::PhysAddr(0x80000000).page_number::<mm::Sv39>(), 
-        1024,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X
-    ).expect("allocate one mapped space");
-    kernel_addr_space.allocate_map(
-        mm::VirtAddr(0x80400000).page_number::<mm::Sv39>(), 
-        mm::PhysAddr(0x80400000).page_number::<mm::Sv39>(), 
-        32,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X | mm::Sv39Flags::U
-    ).expect("allocate one mapped space");
+    #[cfg(target_pointer_width = "64")]
+    mm::test_unmap(frame_alloc);
+    #[cfg(target_pointer_width = "64")]
+    mm::test_translate(frame_alloc);
+    #[cfg(target_pointer_width = "64")]
+    mm::test_map_offset(frame_alloc);
+    #[cfg(target_pointer_width = "64")]
+    mm::test_clone_cow(frame_alloc);
+    mm::test_map_solve_sv32();
+    map_kernel(&mut kernel_addr_space, &free_memory, dtb_pa..dtb_pa + dtb.len());
     // println!("[kernel] Kernel address space: {:x?}", kernel_addr_space);
-    mm::test_asid_alloc();
+    mm::test_asid_manager();
     let max_asid = mm::max_asid();
-    let mut asid_alloc = mm::StackAsidAllocator::new(max_asid);
-    // println!("[kernel-asid] Asid allocator: {:x?}", asid_alloc);
-    let kernel_asid = asid_alloc.allocate_asid().expect("alloc kernel asid");
-    use crate::mm::FrameAllocator;
-    let user_stack_ppn = frame_alloc.allocate_frame().expect("Alloc user stack");
-    println!("User stack ppn: {:?}", user_stack_ppn);
-    kernel_addr_space.allocate_map(
-        mm::VirtAddr(user_stack_ppn.addr_begin::<mm::Sv39>().0).page_number::<mm::Sv39>(), 
-        user_stack_ppn, 
-        1,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X | mm::Sv39Flags::U
-    ).expect("allocate one mapped space");
+    println!("[kernel] Max address space id = {:?}", max_asid);
+    let asid_manager: &'static _ = Box::leak(Box::new(spin::Mutex::new(mm::AsidManager::new(max_asid))));
     unsafe {
-        mm::activate_paged_riscv_sv39(kernel_addr_space.root_page_number(), kernel_asid);
+        mm::activate::<mm::DefaultPageMode>(kernel_addr_space.root_page_number(), mm::DEFAULT_ASID);
+    }
+    executor::init();
+    // 其它处理核通过启动参数得到这些资源，它们放在堆上，不再释放
+    let kernel: &'static Kernel = Box::leak(Box::new(Kernel {
+        apps,
+        frame_alloc,
+        asid_manager,
+        kernel_root: kernel_addr_space.root_page_number(),
+        run_queue: spin::Mutex::new(RunQueue { ready: VecDeque::new(), running: 0 }),
+    }));
+    if let Some(app) = kernel.apps.first() {
+        process::test_isolation(app, frame_alloc, asid_manager);
+    }
+    // 就绪队列。有命令行程序时只加载它，由用户选择运行哪些程序；否则先加载所有程序，再由各个处理核依次运行
+    let boot_apps: Vec<_> = match kernel.apps.iter().find(|app| app.name() == SHELL_APP) {
+        Some(shell) => alloc::vec![shell],
+        None => kernel.apps.iter().collect(),
+    };
+    for app in boot_apps {
+        println!("[kernel] Loading app {}", app.name());
+        match process::Process::try_new_from_elf(app, frame_alloc, asid_manager) {
+            Ok(process) => kernel.run_queue.lock().ready.push_back(executor::Runtime::new_user(process)),
+            Err(e) => println!("[kernel] Failed to load app {}: {:?}", app.name(), e),
+        }
+    }
+    start_other_harts(hartid, &machine.harts, kernel);
+    schedule(kernel);
+    println!("[kernel] All applications completed, shutdown!");
+    sbi::shutdown()
+}
+
+// 内核中有这个程序时，开机只运行它
+const SHELL_APP: &str = "mmu-shell";
+
+// 每个处理核的启动栈大小，和入口中计算栈的方法一致。启动栈用完时，其余的处理核不能启动
+const HART_STACK_SIZE: usize = 1 << 14;
+const MAX_HARTS: usize = BOOT_STACK_SIZE / HART_STACK_SIZE;
+// 程序运行另一个程序时，内核在同一个启动栈上嵌套执行。启动栈没有保护页，嵌套层数要有上限，超过时运行失败
+const MAX_RUN_DEPTH: usize = 4;
+
+// 所有处理核共享的内核资源
+struct Kernel {
+    apps: loader::AppLoader<'static>,
+    frame_alloc: &'static mm::DefaultFrameAllocator,
+    // 地址空间编号在所有处理核之间唯一，刷新别的处理核的页表缓存时，才能只刷新这个编号
+    asid_manager: &'static spin::Mutex<mm::AsidManager>,
+    // 内核地址空间的根页表，其它处理核启动后切换到这个地址空间；内核使用0号地址空间编号
+    kernel_root: mm::PhysPageNum,
+    run_queue: spin::Mutex<RunQueue>,
+}
+
+// 所有处理核共享的就绪队列
+struct RunQueue {
+    ready: VecDeque<executor::Runtime<'static>>,
+    // 正在运行进程的处理核数。它们运行的进程可能复制出新的进程，这时就绪队列为空也不能结束
+    running: usize,
+}
+
+// 用SBI的HSM扩展启动设备树中的其它处理核，它们从secondary_entry开始运行
+fn start_other_harts(boot_hartid: usize, harts: &[usize], kernel: &'static Kernel) {
+    for &hartid in harts {
+        if hartid == boot_hartid {
+            continue;
+        }
+        if hartid >= MAX_HARTS {
+            println!("[kernel] Hart {} has no boot stack, skipped", hartid);
+            continue;
+        }
+        let opaque = kernel as *const Kernel as usize;
+        // 启动时分页没有打开，入口要用物理地址
+        let start_addr = mm::VirtAddr(secondary_entry as usize).kernel_phys().0;
+        match sbi::hart_start(hartid, start_addr, opaque) {
+            Ok(()) => println!("[kernel] Starting hart {}", hartid),
+            Err(e) => println!("[kernel] Failed to start hart {}: {:?}", hartid, e),
+        }
     }
-    unsafe { riscv::register::sstatus::set_sum() };
+}
+
+// 其它处理核的主函数。入口用启动页表打开了分页，这里先设置陷入向量，再切换到内核的地址空间
+extern "C" fn rust_main_secondary(hartid: usize, opaque: usize) -> ! {
+    // note(unsafe): opaque是启动处理核传来的内核资源，它在堆上，不会被释放
+    let kernel = unsafe { &*(opaque as *const Kernel) };
     executor::init();
-    execute(user_stack_ppn.addr_begin::<mm::Sv39>().0 + 0x1000);
+    unsafe {
+        mm::activate::<mm::DefaultPageMode>(kernel.kernel_root, mm::DEFAULT_ASID);
+    }
+    println!("[kernel] Hart {} started", hartid);
+    schedule(kernel);
+    // 所有进程都运行完了，启动处理核会关机，这个处理核停下来就可以了
+    let err = sbi::hart_stop();
+    println!("[kernel] Failed to stop hart {}: {:?}", hartid, err);
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+// 从共享的就绪队列取出进程运行，直到所有进程都运行完。
+// 进程复制出的子进程在它结束后放回就绪队列，可以在任何一个处理核上运行
+fn schedule(kernel: &Kernel) {
+    loop {
+        let rt = {
+            let mut queue = kernel.run_queue.lock();
+            match queue.ready.pop_front() {
+                Some(rt) => {
+                    queue.running += 1;
+                    rt
+                },
+                None if queue.running == 0 => return,
+                None => {
+                    drop(queue);
+                    core::hint::spin_loop();
+                    continue;
+                },
+            }
+        };
+        unsafe { asm!("fence.i") }; // 程序可能是别的处理核加载的
+        let mut forked = VecDeque::new();
+        execute(rt, &mut forked, kernel, 0);
+        // 放回子进程和减少计数在同一个临界区中，别的处理核不会在中间看到空的队列而提前结束
+        let mut queue = kernel.run_queue.lock();
+        queue.ready.extend(forked);
+        queue.running -= 1;
+    }
 }
 
-fn execute(user_stack: usize) -> ! {
-    app::APP_MANAGER.print_app_info();
-    let mut rt = executor::Runtime::new_user(app::APP_MANAGER.prepare_next_app(),/*  user_stack*/);
+// 把内核镜像按段映射到高地址，页帧分配器管理的内存和设备树按内核的偏移映射到高地址，这些页没有用户态权限。
+// 开启分页后，内核通过线性映射访问页表和用户程序的页帧。跳板页映射在和进程的地址空间相同的地址
+fn map_kernel<M, A>(addr_space: &mut mm::PagedAddrSpace<M, A>, free_memory: &[Range<usize>], dtb: Range<usize>)
+where
+    M: mm::PageMode<Flags = mm::Sv39Flags>,
+    A: mm::FrameAllocator + Clone,
+{
+    // 跳板页和陷入上下文页必须在线性映射之外，否则映射会冲突。这里的区间都包含最后一个地址，避免溢出
+    let linear = mm::PhysAddr(mm::LINEAR_MAP_PHYS.start).kernel_virt().0..=mm::PhysAddr(mm::LINEAR_MAP_PHYS.end - 1).kernel_virt().0;
+    let trampoline = executor::TRAP_CONTEXT..=executor::TRAMPOLINE + 0xfff;
+    assert!(trampoline.end() < linear.start() || linear.end() < trampoline.start(),
+        "trampoline pages {:x?} overlap the linear map {:x?}", trampoline, linear);
+    mm::map_kernel_sections(addr_space).expect("map kernel image");
+    mm::map_trampoline(addr_space, mm::VirtAddr(executor::TRAMPOLINE)).expect("map trampoline");
+    for range in free_memory {
+        addr_space.allocate_map(
+            mm::PhysAddr(range.start).kernel_virt().page_number::<M>(),
+            mm::PhysAddr(range.start).page_number::<M>(),
+            (range.end - range.start) / 0x1000,
+            mm::Sv39Flags::R | mm::Sv39Flags::W
+        ).expect("map free memory");
+    }
+    // 设备树解析的结果中还有指向它的引用
+    let (start, end) = (dtb.start & !0xfff, (dtb.end + 0xfff) & !0xfff);
+    addr_space.allocate_map(
+        mm::PhysAddr(start).kernel_virt().page_number::<M>(),
+        mm::PhysAddr(start).page_number::<M>(),
+        (end - start) / 0x1000,
+        mm::Sv39Flags::R
+    ).expect("map device tree");
+}
+
+// 运行用户程序，直到它退出或者出错，返回它结束的方式；运行时释放时，进程占用的资源一起释放。
+// 程序复制出的子进程放进就绪队列，之后再运行。depth是这个程序被别的程序运行时嵌套的层数，开机运行的程序是0
+fn execute(mut rt: executor::Runtime<'static>, ready: &mut VecDeque<executor::Runtime<'static>>, kernel: &Kernel, depth: usize) -> ExitStatus {
     loop {
         match Pin::new(&mut rt).resume(()) {
             GeneratorState::Yielded(KernelTrap::Syscall()) => {
                 let ctx = rt.context_mut();
-                match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5]) {
-                    SyscallOperation::Return(ans) => {
-                        ctx.a0 = ans.code;
-                        ctx.a1 = ans.extra;
-                        ctx.sepc = ctx.sepc.wrapping_add(4);
+                let (module, function, args) = (ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5]);
+                match syscall(module, function, args, rt.process_mut(), &kernel.apps) {
+                    SyscallOperation::Return(ans) => return_to_user(&mut rt, ans),
+                    SyscallOperation::Fork => {
+                        // 父进程得到子进程的编号，子进程得到0
+                        let ans = match rt.fork() {
+                            Ok(mut child) => {
+                                let pid = child.process().pid();
+                                return_to_user(&mut child, SyscallResult { code: 0, extra: 0 });
+                                ready.push_back(child);
+                                SyscallResult { code: 0, extra: pid }
+                            },
+                            Err(e) => {
+                                println!("[kernel] Failed to fork process {}: {:?}", rt.process().pid(), e);
+                                SyscallError::OutOfMemory.into()
+                            }
+                        };
+                        return_to_user(&mut rt, ans);
+                    }
+                    SyscallOperation::Run(index, info) => {
+                        let ans = run_app(&kernel.apps[index], kernel, depth + 1)
+                            .and_then(|(pid, status)| {
+                                // 结束信息：[种类, 退出码, 行, 列]，种类0是正常退出，1是panic，2是被内核结束
+                                let words = match status {
+                                    ExitStatus::Exited(code) => [0, code as isize as usize, 0, 0],
+                                    ExitStatus::Panicked { line, col } => [1, 0, line as usize, col as usize],
+                                    ExitStatus::Killed => [2, 0, 0, 0],
+                                };
+                                if !info.is_null() {
+                                    info.write(rt.process_mut(), words)?;
+                                }
+                                Ok(pid)
+                            });
+                        let ans = match ans {
+                            Ok(pid) => SyscallResult { code: 0, extra: pid },
+                            Err(e) => e.into(),
+                        };
+                        return_to_user(&mut rt, ans);
                     }
                     SyscallOperation::Terminate(code) => {
                         println!("[Kernel] Process returned with code {}", code);
-                        rt.prepare_next_app(app::APP_MANAGER.prepare_next_app());
+                        return ExitStatus::Exited(code);
                     }
                     SyscallOperation::UserPanic(file, line, col, msg) => {
-                        let file = file.unwrap_or("<no file>");
-                        let msg = msg.unwrap_or("<no message>");
+                        let file = file.as_deref().unwrap_or("<no file>");
+                        let msg = msg.as_deref().unwrap_or("<no message>");
                         println!("[Kernel] User process panicked at '{}', {}:{}:{}", msg, file, line, col);
-                        rt.prepare_next_app(app::APP_MANAGER.prepare_next_app());
+                        return ExitStatus::Panicked { line, col };
                     }
                 }
             },
             GeneratorState::Yielded(KernelTrap::LoadAccessFault(a)) => {
                 let ctx = rt.context_mut();
                 println!("[kernel] Load access fault to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
-                rt.prepare_next_app(app::APP_MANAGER.prepare_next_app());
+                return ExitStatus::Killed;
             },
             GeneratorState::Yielded(KernelTrap::StoreAccessFault(a)) => {
                 let ctx = rt.context_mut();
                 println!("[kernel] Store access fault to {:#x} in {:#x}, core dumped.", a, ctx.sepc);
-                rt.prepare_next_app(app::APP_MANAGER.prepare_next_app());
+                return ExitStatus::Killed;
+            },
+            GeneratorState::Yielded(KernelTrap::PageFault(a, access)) => {
+                if rt.process_mut().handle_page_fault(a, access) {
+                    continue; // 已经分配了页帧，重新执行出错的指令
+                }
+                let ctx = rt.context_mut();
+                println!("[kernel] {:?} page fault to {:#x} in {:#x}, core dumped.", access, a, ctx.sepc);
+                return ExitStatus::Killed;
             },
             GeneratorState::Yielded(KernelTrap::IllegalInstruction(a)) => {
                 let ctx = rt.context_mut();
                 println!("[kernel] Illegal instruction {:x} in {:#x}, core dumped.", a, ctx.sepc);
-                rt.prepare_next_app(app::APP_MANAGER.prepare_next_app());
+                return ExitStatus::Killed;
             },
-            GeneratorState::Complete(()) => {
-                sbi::shutdown()
-            }
+            GeneratorState::Complete(()) => return ExitStatus::Killed,
             // _ => todo!("handle more exceptions")
         }
     }
 }
 
+// 系统调用返回到用户，执行ecall的下一条指令
+fn return_to_user(rt: &mut executor::Runtime, ans: SyscallResult) {
+    let ctx = rt.context_mut();
+    ctx.a0 = ans.code;
+    ctx.a1 = ans.extra;
+    ctx.sepc = ctx.sepc.wrapping_add(4);
+}
+
+// 加载程序并运行到它结束，返回进程编号和结束的方式。执行器每次只运行一个进程到结束，
+// 所以调用者在这里等待：子进程和它复制出的进程都运行完后，才回到调用者
+fn run_app(app: &loader::App, kernel: &Kernel, depth: usize) -> Result<(usize, ExitStatus), SyscallError> {
+    if depth > MAX_RUN_DEPTH {
+        println!("[kernel] Failed to run app {}: nested more than {} levels", app.name(), MAX_RUN_DEPTH);
+        return Err(SyscallError::NotSupported)
+    }
+    println!("[kernel] Loading app {}", app.name());
+    let process = process::Process::try_new_from_elf(app, kernel.frame_alloc, kernel.asid_manager).map_err(|e| {
+        println!("[kernel] Failed to load app {}: {:?}", app.name(), e);
+        // 内存不足之外的错误，都是程序文件本身有问题
+        match e {
+            ProcessError::FrameAlloc(_) |
+            ProcessError::Unmap(mm::UnmapError::FrameAlloc(_)) |
+            ProcessError::Load(loader::LoadError::FrameAlloc(_)) => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument,
+        }
+    })?;
+    unsafe { asm!("fence.i") }; // 刚写入了程序的代码
+    let rt = executor::Runtime::new_user(process);
+    let pid = rt.process().pid();
+    let mut ready = VecDeque::new();
+    let status = execute(rt, &mut ready, kernel, depth);
+    while let Some(rt) = ready.pop_front() {
+        execute(rt, &mut ready, kernel, depth);
+    }
+    Ok((pid, status))
+}
+
 #[cfg_attr(not(test), panic_handler)]
 #[allow(unused)]
 fn panic(info: &PanicInfo) -> ! {
@@ -152,20 +418,133 @@ static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 #[export_name = "_start"]
 unsafe extern "C" fn entry() -> ! {
     asm!("
-    # 1. set sp
-    # sp = bootstack + (hartid + 1) * 0x10000
+1:  auipc   t2, %pcrel_hi({rust_main})
+    addi    t2, t2, %pcrel_lo(1b)
+    j       {start_kernel}
+    ", 
+    rust_main = sym rust_main,
+    start_kernel = sym start_kernel,
+    options(noreturn))
+}
+
+// 其它处理核的入口，和启动处理核一样打开分页，设置栈和tp。a0是处理核的编号，a1是启动时传入的参数
+#[naked]
+#[link_section = ".text"]
+unsafe extern "C" fn secondary_entry() -> ! {
+    asm!("
+1:  auipc   t2, %pcrel_hi({rust_main})
+    addi    t2, t2, %pcrel_lo(1b)
+    j       {start_kernel}
+    ", 
+    rust_main = sym rust_main_secondary,
+    start_kernel = sym start_kernel,
+    options(noreturn))
+}
+
+// 启动页表。内核链接在高地址，入口还在物理地址上运行：打开分页时，内核所在的物理地址要恒等映射，跳到高地址后不再使用；
+// 高地址按内核的偏移映射物理内存，建立内核的地址空间之前，内核通过它访问物理内存
+#[repr(C, align(4096))]
+struct BootPageTable([usize; BOOT_PAGE_TABLE_ENTRIES]);
+
+#[cfg(target_pointer_width = "64")]
+const BOOT_PAGE_TABLE_ENTRIES: usize = 512;
+#[cfg(target_pointer_width = "32")]
+const BOOT_PAGE_TABLE_ENTRIES: usize = 1024;
+
+static BOOT_PAGE_TABLE: BootPageTable = boot_page_table();
+
+const fn boot_page_table() -> BootPageTable {
+    const FLAGS: usize = 0xcf; // V | R | W | X | A | D
+    let mut table = [0; BOOT_PAGE_TABLE_ENTRIES];
+    // 1G大页：恒等映射0x80000000开始的1G；0xffffffc000000000开始的255G映射物理地址0开始的255G，和线性映射相同
+    #[cfg(target_pointer_width = "64")]
+    {
+        table[2] = (0x80000 << 10) | FLAGS;
+        let mut i = 0;
+        while i < 255 {
+            table[256 + i] = ((i << 18) << 10) | FLAGS;
+            i += 1;
+        }
+    }
+    // 4M大页：恒等映射0x80000000开始的4M；0xc0000000开始的1020M映射物理地址0x80000000开始的1020M，和线性映射相同
+    #[cfg(target_pointer_width = "32")]
+    {
+        table[0x200] = (0x80000 << 10) | FLAGS;
+        let mut i = 0;
+        while i < 255 {
+            table[0x300 + i] = ((0x80000 + (i << 10)) << 10) | FLAGS;
+            i += 1;
+        }
+    }
+    BootPageTable(table)
+}
+
+// 用启动页表打开分页，按处理核编号设置栈和tp，跳到t2中主函数的高地址。
+// 这时还在物理地址上运行，pc相对寻址得到的都是物理地址，加上内核的偏移才是高地址
+#[cfg(target_pointer_width = "64")]
+#[naked]
+#[link_section = ".text.entry"]
+unsafe extern "C" fn start_kernel() -> ! {
+    asm!("
+    # 1. 打开分页，模式为Sv39
+1:  auipc   t0, %pcrel_hi({boot_page_table})
+    addi    t0, t0, %pcrel_lo(1b)
+    srli    t0, t0, 12
+    li      t1, 8 << 60
+    or      t0, t0, t1
+    csrw    satp, t0
+    sfence.vma
+
+    # 2. set sp
+    # sp = bootstack + (hartid + 1) * 0x4000
+    li      t1, 0xffffffc000000000
+    mv      tp, a0
     add     t0, a0, 1
     slli    t0, t0, 14
 1:  auipc   sp, %pcrel_hi({boot_stack})
     addi    sp, sp, %pcrel_lo(1b)
     add     sp, sp, t0
+    add     sp, sp, t1
+
+    # 3. jump to main function (higher half address)
+    add     t2, t2, t1
+    jr      t2
+    ", 
+    boot_page_table = sym BOOT_PAGE_TABLE,
+    boot_stack = sym BOOT_STACK, 
+    options(noreturn))
+}
 
-    # 2. jump to rust_main (absolute address)
-1:  auipc   t0, %pcrel_hi({rust_main})
+#[cfg(target_pointer_width = "32")]
+#[naked]
+#[link_section = ".text.entry"]
+unsafe extern "C" fn start_kernel() -> ! {
+    asm!("
+    # 1. 打开分页，模式为Sv32
+1:  auipc   t0, %pcrel_hi({boot_page_table})
     addi    t0, t0, %pcrel_lo(1b)
-    jr      t0
+    srli    t0, t0, 12
+    li      t1, 1 << 31
+    or      t0, t0, t1
+    csrw    satp, t0
+    sfence.vma
+
+    # 2. set sp
+    # sp = bootstack + (hartid + 1) * 0x4000
+    li      t1, 0x40000000
+    mv      tp, a0
+    add     t0, a0, 1
+    slli    t0, t0, 14
+1:  auipc   sp, %pcrel_hi({boot_stack})
+    addi    sp, sp, %pcrel_lo(1b)
+    add     sp, sp, t0
+    add     sp, sp, t1
+
+    # 3. jump to main function (higher half address)
+    add     t2, t2, t1
+    jr      t2
     ", 
+    boot_page_table = sym BOOT_PAGE_TABLE,
     boot_stack = sym BOOT_STACK, 
-    rust_main = sym rust_main,
     options(noreturn))
 }
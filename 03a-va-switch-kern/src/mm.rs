@@ -4,7 +4,8 @@ use alloc::alloc::Layout;
 use buddy_system_allocator::LockedHeap;
 use core::ops::Range;
 
-const KERNEL_HEAP_SIZE: usize = 64 * 1024;
+// 页帧分配器的位图也放在堆上，物理内存越大，需要的堆空间越多
+const KERNEL_HEAP_SIZE: usize = 1024 * 1024;
 
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
@@ -31,6 +32,21 @@ pub(crate) fn heap_init() {
     println!("[kernel] Alloc test: {:?}", vec);
 }
 
+// 内核的虚拟地址和物理地址之差。内核链接在高半部分的地址上，内核镜像和所有物理内存都按这个偏移映射，
+// 内核通过这段线性映射访问页表和页帧。Sv39的高半部分从0xffff_ffc0_0000_0000开始，一共256G
+#[cfg(target_pointer_width = "64")]
+pub const KERNEL_OFFSET: usize = 0xffff_ffc0_0000_0000;
+#[cfg(target_pointer_width = "32")]
+pub const KERNEL_OFFSET: usize = 0x4000_0000;
+
+// 线性映射能覆盖的物理地址。这之外的物理内存内核访问不到，启动时不交给页帧分配器。
+// 虚拟地址最高的一个大页留给跳板页和陷入上下文页，不属于线性映射：RV64留出1G，覆盖物理地址0开始的255G；
+// RV32只有4G的虚拟地址，0xc0000000以上的1G留给内核，留出4M后覆盖0x80000000开始的1020M
+#[cfg(target_pointer_width = "64")]
+pub const LINEAR_MAP_PHYS: Range<usize> = 0..0x3f_c000_0000;
+#[cfg(target_pointer_width = "32")]
+pub const LINEAR_MAP_PHYS: Range<usize> = 0x8000_0000..0xbfc0_0000;
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct PhysAddr(pub usize);
 
@@ -38,6 +54,13 @@ impl PhysAddr {
     pub fn page_number<M: PageMode>(&self) -> PhysPageNum { 
         PhysPageNum(self.0 >> M::FRAME_SIZE_BITS)
     }
+    // 内核访问这个物理地址时使用的虚拟地址。物理地址不在线性映射中时panic
+    pub fn kernel_virt(&self) -> VirtAddr {
+        match self.0.checked_add(KERNEL_OFFSET) {
+            Some(va) if LINEAR_MAP_PHYS.contains(&self.0) => VirtAddr(va),
+            _ => panic!("physical address {:#x} is outside the kernel's linear map", self.0),
+        }
+    }
     // pub fn page_offset(&self) -> usize { 
     //     self.0 & (PAGE_SIZE - 1)
     // }
@@ -50,6 +73,13 @@ impl VirtAddr {
     pub fn page_number<M: PageMode>(&self) -> VirtPageNum { 
         VirtPageNum(self.0 >> M::FRAME_SIZE_BITS)
     }
+    // 内核镜像或者线性映射中的虚拟地址对应的物理地址。不在线性映射中时panic
+    pub fn kernel_phys(&self) -> PhysAddr {
+        match self.0.checked_sub(KERNEL_OFFSET) {
+            Some(pa) if LINEAR_MAP_PHYS.contains(&pa) => PhysAddr(pa),
+            _ => panic!("virtual address {:#x} is outside the kernel's linear map", self.0),
+        }
+    }
 //     pub fn page_offset(&self) -> usize { 
 //         self.0 & (PAGE_SIZE - 1)
     // }
@@ -124,6 +154,21 @@ impl StackFrameAllocator {
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct FrameAllocError;
 
+// 取消映射失败的原因。失败时页表没有被修改
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnmapError {
+    // 拆分大页时分配页表失败
+    FrameAlloc(FrameAllocError),
+    // 叶子等级的页表项不是叶子节点，页表有误。参数为这个页表项的虚拟页号
+    BadPageTable(VirtPageNum),
+}
+
+impl From<FrameAllocError> for UnmapError {
+    fn from(src: FrameAllocError) -> UnmapError {
+        UnmapError::FrameAlloc(src)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct FrameLayout {
     // 对齐到的页帧数。比如，如果是1，说明按字节运算，对齐到4K字节，
@@ -159,20 +204,151 @@ pub(crate) fn test_frame_alloc() {
     println!("[kernel-frame-test] Frame allocator test passed");
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub struct AddressSpaceId(u16);
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sbi;
 
-impl AddressSpaceId {
-    fn next_asid(&self, max_asid: AddressSpaceId) -> Option<AddressSpaceId> {
-        if self.0 >= max_asid.0 {
-            None
-        } else {
-            Some(AddressSpaceId(self.0.wrapping_add(1)))
+const BUDDY_MAX_ORDER: usize = 32;
+
+// 伙伴系统页帧分配器。**对于物理空间的一个片段，只存在一个页帧分配器，无论有多少个处理核**
+//
+// 第k阶的块包含2^k个页帧，它的起始物理页号对齐到2^k，所以分配大页需要的2M、1G对齐的内存时，只要分配足够大的块。
+// 释放时和相邻的伙伴合并为更大的块。另外用一个位图记录每个页帧是否已分配，用来检查重复释放
+#[derive(Debug)]
+pub struct BuddyFrameAllocator {
+    start: PhysPageNum,
+    end: PhysPageNum,
+    free_lists: Vec<BTreeSet<usize>>, // 每一阶空闲块的起始物理页号
+    allocated: Vec<u64>, // 每个页帧占一位，已分配为1
+}
+
+impl BuddyFrameAllocator {
+    pub fn new(start: PhysPageNum, end: PhysPageNum) -> Self {
+        Self::from_ranges(&[start..end])
+    }
+    // 管理若干段物理内存，区间之间的空洞不会被分配
+    pub fn from_ranges(ranges: &[Range<PhysPageNum>]) -> Self {
+        let start = PhysPageNum(ranges.iter().map(|r| r.start.0).min().unwrap_or(0));
+        let end = PhysPageNum(ranges.iter().map(|r| r.end.0).max().unwrap_or(0));
+        let n = end.0 - start.0;
+        let mut ans = BuddyFrameAllocator {
+            start, end,
+            free_lists: (0..BUDDY_MAX_ORDER).map(|_| BTreeSet::new()).collect(),
+            allocated: alloc::vec![0; (n + 63) / 64],
+        };
+        for range in ranges {
+            ans.insert_range(range.start.0, range.end.0);
+        }
+        ans
+    }
+    pub fn allocate_frame(&mut self) -> Result<PhysPageNum, FrameAllocError> {
+        self.allocate_frames(unsafe { FrameLayout::new_unchecked(1) }, 1)
+    }
+    pub fn deallocate_frame(&mut self, ppn: PhysPageNum) {
+        self.deallocate_frames(ppn, 1)
+    }
+    // 分配count个物理上连续的页帧，起始物理页号满足layout的对齐要求
+    pub fn allocate_frames(&mut self, layout: FrameLayout, count: usize) -> Result<PhysPageNum, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError)
+        }
+        let size = core::cmp::max(count.next_power_of_two(), layout.frame_align().next_power_of_two());
+        let order = size.trailing_zeros() as usize;
+        if order >= BUDDY_MAX_ORDER {
+            return Err(FrameAllocError)
+        }
+        let ppn = self.allocate_block(order).ok_or(FrameAllocError)?;
+        // 块的大小向上取整到了2的幂，多出来的部分还给分配器
+        self.insert_range(ppn + count, ppn + size);
+        self.set_allocated(ppn, count, true);
+        Ok(PhysPageNum(ppn))
+    }
+    pub fn deallocate_frames(&mut self, ppn: PhysPageNum, count: usize) {
+        // validity check
+        if ppn.0 < self.start.0 || ppn.0 + count > self.end.0 || (ppn.0..ppn.0 + count).any(|p| !self.is_allocated(p)) {
+            panic!("Frame ppn={:x?} has not been allocated!", ppn);
+        }
+        self.set_allocated(ppn.0, count, false);
+        // recycle
+        self.insert_range(ppn.0, ppn.0 + count);
+    }
+    // 从不小于order阶的块中拆分出一个order阶的块
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let found = (order..BUDDY_MAX_ORDER).find(|&o| !self.free_lists[o].is_empty())?;
+        let ppn = *self.free_lists[found].iter().next().unwrap();
+        self.free_lists[found].remove(&ppn);
+        for o in (order..found).rev() { // 拆分剩下的后半部分放回空闲链表
+            self.free_lists[o].insert(ppn + (1 << o));
+        }
+        Some(ppn)
+    }
+    // 把一个区间按对齐拆分为尽量大的块，放回空闲链表
+    fn insert_range(&mut self, mut from: usize, to: usize) {
+        while from < to {
+            let mut order = core::cmp::min(from.trailing_zeros() as usize, BUDDY_MAX_ORDER - 1);
+            while from + (1 << order) > to {
+                order -= 1;
+            }
+            self.free_block(from, order);
+            from += 1 << order;
+        }
+    }
+    // 释放一个块，如果它的伙伴也是空闲的，合并为更大的块
+    fn free_block(&mut self, mut ppn: usize, mut order: usize) {
+        while order + 1 < BUDDY_MAX_ORDER {
+            let buddy = ppn ^ (1 << order);
+            if !self.free_lists[order].remove(&buddy) {
+                break
+            }
+            ppn = core::cmp::min(ppn, buddy);
+            order += 1;
+        }
+        self.free_lists[order].insert(ppn);
+    }
+    fn is_allocated(&self, ppn: usize) -> bool {
+        let idx = ppn - self.start.0;
+        self.allocated[idx / 64] & (1 << (idx % 64)) != 0
+    }
+    fn set_allocated(&mut self, ppn: usize, count: usize, value: bool) {
+        for idx in ppn - self.start.0..ppn - self.start.0 + count {
+            if value {
+                self.allocated[idx / 64] |= 1 << (idx % 64);
+            } else {
+                self.allocated[idx / 64] &= !(1 << (idx % 64));
+            }
         }
     }
 }
 
-const DEFAULT_ASID: AddressSpaceId = AddressSpaceId(0); // RISC-V架构规定，必须实现
+pub(crate) fn test_buddy_frame_alloc() {
+    let from = PhysPageNum(0x80000);
+    let to = PhysPageNum(0x80800);
+    let mut alloc = BuddyFrameAllocator::new(from, to);
+    let f1 = alloc.allocate_frame();
+    assert_eq!(f1, Ok(PhysPageNum(0x80000)), "first allocation");
+    let layout_2m = unsafe { FrameLayout::new_unchecked(512) };
+    let f2 = alloc.allocate_frames(layout_2m, 512);
+    assert_eq!(f2, Ok(PhysPageNum(0x80200)), "2M aligned allocation");
+    alloc.deallocate_frame(f1.unwrap());
+    let f3 = alloc.allocate_frames(unsafe { FrameLayout::new_unchecked(1) }, 3);
+    assert_eq!(f3, Ok(PhysPageNum(0x80000)), "after free first, contiguous allocation");
+    let f4 = alloc.allocate_frame();
+    assert_eq!(f4, Ok(PhysPageNum(0x80003)), "rest of rounded up block is recycled");
+    let layout_1g = unsafe { FrameLayout::new_unchecked(512 * 512) };
+    assert_eq!(alloc.allocate_frames(layout_1g, 1), Err(FrameAllocError), "no 1G aligned block");
+    alloc.deallocate_frames(f2.unwrap(), 512);
+    alloc.deallocate_frames(f3.unwrap(), 3);
+    alloc.deallocate_frame(f4.unwrap());
+    let f5 = alloc.allocate_frames(layout_2m, 2048);
+    assert_eq!(f5, Ok(PhysPageNum(0x80000)), "all frames are merged after free");
+    println!("[kernel-frame-test] Buddy frame allocator test passed");
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AddressSpaceId(u16);
+
+pub const DEFAULT_ASID: AddressSpaceId = AddressSpaceId(0); // RISC-V架构规定，必须实现
 
 // 每个平台上是不一样的，需要通过读写satp寄存器获得
 pub fn max_asid() -> AddressSpaceId {
@@ -192,95 +368,105 @@ pub fn max_asid() -> AddressSpaceId {
     return AddressSpaceId(((val >> 22) & ((1 << 9) - 1)) as u16);
 }
 
-// 在看代码的同志们可能发现，这里分配地址空间编号的算法和StackFrameAllocator很像。
-// 这里需要注意的是，分配页帧的算法经常要被使用，而且包含很多参数，最好最快的写法不一定是简单的栈式回收分配，
-// 更好的高性能内核设计，页帧分配的算法或许会有较大的优化空间。
-// 可以包含的参数，比如，页帧的内存布局，包括内存对齐的选项，这是大页优化非常需要的选项。
-// 但是地址空间编号的分配算法而且不需要经常调用，所以可以设计得很简单，普通栈式回收的算法就足够使用了。
+// 分配到的地址空间编号，和分配时的代
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct AsidTag {
+    generation: usize,
+    asid: AddressSpaceId,
+}
 
-// 地址空间编号分配器，**每个处理核都有一个**
+// 地址空间编号管理器，**所有处理核共享一个**
+//
+// 编号按代分配：一代之中依次分配编号，不回收。编号用完时进入下一代，之前分配的编号全部失效，
+// 地址空间下次切换时再分配新一代的编号；所有处理核在下次切换地址空间时刷新全部页表缓存，之后才会用到新一代的编号。
+// 这样不管硬件实现了多少位地址空间编号，地址空间的数量都不受限制。
+// 0号编号留给内核的地址空间；硬件没有实现地址空间编号时，所有地址空间共用0号，每次分配都会换代
 #[derive(Debug)]
-pub struct StackAsidAllocator {
-    current: AddressSpaceId,
-    exhausted: bool, 
+pub struct AsidManager {
+    generation: usize,
+    next: usize, // 这一代下一个分配的编号
+    first: usize,
     max: AddressSpaceId,
-    recycled: Vec<AddressSpaceId>,
+    flush_pending: usize, // 换代后还没有刷新页表缓存的处理核，按处理核编号的位图
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub struct AsidAllocError;
-
-impl StackAsidAllocator {
+impl AsidManager {
     pub fn new(max_asid: AddressSpaceId) -> Self {
-        StackAsidAllocator { current: DEFAULT_ASID, exhausted: false, max: max_asid, recycled: Vec::new() }
+        let first = if max_asid == DEFAULT_ASID { 0 } else { 1 };
+        AsidManager { generation: 1, next: first, first, max: max_asid, flush_pending: 0 }
     }
-
-    pub fn allocate_asid(&mut self) -> Result<AddressSpaceId, AsidAllocError> {
-        if let Some(asid) = self.recycled.pop() {
-            return Ok(asid)
-        }
-        if self.exhausted {
-            return Err(AsidAllocError)
-        }
-        if self.current == self.max {
-            self.exhausted = true;
-            return Ok(self.max)
+    // 分配这一代的下一个编号，用完时换代
+    fn allocate(&mut self) -> AsidTag {
+        if self.next > self.max.0 as usize {
+            self.generation += 1;
+            self.next = self.first;
+            self.flush_pending = usize::MAX;
         }
-        if let Some(next) = self.current.next_asid(self.max) {
-            let ans = self.current;
-            self.current = next;
-            Ok(ans)
-        } else {
-            Err(AsidAllocError)
-        }
-    }
-    
-    fn deallocate_asid(&mut self, asid: AddressSpaceId) {
-        if asid.next_asid(self.max).is_none() || self.recycled.iter().find(|&v| {*v == asid}).is_some() {
-            panic!("Asid {:x?} has not been allocated!", asid);
-        }
-        self.recycled.push(asid);
-    }
-}
-
-pub(crate) fn test_asid_alloc() {
-    let max_asid = AddressSpaceId(0xffff);
-    let mut alloc = StackAsidAllocator::new(max_asid);
-    let a1 = alloc.allocate_asid();
-    assert_eq!(a1, Ok(AddressSpaceId(0)), "first allocation");
-    let a2 = alloc.allocate_asid();
-    assert_eq!(a2, Ok(AddressSpaceId(1)), "second allocation");
-    alloc.deallocate_asid(a1.unwrap());
-    let a3 = alloc.allocate_asid();
-    assert_eq!(a3, Ok(AddressSpaceId(0)), "after free first one, third allocation");
-    for _ in 0..max_asid.0 - 2 {
-        alloc.allocate_asid().unwrap();
-    }
-    let an = alloc.allocate_asid();
-    assert_eq!(an, Ok(max_asid), "last asid");
-    let an = alloc.allocate_asid();
-    assert_eq!(an, Err(AsidAllocError), "when asid exhausted, allocate next");
-    alloc.deallocate_asid(a2.unwrap());
-    let an = alloc.allocate_asid();
-    assert_eq!(an, Ok(AddressSpaceId(1)), "after free second one, allocate next");
-    let an = alloc.allocate_asid();
-    assert_eq!(an, Err(AsidAllocError), "no asid remains, allocate next");
-    
-    let mut alloc = StackAsidAllocator::new(DEFAULT_ASID); // asid not implemented
-    let a1 = alloc.allocate_asid();
-    assert_eq!(a1, Ok(AddressSpaceId(0)), "asid not implemented, first allocation");
-    let a2 = alloc.allocate_asid();
-    assert_eq!(a2, Err(AsidAllocError), "asid not implemented, second allocation");
-
-    println!("[kernel-asid-test] Asid allocator test passed");
+        let asid = AddressSpaceId(self.next as u16);
+        self.next += 1;
+        AsidTag { generation: self.generation, asid }
+    }
+    // 编号是否还属于这一代
+    fn is_current(&self, tag: AsidTag) -> bool {
+        tag.generation == self.generation
+    }
+    // 换代后，处理核第一次切换地址空间时返回true，这时需要刷新它的全部页表缓存
+    fn take_flush(&mut self, hartid: usize) -> bool {
+        let pending = self.flush_pending & (1 << hartid) != 0;
+        self.flush_pending &= !(1 << hartid);
+        pending
+    }
+}
+
+pub(crate) fn test_asid_manager() {
+    let mut manager = AsidManager::new(AddressSpaceId(3));
+    let a1 = manager.allocate();
+    assert_eq!(a1.asid, AddressSpaceId(1), "asid 0 is reserved for kernel, first allocation");
+    assert_eq!(manager.allocate().asid, AddressSpaceId(2), "second allocation");
+    assert_eq!(manager.allocate().asid, AddressSpaceId(3), "last asid");
+    assert!(!manager.take_flush(0), "no flush before rollover");
+    let a4 = manager.allocate();
+    assert_eq!(a4.asid, AddressSpaceId(1), "when asid exhausted, roll over to next generation");
+    assert!(!manager.is_current(a1), "asid from previous generation is invalid");
+    assert!(manager.is_current(a4), "asid from this generation is valid");
+    assert!(manager.take_flush(0), "hart flushes after rollover");
+    assert!(!manager.take_flush(0), "hart flushes only once");
+    assert!(manager.take_flush(1), "other harts flush too");
+
+    let mut manager = AsidManager::new(DEFAULT_ASID); // asid not implemented
+    let a1 = manager.allocate();
+    assert_eq!(a1.asid, DEFAULT_ASID, "asid not implemented, first allocation");
+    let a2 = manager.allocate();
+    assert_eq!(a2.asid, DEFAULT_ASID, "asid not implemented, second allocation shares asid 0");
+    assert!(!manager.is_current(a1), "asid not implemented, first address space is reassigned");
+    assert!(manager.take_flush(0), "asid not implemented, flush on every allocation");
+    for _ in 0..0x20000 {
+        manager.allocate();
+    }
+    assert!(manager.is_current(manager.allocate()), "unlimited allocations");
+
+    println!("[kernel-asid-test] Asid manager test passed");
 }
 
 pub trait FrameAllocator {
     fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError>;
     fn deallocate_frame(&self, ppn: PhysPageNum);
+    // 分配count个物理上连续的页帧，起始物理页号满足layout的对齐要求。默认只能分配一个不需要对齐的页帧
+    fn allocate_frames(&self, layout: FrameLayout, count: usize) -> Result<PhysPageNum, FrameAllocError> {
+        if layout.frame_align() == 1 && count == 1 {
+            self.allocate_frame()
+        } else {
+            Err(FrameAllocError)
+        }
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        for i in 0..count {
+            self.deallocate_frame(PhysPageNum(ppn.0 + i))
+        }
+    }
 }
 
-pub type DefaultFrameAllocator = spin::Mutex<StackFrameAllocator>;
+pub type DefaultFrameAllocator = spin::Mutex<BuddyFrameAllocator>;
 
 impl FrameAllocator for DefaultFrameAllocator {
     fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
@@ -289,6 +475,21 @@ impl FrameAllocator for DefaultFrameAllocator {
     fn deallocate_frame(&self, ppn: PhysPageNum) {
         self.lock().deallocate_frame(ppn)
     }
+    fn allocate_frames(&self, layout: FrameLayout, count: usize) -> Result<PhysPageNum, FrameAllocError> {
+        self.lock().allocate_frames(layout, count)
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        self.lock().deallocate_frames(ppn, count)
+    }
+}
+
+impl FrameAllocator for spin::Mutex<StackFrameAllocator> {
+    fn allocate_frame(&self) -> Result<PhysPageNum, FrameAllocError> {
+        self.lock().allocate_frame()
+    }
+    fn deallocate_frame(&self, ppn: PhysPageNum) {
+        self.lock().deallocate_frame(ppn)
+    }
 }
 
 impl<A: FrameAllocator + ?Sized> FrameAllocator for &A { 
@@ -298,6 +499,12 @@ impl<A: FrameAllocator + ?Sized> FrameAllocator for &A {
     fn deallocate_frame(&self, ppn: PhysPageNum) {
         (**self).deallocate_frame(ppn)
     }
+    fn allocate_frames(&self, layout: FrameLayout, count: usize) -> Result<PhysPageNum, FrameAllocError> {
+        (**self).allocate_frames(layout, count)
+    }
+    fn deallocate_frames(&self, ppn: PhysPageNum, count: usize) {
+        (**self).deallocate_frames(ppn, count)
+    }
 }
 
 // 表示整个页帧内存的所有权
@@ -332,6 +539,45 @@ impl<A: FrameAllocator> Drop for FrameBox<A> {
     }
 }
 
+// 表示一段物理上连续的页帧内存的所有权
+#[derive(Debug)]
+pub struct FrameBlock<A: FrameAllocator = DefaultFrameAllocator> {
+    ppn: PhysPageNum,
+    count: usize,
+    frame_alloc: A,
+}
+
+impl<A: FrameAllocator> FrameBlock<A> {
+    // 分配count个连续的页帧并创建FrameBlock
+    pub fn try_new_in(layout: FrameLayout, count: usize, frame_alloc: A) -> Result<FrameBlock<A>, FrameAllocError> {
+        let ppn = frame_alloc.allocate_frames(layout, count)?;
+        Ok(FrameBlock { ppn, count, frame_alloc })
+    }
+
+    pub fn phys_page_num(&self) -> PhysPageNum {
+        self.ppn
+    }
+}
+
+impl<A: FrameAllocator + Clone> FrameBlock<A> {
+    // 把连续页帧的所有权拆分为每一页的所有权，之后每一页可以单独释放
+    pub fn into_frames(self) -> Vec<FrameBox<A>> {
+        let block = core::mem::ManuallyDrop::new(self);
+        let ans = (0..block.count)
+            .map(|i| FrameBox { ppn: PhysPageNum(block.ppn.0 + i), frame_alloc: block.frame_alloc.clone() })
+            .collect();
+        // note(unsafe): 页帧已经交给每一页的FrameBox释放，这里只释放块中的分配器
+        drop(unsafe { core::ptr::read(&block.frame_alloc) });
+        ans
+    }
+}
+
+impl<A: FrameAllocator> Drop for FrameBlock<A> {
+    fn drop(&mut self) {
+        self.frame_alloc.deallocate_frames(self.ppn, self.count);
+    }
+}
+
 // 分页模式
 //
 // 在每个页式管理模式下，我们认为分页系统分为不同的等级，每一级如果存在大页页表，都应当有相应的对齐要求。
@@ -343,6 +589,12 @@ pub trait PageMode: Copy {
     const FRAME_SIZE_BITS: usize;
     // 当前分页模式下，物理页号的位数
     const PPN_BITS: usize;
+    // 当前分页模式下，虚拟地址的有效位数。低的一半留给用户，高的一半留给内核
+    const VIRT_ADDR_BITS: usize;
+    // 当前分页模式下，一个页表包含的页表项数目
+    const PAGE_TABLE_ENTRIES: usize;
+    // 当前分页模式下，写入satp寄存器MODE字段的值
+    const SATP_MODE: usize;
     // 得到这一层大页物理地址最低的对齐要求
     fn get_layout_for_level(level: PageLevel) -> FrameLayout;
     // 得到从高到低的页表等级
@@ -377,6 +629,12 @@ pub trait PageMode: Copy {
     fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags);
     // 得到一个页表项目包含的物理页号
     fn entry_get_ppn(entry: &mut Self::Entry) -> PhysPageNum;
+    // 得到一个页表项目的设置
+    fn entry_get_flags(entry: &mut Self::Entry) -> Self::Flags;
+    // 页表项目是否为叶子节点；如果不是叶子节点，它指向下一级页表
+    fn entry_is_leaf_page(entry: &mut Self::Entry) -> bool;
+    // 写数据，把页表项设置为无效
+    fn slot_set_invalid(slot: &mut Self::Slot);
 }
 
 // 我们认为今天的分页系统都是分为不同的等级，就是多级页表，这里表示页表的等级是多少
@@ -391,12 +649,17 @@ impl PageLevel {
 }
 
 // Sv39分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Sv39;
 
+#[cfg(target_pointer_width = "64")]
 impl PageMode for Sv39 {
     const FRAME_SIZE_BITS: usize = 12;
     const PPN_BITS: usize = 44;
+    const VIRT_ADDR_BITS: usize = 39;
+    const PAGE_TABLE_ENTRIES: usize = 512;
+    const SATP_MODE: usize = 8;
     type PageTable = Sv39PageTable;
     fn get_layout_for_level(level: PageLevel) -> FrameLayout {
         unsafe { match level.0 {
@@ -449,7 +712,7 @@ impl PageMode for Sv39 {
         VirtPageNum(match level.0 {
             0 => (vpn.0 & !((1 << 9) - 1)) + idx,
             1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
-            2 => (vpn.0 & !((1 << 44) - 1)) + (idx << 18),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
             _ => unimplemented!("this level does not exist on Sv39"),
         })
     }
@@ -482,13 +745,25 @@ impl PageMode for Sv39 {
     fn entry_get_ppn(entry: &mut Sv39PageEntry) -> PhysPageNum {
         entry.ppn()
     }
+    fn entry_get_flags(entry: &mut Sv39PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn entry_is_leaf_page(entry: &mut Sv39PageEntry) -> bool {
+        // R、W、X中有一位不为零，说明是叶子节点
+        entry.flags().intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X)
+    }
+    fn slot_set_invalid(slot: &mut Sv39PageSlot) {
+        slot.bits = 0; // V=0
+    }
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageTable {
     entries: [Sv39PageSlot; 512], // todo: other modes
 }
 
+#[cfg(target_pointer_width = "64")]
 impl core::ops::Index<usize> for Sv39PageTable {
     type Output = Sv39PageSlot;
     fn index(&self, idx: usize) -> &Sv39PageSlot {
@@ -496,17 +771,20 @@ impl core::ops::Index<usize> for Sv39PageTable {
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 impl core::ops::IndexMut<usize> for Sv39PageTable {
     fn index_mut(&mut self, idx: usize) -> &mut Sv39PageSlot {
         &mut self.entries[idx]
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageSlot {
     bits: usize,
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageEntry {
     bits: usize,
@@ -514,6 +792,7 @@ pub struct Sv39PageEntry {
 
 use bit_field::BitField;
 
+#[cfg(target_pointer_width = "64")]
 impl Sv39PageEntry {
     #[inline]
     pub fn ppn(&self) -> PhysPageNum {
@@ -542,106 +821,921 @@ bitflags::bitflags! {
     }
 }
 
-// 表示一个分页系统实现的地址空间
-//
-// 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
-#[derive(Debug)]
-pub struct PagedAddrSpace<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
-    root_frame: FrameBox<A>,
-    frames: Vec<FrameBox<A>>,
-    frame_alloc: A,
-    page_mode: M,
-}
+// Sv48分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv48;
 
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
-    // 创建一个空的分页地址空间。一定会产生内存的写操作
-    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
-        // 新建一个满足根页表对齐要求的帧；虽然代码没有体现，通常对齐要求是1
-        let mut root_frame = FrameBox::try_new_in(frame_alloc.clone())?;
-        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
-        // 向帧里填入一个空的根页表 
-        unsafe { fill_frame_with_initialized_page_table::<A, M>(&mut root_frame) };
-        Ok(Self { root_frame, frames: Vec::new(), frame_alloc, page_mode })
+#[cfg(target_pointer_width = "64")]
+impl PageMode for Sv48 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 44;
+    const VIRT_ADDR_BITS: usize = 48;
+    const PAGE_TABLE_ENTRIES: usize = 512;
+    const SATP_MODE: usize = 9;
+    type PageTable = Sv48PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(512), // 2M页
+            2 => FrameLayout::new_unchecked(512 * 512), // 1G页
+            3 => FrameLayout::new_unchecked(512 * 512 * 512), // 512G页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv48")
+        } }
     }
-    // 得到根页表的地址
-    pub fn root_page_number(&self) -> PhysPageNum {
-        self.root_frame.phys_page_num()
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(3), PageLevel(2), PageLevel(1)],
+            2 => &[PageLevel(3), PageLevel(2)],
+            3 => &[PageLevel(3)],
+            _ => unimplemented!("this level does not exist on Sv48"),
+        }
+    }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(3), PageLevel(2), PageLevel(1)],
+            1 => &[PageLevel(3), PageLevel(2)],
+            2 => &[PageLevel(3)],
+            3 => &[],
+            _ => unimplemented!("this level does not exist on Sv48"),
+        }
+    }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            2 => &[PageLevel(2), PageLevel(1), PageLevel(0)],
+            3 => &[PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv48"),
+        }
+    }
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 9)) & 511
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
+        let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
+        if level.0 <= 2 {
+            let start_idx1 = vpn_range.start.0 >> ((level.0 + 1) * 9);
+            let end_idx1 = vpn_range.end.0 >> ((level.0 + 1) * 9);
+            if end_idx1 > start_idx1 {
+                end = 512;
+            }
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
+            3 => (vpn.0 & !((1 << 36) - 1)) + (idx << 27),
+            _ => unimplemented!("this level does not exist on Sv48"),
+        })
+    }
+    type Entry = Sv48PageEntry;
+    type Slot = Sv48PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv48PageSlot) -> Result<&mut Sv48PageEntry, &mut Sv48PageSlot> {
+        // note(unsafe): slot是合法的
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv48PageEntry) };
+        if ans.flags().contains(Sv39Flags::V) {
+            Ok(ans)
+        } else {
+            Err(slot)
+        }
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() }; // 全零
+    }
+    type Flags = Sv39Flags; // 页表项的设置和Sv39相同
+    fn slot_set_child(slot: &mut Sv48PageSlot, ppn: PhysPageNum) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv48PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V); // V=1, R=W=X=0
+    }
+    fn slot_set_mapping(slot: &mut Sv48PageSlot, ppn: PhysPageNum, flags: Sv39Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv48PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V | flags);
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv48PageEntry, ppn: PhysPageNum, flags: Sv39Flags) {
+        entry.write_ppn_flags(ppn, flags);
+    }
+    fn entry_get_ppn(entry: &mut Sv48PageEntry) -> PhysPageNum {
+        entry.ppn()
+    }
+    fn entry_get_flags(entry: &mut Sv48PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn entry_is_leaf_page(entry: &mut Sv48PageEntry) -> bool {
+        entry.flags().intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X)
+    }
+    fn slot_set_invalid(slot: &mut Sv48PageSlot) {
+        slot.bits = 0;
     }
 }
 
-#[inline] unsafe fn unref_ppn_mut<'a, M: PageMode>(ppn: PhysPageNum) -> &'a mut M::PageTable {
-    let pa = ppn.addr_begin::<M>();
-    &mut *(pa.0 as *mut M::PageTable)
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+pub struct Sv48PageTable {
+    entries: [Sv48PageSlot; 512],
 }
 
-#[inline] unsafe fn fill_frame_with_initialized_page_table<A: FrameAllocator, M: PageMode>(b: &mut FrameBox<A>) {
-    let a = &mut *(b.ppn.addr_begin::<M>().0 as *mut M::PageTable);
-    M::init_page_table(a);
+#[cfg(target_pointer_width = "64")]
+impl core::ops::Index<usize> for Sv48PageTable {
+    type Output = Sv48PageSlot;
+    fn index(&self, idx: usize) -> &Sv48PageSlot {
+        &self.entries[idx]
+    }
 }
 
-impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {    
-    // 设置entry。如果寻找的过程中，中间的页表没创建，那么创建它们
-    unsafe fn alloc_get_table(&mut self, entry_level: PageLevel, vpn_start: VirtPageNum) -> Result<&mut M::PageTable, FrameAllocError> {
-        let mut ppn = self.root_frame.phys_page_num();
-        for &level in M::visit_levels_before(entry_level) {
-            // println!("[] BEFORE PPN = {:x?}", ppn);
-            let page_table = unref_ppn_mut::<M>(ppn);
-            let vidx = M::vpn_index(vpn_start, level);
-            match M::slot_try_get_entry(&mut page_table[vidx]) {
-                Ok(entry) => ppn = M::entry_get_ppn(entry),
-                Err(mut slot) => {  // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
-                    let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
-                    M::slot_set_child(&mut slot, frame_box.phys_page_num());
-                    // println!("[] Created a new frame box");
-                    ppn = frame_box.phys_page_num();
-                    self.frames.push(frame_box);
-                }
-            }
-        }
-        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
-        let page_table = unref_ppn_mut::<M>(ppn); // 此时ppn是当前所需要修改的页表
-        // 创建了一个没有约束的生命周期。不过我们可以判断它是合法的，因为它的所有者是Self，在Self的周期内都合法
-        Ok(&mut *(page_table as *mut _))
-    }
-    pub fn allocate_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
-        for (page_level, vpn_range) in MapPairs::solve(vpn, ppn, n, self.page_mode) {
-            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
-            let table = unsafe { self.alloc_get_table(page_level, vpn_range.start) }?;
-            let idx_range = M::vpn_index_range(vpn_range.clone(), page_level);
-            // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
-            for vidx in idx_range {
-                let this_ppn = PhysPageNum(ppn.0 - vpn.0 + M::vpn_level_index(vpn_range.start, page_level, vidx).0);
-                // println!("[kernel-alloc-map-test] Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
-                match M::slot_try_get_entry(&mut table[vidx]) {
-                    Ok(_entry) => panic!("already allocated"),
-                    Err(slot) => M::slot_set_mapping(slot, this_ppn, flags.clone())
-                }
-            }
-        }
-        Ok(())
+#[cfg(target_pointer_width = "64")]
+impl core::ops::IndexMut<usize> for Sv48PageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv48PageSlot {
+        &mut self.entries[idx]
     }
-    // pub fn unmap(&mut self, vpn: VirtPageNum) {
-    //     todo!()
-    // }
 }
 
-#[derive(Debug)]
-pub struct MapPairs<M> {
-    ans_iter: alloc::vec::IntoIter<(PageLevel, Range<VirtPageNum>)>,
-    mode: M,
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+pub struct Sv48PageSlot {
+    bits: usize,
 }
 
-impl<M: PageMode> MapPairs<M> {
-    pub fn solve(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, mode: M) -> Self {
-        let mut ans = Vec::new();
-        for &i in M::visit_levels_until(PageLevel::leaf_level()) {
-            let align = M::get_layout_for_level(i).frame_align();
-            if (vpn.0 - ppn.0) % align != 0 || n < align {
-                continue;
-            }
-            let (mut ve_prev, mut vs_prev) = (None, None);
-            for &j in M::visit_levels_from(i) {
-                let align_cur = M::get_layout_for_level(j).frame_align();
+// 页表项的格式和Sv39相同
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+pub struct Sv48PageEntry {
+    bits: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+impl Sv48PageEntry {
+    #[inline]
+    pub fn ppn(&self) -> PhysPageNum {
+        PhysPageNum(self.bits.get_bits(10..54))
+    }
+    #[inline]
+    pub fn flags(&self) -> Sv39Flags {
+        Sv39Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
+    }
+    #[inline]
+    pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
+        self.bits = (ppn.0 << 10) | flags.bits() as usize
+    }
+}
+
+// Sv57分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv57;
+
+#[cfg(target_pointer_width = "64")]
+impl PageMode for Sv57 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 44;
+    const VIRT_ADDR_BITS: usize = 57;
+    const PAGE_TABLE_ENTRIES: usize = 512;
+    const SATP_MODE: usize = 10;
+    type PageTable = Sv57PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(512), // 2M页
+            2 => FrameLayout::new_unchecked(512 * 512), // 1G页
+            3 => FrameLayout::new_unchecked(512 * 512 * 512), // 512G页
+            4 => FrameLayout::new_unchecked(512 * 512 * 512 * 512), // 256T页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv57")
+        } }
+    }
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1)],
+            2 => &[PageLevel(4), PageLevel(3), PageLevel(2)],
+            3 => &[PageLevel(4), PageLevel(3)],
+            4 => &[PageLevel(4)],
+            _ => unimplemented!("this level does not exist on Sv57"),
+        }
+    }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1)],
+            1 => &[PageLevel(4), PageLevel(3), PageLevel(2)],
+            2 => &[PageLevel(4), PageLevel(3)],
+            3 => &[PageLevel(4)],
+            4 => &[],
+            _ => unimplemented!("this level does not exist on Sv57"),
+        }
+    }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            2 => &[PageLevel(2), PageLevel(1), PageLevel(0)],
+            3 => &[PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            4 => &[PageLevel(4), PageLevel(3), PageLevel(2), PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv57"),
+        }
+    }
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 9)) & 511
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 9)) & 511;
+        let mut end = (vpn_range.end.0 >> (level.0 * 9)) & 511;
+        if level.0 <= 3 {
+            let start_idx1 = vpn_range.start.0 >> ((level.0 + 1) * 9);
+            let end_idx1 = vpn_range.end.0 >> ((level.0 + 1) * 9);
+            if end_idx1 > start_idx1 {
+                end = 512;
+            }
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 9) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 18) - 1)) + (idx << 9),
+            2 => (vpn.0 & !((1 << 27) - 1)) + (idx << 18),
+            3 => (vpn.0 & !((1 << 36) - 1)) + (idx << 27),
+            4 => (vpn.0 & !((1 << 45) - 1)) + (idx << 36),
+            _ => unimplemented!("this level does not exist on Sv57"),
+        })
+    }
+    type Entry = Sv57PageEntry;
+    type Slot = Sv57PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv57PageSlot) -> Result<&mut Sv57PageEntry, &mut Sv57PageSlot> {
+        // note(unsafe): slot是合法的
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv57PageEntry) };
+        if ans.flags().contains(Sv39Flags::V) {
+            Ok(ans)
+        } else {
+            Err(slot)
+        }
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() }; // 全零
+    }
+    type Flags = Sv39Flags; // 页表项的设置和Sv39相同
+    fn slot_set_child(slot: &mut Sv57PageSlot, ppn: PhysPageNum) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv57PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V); // V=1, R=W=X=0
+    }
+    fn slot_set_mapping(slot: &mut Sv57PageSlot, ppn: PhysPageNum, flags: Sv39Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv57PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V | flags);
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv57PageEntry, ppn: PhysPageNum, flags: Sv39Flags) {
+        entry.write_ppn_flags(ppn, flags);
+    }
+    fn entry_get_ppn(entry: &mut Sv57PageEntry) -> PhysPageNum {
+        entry.ppn()
+    }
+    fn entry_get_flags(entry: &mut Sv57PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn entry_is_leaf_page(entry: &mut Sv57PageEntry) -> bool {
+        entry.flags().intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X)
+    }
+    fn slot_set_invalid(slot: &mut Sv57PageSlot) {
+        slot.bits = 0;
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+pub struct Sv57PageTable {
+    entries: [Sv57PageSlot; 512],
+}
+
+#[cfg(target_pointer_width = "64")]
+impl core::ops::Index<usize> for Sv57PageTable {
+    type Output = Sv57PageSlot;
+    fn index(&self, idx: usize) -> &Sv57PageSlot {
+        &self.entries[idx]
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+impl core::ops::IndexMut<usize> for Sv57PageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv57PageSlot {
+        &mut self.entries[idx]
+    }
+}
+
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+pub struct Sv57PageSlot {
+    bits: usize,
+}
+
+// 页表项的格式和Sv39相同
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+pub struct Sv57PageEntry {
+    bits: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+impl Sv57PageEntry {
+    #[inline]
+    pub fn ppn(&self) -> PhysPageNum {
+        PhysPageNum(self.bits.get_bits(10..54))
+    }
+    #[inline]
+    pub fn flags(&self) -> Sv39Flags {
+        Sv39Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
+    }
+    #[inline]
+    pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
+        self.bits = (ppn.0 << 10) | flags.bits() as usize
+    }
+}
+
+// Sv32分页系统模式；RISC-V RV32下有效
+//
+// 页表项的物理页号有22位，所以物理地址有34位，比RV32的usize更宽。这个模式下，物理页号是合法的，
+// 但是超过4G的物理地址不能用PhysAddr表示
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv32;
+
+impl PageMode for Sv32 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 22;
+    const VIRT_ADDR_BITS: usize = 32;
+    const PAGE_TABLE_ENTRIES: usize = 1024;
+    const SATP_MODE: usize = 1;
+    type PageTable = Sv32PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(1024), // 4M页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv32")
+        } }
+    }
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(1)],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(1)],
+            1 => &[],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 10)) & 1023
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 10)) & 1023;
+        let mut end = (vpn_range.end.0 >> (level.0 * 10)) & 1023;
+        if level.0 == 0 {
+            let start_idx1 = vpn_range.start.0 >> 10;
+            let end_idx1 = vpn_range.end.0 >> 10;
+            if end_idx1 > start_idx1 {
+                end = 1024;
+            }
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 10) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 20) - 1)) + (idx << 10),
+            _ => unimplemented!("this level does not exist on Sv32"),
+        })
+    }
+    type Entry = Sv32PageEntry;
+    type Slot = Sv32PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv32PageSlot) -> Result<&mut Sv32PageEntry, &mut Sv32PageSlot> {
+        // note(unsafe): slot是合法的
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        if ans.flags().contains(Sv39Flags::V) {
+            Ok(ans)
+        } else {
+            Err(slot)
+        }
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() }; // 全零
+    }
+    type Flags = Sv39Flags; // 页表项低8位的设置和Sv39相同
+    fn slot_set_child(slot: &mut Sv32PageSlot, ppn: PhysPageNum) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V); // V=1, R=W=X=0
+    }
+    fn slot_set_mapping(slot: &mut Sv32PageSlot, ppn: PhysPageNum, flags: Sv39Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv39Flags::V | flags);
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv32PageEntry, ppn: PhysPageNum, flags: Sv39Flags) {
+        entry.write_ppn_flags(ppn, flags);
+    }
+    fn entry_get_ppn(entry: &mut Sv32PageEntry) -> PhysPageNum {
+        entry.ppn()
+    }
+    fn entry_get_flags(entry: &mut Sv32PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn entry_is_leaf_page(entry: &mut Sv32PageEntry) -> bool {
+        entry.flags().intersects(Sv39Flags::R | Sv39Flags::W | Sv39Flags::X)
+    }
+    fn slot_set_invalid(slot: &mut Sv32PageSlot) {
+        slot.bits = 0;
+    }
+}
+
+#[repr(C)]
+pub struct Sv32PageTable {
+    entries: [Sv32PageSlot; 1024],
+}
+
+impl core::ops::Index<usize> for Sv32PageTable {
+    type Output = Sv32PageSlot;
+    fn index(&self, idx: usize) -> &Sv32PageSlot {
+        &self.entries[idx]
+    }
+}
+
+impl core::ops::IndexMut<usize> for Sv32PageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv32PageSlot {
+        &mut self.entries[idx]
+    }
+}
+
+// Sv32的页表项总是32位的
+#[repr(C)]
+pub struct Sv32PageSlot {
+    bits: u32,
+}
+
+#[repr(C)]
+pub struct Sv32PageEntry {
+    bits: u32,
+}
+
+impl Sv32PageEntry {
+    #[inline]
+    pub fn ppn(&self) -> PhysPageNum {
+        PhysPageNum(self.bits.get_bits(10..32) as usize)
+    }
+    #[inline]
+    pub fn flags(&self) -> Sv39Flags {
+        Sv39Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
+    }
+    #[inline]
+    pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv39Flags) {
+        self.bits = ((ppn.0 as u32) << 10) | flags.bits() as u32
+    }
+}
+
+// 内核使用的分页模式
+#[cfg(target_pointer_width = "64")]
+pub use Sv39 as DefaultPageMode;
+#[cfg(target_pointer_width = "32")]
+pub use Sv32 as DefaultPageMode;
+
+// 表示一个分页系统实现的地址空间
+//
+// 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
+#[derive(Debug)]
+pub struct PagedAddrSpace<M: PageMode, A: FrameAllocator = DefaultFrameAllocator> {
+    root_frame: FrameBox<A>,
+    frames: Vec<FrameBox<A>>,
+    // 由地址空间分配的数据页帧，按物理页号记录每一页。写时复制的地址空间共享这些页帧，
+    // 每个地址空间持有一个引用；最后一个使用它的地址空间复制了自己的页、取消映射或者释放时，页帧才释放
+    data_frames: BTreeMap<usize, Arc<FrameBox<A>>>,
+    cow_pages: BTreeSet<usize>, // 写时复制的叶子页表项，记录它开始的虚拟页号
+    frame_alloc: A,
+    page_mode: M,
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 创建一个空的分页地址空间。一定会产生内存的写操作
+    pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
+        // 新建一个满足根页表对齐要求的帧；虽然代码没有体现，通常对齐要求是1
+        let mut root_frame = FrameBox::try_new_in(frame_alloc.clone())?;
+        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
+        // 向帧里填入一个空的根页表 
+        unsafe { fill_frame_with_initialized_page_table::<A, M>(&mut root_frame) };
+        Ok(Self { root_frame, frames: Vec::new(), data_frames: BTreeMap::new(), cow_pages: BTreeSet::new(), frame_alloc, page_mode })
+    }
+    // 得到根页表的地址
+    pub fn root_page_number(&self) -> PhysPageNum {
+        self.root_frame.phys_page_num()
+    }
+    // 软件遍历页表，把虚拟地址翻译为物理地址。返回物理地址、页表项的设置和叶子页表项所在的等级；
+    // 如果没有映射，返回None
+    pub fn translate(&self, va: VirtAddr) -> Option<(PhysAddr, M::Flags, PageLevel)> {
+        let vpn = va.page_number::<M>();
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_until(PageLevel::leaf_level()) {
+            // note(unsafe): 页表都由这个地址空间拥有，在self的周期内合法
+            let page_table = unsafe { unref_ppn_mut::<M>(ppn) };
+            let entry = M::slot_try_get_entry(&mut page_table[M::vpn_index(vpn, level)]).ok()?;
+            if M::entry_is_leaf_page(entry) {
+                // 如果是大页，虚拟页号在大页里的偏移也要加到物理页号上
+                let page_offset = vpn.0 % M::get_layout_for_level(level).frame_align();
+                let frame_offset = va.0 & ((1 << M::FRAME_SIZE_BITS) - 1);
+                let pa = PhysPageNum(M::entry_get_ppn(entry).0 + page_offset).addr_begin::<M>().0 + frame_offset;
+                return Some((PhysAddr(pa), M::entry_get_flags(entry), level))
+            }
+            ppn = M::entry_get_ppn(entry);
+        }
+        None // 叶子等级的页表项不是叶子节点，页表有误
+    }
+    // 找到虚拟页号所在的叶子页表项和它的等级
+    fn find_leaf_entry(&mut self, vpn: VirtPageNum) -> Option<(&mut M::Entry, PageLevel)> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_until(PageLevel::leaf_level()) {
+            // note(unsafe): 页表都由这个地址空间拥有，可变借用self期间不会有别的引用
+            let page_table = unsafe { unref_ppn_mut::<M>(ppn) };
+            let entry = M::slot_try_get_entry(&mut page_table[M::vpn_index(vpn, level)]).ok()?;
+            if M::entry_is_leaf_page(entry) {
+                return Some((entry, level))
+            }
+            ppn = M::entry_get_ppn(entry);
+        }
+        None
+    }
+    // 只读地遍历地址空间的所有映射
+    pub fn walk(&self) -> PageWalk<'_, M, A> {
+        let root_level = M::visit_levels_until(PageLevel::leaf_level())[0];
+        let mut stack = Vec::new();
+        stack.push((self.root_frame.phys_page_num(), root_level, 0, VirtPageNum(0)));
+        PageWalk { stack, _space: core::marker::PhantomData }
+    }
+}
+
+// 页表遍历器，按虚拟页号从小到大，得到每个叶子页表项的(虚拟页号, 物理页号, 设置, 页表等级)
+//
+// 虚拟页号没有经过符号扩展，比如Sv39下，高半部分的地址得到的虚拟页号不包含最高的符号位
+pub struct PageWalk<'a, M: PageMode, A: FrameAllocator> {
+    // (页表的物理页号, 页表等级, 下一个要访问的索引, 页表开始的虚拟页号)
+    stack: Vec<(PhysPageNum, PageLevel, usize, VirtPageNum)>,
+    _space: core::marker::PhantomData<&'a PagedAddrSpace<M, A>>,
+}
+
+impl<'a, M: PageMode, A: FrameAllocator> Iterator for PageWalk<'a, M, A> {
+    type Item = (VirtPageNum, PhysPageNum, M::Flags, PageLevel);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (table_ppn, level, idx, table_vpn) = self.stack.last_mut()?;
+            if *idx >= M::PAGE_TABLE_ENTRIES {
+                self.stack.pop();
+                continue;
+            }
+            let (table_ppn, level, vidx) = (*table_ppn, *level, *idx);
+            *idx += 1;
+            let vpn = VirtPageNum(table_vpn.0 + vidx * M::get_layout_for_level(level).frame_align());
+            // note(unsafe): 遍历器借用了地址空间，页表在遍历期间不会被修改或释放
+            let page_table = unsafe { unref_ppn_mut::<M>(table_ppn) };
+            if let Ok(entry) = M::slot_try_get_entry(&mut page_table[vidx]) {
+                if M::entry_is_leaf_page(entry) {
+                    return Some((vpn, M::entry_get_ppn(entry), M::entry_get_flags(entry), level))
+                }
+                // 叶子等级的页表项不是叶子节点时，页表有误，跳过这一项
+                if let Some(&next_level) = M::visit_levels_from(level).get(1) {
+                    self.stack.push((M::entry_get_ppn(entry), next_level, 0, vpn));
+                }
+            }
+        }
+    }
+}
+
+#[inline] unsafe fn unref_ppn_mut<'a, M: PageMode>(ppn: PhysPageNum) -> &'a mut M::PageTable {
+    let va = ppn.addr_begin::<M>().kernel_virt();
+    &mut *(va.0 as *mut M::PageTable)
+}
+
+#[inline] unsafe fn fill_frame_with_initialized_page_table<A: FrameAllocator, M: PageMode>(b: &mut FrameBox<A>) {
+    let a = &mut *(b.ppn.addr_begin::<M>().kernel_virt().0 as *mut M::PageTable);
+    M::init_page_table(a);
+}
+
+impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {    
+    // 设置entry。如果寻找的过程中，中间的页表没创建，那么创建它们
+    unsafe fn alloc_get_table(&mut self, entry_level: PageLevel, vpn_start: VirtPageNum) -> Result<&mut M::PageTable, FrameAllocError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_before(entry_level) {
+            // println!("[] BEFORE PPN = {:x?}", ppn);
+            let page_table = unref_ppn_mut::<M>(ppn);
+            let vidx = M::vpn_index(vpn_start, level);
+            match M::slot_try_get_entry(&mut page_table[vidx]) {
+                Ok(entry) => ppn = M::entry_get_ppn(entry),
+                Err(mut slot) => {  // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
+                    let mut frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+                    // 页帧可能是回收过的，里面还有旧的页表数据，必须先清空
+                    fill_frame_with_initialized_page_table::<A, M>(&mut frame_box);
+                    M::slot_set_child(&mut slot, frame_box.phys_page_num());
+                    // println!("[] Created a new frame box");
+                    ppn = frame_box.phys_page_num();
+                    self.frames.push(frame_box);
+                }
+            }
+        }
+        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
+        let page_table = unref_ppn_mut::<M>(ppn); // 此时ppn是当前所需要修改的页表
+        // 创建了一个没有约束的生命周期。不过我们可以判断它是合法的，因为它的所有者是Self，在Self的周期内都合法
+        Ok(&mut *(page_table as *mut _))
+    }
+    pub fn allocate_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
+        for (page_level, vpn_range) in MapPairs::solve(vpn, ppn, n, self.page_mode) {
+            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
+            let table = unsafe { self.alloc_get_table(page_level, vpn_range.start) }?;
+            let idx_range = M::vpn_index_range(vpn_range.clone(), page_level);
+            // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
+            for vidx in idx_range {
+                let this_ppn = PhysPageNum(ppn.0 + (M::vpn_level_index(vpn_range.start, page_level, vidx).0 - vpn.0));
+                // println!("[kernel-alloc-map-test] Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
+                match M::slot_try_get_entry(&mut table[vidx]) {
+                    Ok(_entry) => panic!("already allocated"),
+                    Err(slot) => M::slot_set_mapping(slot, this_ppn, flags.clone())
+                }
+            }
+        }
+        Ok(())
+    }
+    // 分配n个物理上连续的新页帧，清零后映射到vpn开始的虚拟页，返回分配的起始物理页号。
+    // 如果vpn满足大页的对齐要求，页帧也按大页对齐，这样中间的部分可以用大页映射。
+    // 页帧由地址空间拥有，取消映射或者地址空间释放时释放
+    pub fn allocate_map_frames(&mut self, vpn: VirtPageNum, n: usize, flags: M::Flags) -> Result<PhysPageNum, FrameAllocError> {
+        let mut layout = M::get_layout_for_level(PageLevel::leaf_level());
+        for &level in M::visit_levels_until(PageLevel::leaf_level()) {
+            let level_layout = M::get_layout_for_level(level);
+            let align = level_layout.frame_align();
+            if n >= align && vpn.0 % align == 0 {
+                layout = level_layout;
+                break;
+            }
+        }
+        let block = FrameBlock::try_new_in(layout, n, self.frame_alloc.clone())?;
+        let ppn = block.phys_page_num();
+        // note(unsafe): 页帧是刚分配的，内核通过线性映射访问
+        unsafe { core::ptr::write_bytes(ppn.addr_begin::<M>().kernel_virt().0 as *mut u8, 0, n << M::FRAME_SIZE_BITS) };
+        for frame in block.into_frames() {
+            self.data_frames.insert(frame.phys_page_num().0, Arc::new(frame));
+        }
+        self.allocate_map(vpn, ppn, n, flags)?;
+        Ok(ppn)
+    }
+    // 取消从vpn开始n个页的映射。如果区间只覆盖了大页的一部分，先把大页拆分为下一级页表，再取消映射。
+    // 拆分用的页表在修改之前全部分配好；分配失败或者页表有误时返回错误，页表保持原样。
+    // 取消映射后变为空的中间页表将被释放，页帧还给页帧分配器；根页表不会释放。
+    // 由地址空间分配的页帧，去掉这个地址空间的引用。这个函数不会刷新页表缓存，调用者需要用TlbShootdown::flush刷新这个区间
+    pub fn unmap(&mut self, vpn: VirtPageNum, n: usize) -> Result<(), UnmapError> {
+        let root_ppn = self.root_frame.phys_page_num();
+        let root_level = M::visit_levels_until(PageLevel::leaf_level())[0];
+        let vpn_range = vpn..VirtPageNum(vpn.0 + n);
+        // note(unsafe): 页表都由这个地址空间拥有，这里只读
+        let splits = unsafe { count_splits::<M>(root_ppn, root_level, vpn_range.clone()) }?;
+        let mut spare = Vec::with_capacity(splits);
+        for _ in 0..splits {
+            spare.push(FrameBox::try_new_in(self.frame_alloc.clone())?); // 失败时已经分配的页帧随spare释放
+        }
+        unsafe { self.unmap_in_table(root_ppn, root_level, vpn_range, &mut spare) };
+        Ok(())
+    }
+    // 在等级为level的页表中取消映射，vpn_range必须位于这个页表管理的范围中。
+    // 页表已经由count_splits检查过，spare中有足够的页帧拆分大页
+    unsafe fn unmap_in_table(&mut self, table_ppn: PhysPageNum, level: PageLevel, vpn_range: Range<VirtPageNum>, spare: &mut Vec<FrameBox<A>>) {
+        let table = unref_ppn_mut::<M>(table_ppn);
+        let entry_pages = M::get_layout_for_level(level).frame_align(); // 这一级的一个页表项管理的页数
+        let mut vpn = vpn_range.start;
+        while vpn.0 < vpn_range.end.0 {
+            let vidx = M::vpn_index(vpn, level);
+            let entry_start = vpn.0 - vpn.0 % entry_pages;
+            let cur_end = core::cmp::min(entry_start + entry_pages, vpn_range.end.0);
+            let is_whole_entry = vpn.0 == entry_start && cur_end == entry_start + entry_pages;
+            let child_ppn = match M::slot_try_get_entry(&mut table[vidx]) {
+                Err(_slot) => None, // 这一段没有映射，跳过
+                Ok(entry) => if !M::entry_is_leaf_page(entry) {
+                    Some(M::entry_get_ppn(entry))
+                } else if is_whole_entry {
+                    let ppn = M::entry_get_ppn(entry);
+                    M::slot_set_invalid(&mut table[vidx]);
+                    self.cow_pages.remove(&entry_start);
+                    for i in 0..entry_pages {
+                        self.data_frames.remove(&(ppn.0 + i));
+                    }
+                    None
+                } else { // 只覆盖了大页的一部分，需要拆分
+                    let (ppn, flags) = (M::entry_get_ppn(entry), M::entry_get_flags(entry));
+                    let frame_box = spare.pop().expect("split tables are allocated before unmapping");
+                    Some(self.split_huge_page(&mut table[vidx], level, VirtPageNum(entry_start), ppn, flags, frame_box))
+                },
+            };
+            if let Some(child_ppn) = child_ppn {
+                let next_level = M::visit_levels_from(level)[1]; // count_splits检查过，叶子等级的页表项不会指向子页表
+                self.unmap_in_table(child_ppn, next_level, vpn..VirtPageNum(cur_end), spare);
+                let child_entries = entry_pages / M::get_layout_for_level(next_level).frame_align();
+                if page_table_is_empty::<M>(child_ppn, child_entries) {
+                    M::slot_set_invalid(&mut table[vidx]);
+                    self.deallocate_table_frame(child_ppn);
+                }
+            }
+            vpn = VirtPageNum(cur_end);
+        }
+    }
+    // 把从vpn开始的一个大页拆分为下一级的页表，新页表放在frame_box中，每一项映射到大页对应的部分，设置和原来的大页相同；
+    // 写时复制的大页拆分后，每一项都是写时复制的。返回新页表的物理页号
+    unsafe fn split_huge_page(&mut self, slot: &mut M::Slot, level: PageLevel, vpn: VirtPageNum, ppn: PhysPageNum, flags: M::Flags, mut frame_box: FrameBox<A>) -> PhysPageNum {
+        let next_level = M::visit_levels_from(level)[1];
+        let child_pages = M::get_layout_for_level(next_level).frame_align();
+        let child_entries = M::get_layout_for_level(level).frame_align() / child_pages;
+        fill_frame_with_initialized_page_table::<A, M>(&mut frame_box);
+        let child_table = unref_ppn_mut::<M>(frame_box.phys_page_num());
+        for idx in 0..child_entries {
+            M::slot_set_mapping(&mut child_table[idx], PhysPageNum(ppn.0 + idx * child_pages), flags.clone());
+        }
+        if self.cow_pages.remove(&vpn.0) {
+            for idx in 0..child_entries {
+                self.cow_pages.insert(vpn.0 + idx * child_pages);
+            }
+        }
+        let child_ppn = frame_box.phys_page_num();
+        M::slot_set_child(slot, child_ppn);
+        self.frames.push(frame_box);
+        child_ppn
+    }
+    // 把vpn所在的大页逐级拆分，直到vpn所在的叶子页表项是最低一级的页。没有映射时什么也不做
+    fn split_to_leaf(&mut self, vpn: VirtPageNum) -> Result<(), FrameAllocError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &level in M::visit_levels_until(PageLevel::leaf_level()) {
+            // note(unsafe): 页表都由这个地址空间拥有，可变借用self期间不会有别的引用
+            let page_table = unsafe { unref_ppn_mut::<M>(ppn) };
+            let idx = M::vpn_index(vpn, level);
+            let (is_leaf, entry_ppn, flags) = match M::slot_try_get_entry(&mut page_table[idx]) {
+                Ok(entry) => (M::entry_is_leaf_page(entry), M::entry_get_ppn(entry), M::entry_get_flags(entry)),
+                Err(_) => return Ok(()),
+            };
+            if !is_leaf {
+                ppn = entry_ppn;
+                continue;
+            }
+            if level == PageLevel::leaf_level() {
+                return Ok(())
+            }
+            let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
+            let pages = M::get_layout_for_level(level).frame_align();
+            let leaf_vpn = VirtPageNum(vpn.0 - vpn.0 % pages);
+            ppn = unsafe { self.split_huge_page(&mut page_table[idx], level, leaf_vpn, entry_ppn, flags, frame_box) };
+        }
+        Ok(())
+    }
+    // 释放一个中间页表占用的页帧
+    fn deallocate_table_frame(&mut self, ppn: PhysPageNum) {
+        if let Some(pos) = self.frames.iter().position(|frame| frame.phys_page_num() == ppn) {
+            self.frames.swap_remove(pos); // FrameBox被丢弃，页帧还给页帧分配器
+        }
+    }
+}
+
+// 检查等级为level的页表中要取消映射的区间，不修改页表：返回需要拆分的大页个数，包括拆分后还要继续拆分的。
+// 叶子等级的页表项不是叶子节点时返回错误
+unsafe fn count_splits<M: PageMode>(table_ppn: PhysPageNum, level: PageLevel, vpn_range: Range<VirtPageNum>) -> Result<usize, UnmapError> {
+    let table = unref_ppn_mut::<M>(table_ppn);
+    let entry_pages = M::get_layout_for_level(level).frame_align();
+    let mut ans = 0;
+    let mut vpn = vpn_range.start;
+    while vpn.0 < vpn_range.end.0 {
+        let entry_start = vpn.0 - vpn.0 % entry_pages;
+        let cur_end = core::cmp::min(entry_start + entry_pages, vpn_range.end.0);
+        if let Ok(entry) = M::slot_try_get_entry(&mut table[M::vpn_index(vpn, level)]) {
+            if M::entry_is_leaf_page(entry) {
+                ans += huge_page_splits::<M>(level, vpn..VirtPageNum(cur_end));
+            } else {
+                let next_level = *M::visit_levels_from(level).get(1).ok_or(UnmapError::BadPageTable(VirtPageNum(entry_start)))?;
+                ans += count_splits::<M>(M::entry_get_ppn(entry), next_level, vpn..VirtPageNum(cur_end))?;
+            }
+        }
+        vpn = VirtPageNum(cur_end);
+    }
+    Ok(ans)
+}
+
+// 等级为level的叶子页表项中，取消映射vpn_range需要拆分的次数。vpn_range不为空，位于这个页表项之中
+fn huge_page_splits<M: PageMode>(level: PageLevel, vpn_range: Range<VirtPageNum>) -> usize {
+    let pages = M::get_layout_for_level(level).frame_align();
+    let entry_start = vpn_range.start.0 - vpn_range.start.0 % pages;
+    if vpn_range.start.0 == entry_start && vpn_range.end.0 == entry_start + pages {
+        return 0 // 整个页表项都取消映射，不需要拆分；叶子等级的页一定是这种情况
+    }
+    let next_level = M::visit_levels_from(level)[1];
+    let child_pages = M::get_layout_for_level(next_level).frame_align();
+    // 拆分后，只有区间两端所在的子页可能只覆盖了一部分
+    let first = vpn_range.start.0 - vpn_range.start.0 % child_pages;
+    let last = (vpn_range.end.0 - 1) - (vpn_range.end.0 - 1) % child_pages;
+    let mut ans = 1 + huge_page_splits::<M>(next_level, vpn_range.start..VirtPageNum(core::cmp::min(first + child_pages, vpn_range.end.0)));
+    if last != first {
+        ans += huge_page_splits::<M>(next_level, VirtPageNum(last)..vpn_range.end);
+    }
+    ans
+}
+
+#[inline] unsafe fn page_table_is_empty<M: PageMode>(ppn: PhysPageNum, n_entries: usize) -> bool {
+    let table = unref_ppn_mut::<M>(ppn);
+    (0..n_entries).all(|idx| M::slot_try_get_entry(&mut table[idx]).is_err())
+}
+
+impl<M: PageMode<Flags = Sv39Flags>, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
+    // 写时复制地复制整个地址空间。
+    // 用户态可以访问的叶子页表项，父子地址空间共享同一个页帧，并且都清除可写位；第一次写入时，再由handle_cow_fault复制页帧。
+    // 没有用户态权限的页（比如内核的跳板）直接共享。
+    // 父地址空间的页表被修改，返回子地址空间和父地址空间中去掉可写位的虚拟页区间，调用者需要刷新这些区间的页表缓存
+    pub fn clone_cow(&mut self) -> Result<(Self, Vec<Range<VirtPageNum>>), FrameAllocError> {
+        let mut child = Self::try_new_in(self.page_mode, self.frame_alloc.clone())?;
+        let mut downgraded: Vec<Range<VirtPageNum>> = Vec::new();
+        let leaves: Vec<_> = self.walk().collect();
+        for (vpn, ppn, flags, level) in leaves {
+            let n = M::get_layout_for_level(level).frame_align();
+            let mut child_flags = flags;
+            if flags.contains(Sv39Flags::U) && (flags.contains(Sv39Flags::W) || self.cow_pages.contains(&vpn.0)) {
+                child_flags.remove(Sv39Flags::W);
+                let (entry, _) = self.find_leaf_entry(vpn).expect("leaf entry from walk");
+                M::entry_write_ppn_flags(entry, ppn, child_flags);
+                self.cow_pages.insert(vpn.0);
+                child.cow_pages.insert(vpn.0);
+                // 相邻的页合并为一个区间，减少刷新的次数
+                match downgraded.last_mut() {
+                    Some(last) if last.end == vpn => last.end = VirtPageNum(vpn.0 + n),
+                    _ => downgraded.push(vpn..VirtPageNum(vpn.0 + n)),
+                }
+            }
+            child.allocate_map(vpn, ppn, n, child_flags)?;
+        }
+        child.data_frames = self.data_frames.clone();
+        Ok((child, downgraded))
+    }
+    // 处理写时复制页的存储异常。如果va所在的页是写时复制的，让这一页重新可写，返回true；否则返回false。
+    // 大页先拆分到最低一级，只处理写入的这一页。如果页帧还被其它地址空间引用，先复制出一个私有的页帧，
+    // 并去掉这个地址空间对原来页帧的引用；如果只有这个地址空间在使用，直接恢复可写位。
+    // 这个函数不会刷新页表缓存，调用者需要自己刷新
+    pub fn handle_cow_fault(&mut self, va: VirtAddr) -> Result<bool, FrameAllocError> {
+        let vpn = va.page_number::<M>();
+        let level = match self.find_leaf_entry(vpn) {
+            Some((_, level)) => level,
+            None => return Ok(false),
+        };
+        let n = M::get_layout_for_level(level).frame_align();
+        if !self.cow_pages.contains(&(vpn.0 - vpn.0 % n)) {
+            return Ok(false)
+        }
+        self.split_to_leaf(vpn)?;
+        let (entry, _) = self.find_leaf_entry(vpn).expect("leaf entry found above");
+        let (ppn, flags) = (M::entry_get_ppn(entry), M::entry_get_flags(entry));
+        // 不是由地址空间分配的页帧，不知道还有谁在使用，总是复制
+        let shared = self.data_frames.get(&ppn.0).map(|frame| Arc::strong_count(frame) > 1).unwrap_or(true);
+        let new_ppn = if shared {
+            let frame = FrameBox::try_new_in(self.frame_alloc.clone())?;
+            let new_ppn = frame.phys_page_num();
+            // note(unsafe): 新的页帧是刚分配的，内核通过线性映射访问
+            unsafe { core::ptr::copy_nonoverlapping(
+                ppn.addr_begin::<M>().kernel_virt().0 as *const u8,
+                new_ppn.addr_begin::<M>().kernel_virt().0 as *mut u8,
+                1 << M::FRAME_SIZE_BITS,
+            ) };
+            self.data_frames.insert(new_ppn.0, Arc::new(frame));
+            self.data_frames.remove(&ppn.0); // 其它地址空间是最后一个引用时，它写入时就不用再复制
+            new_ppn
+        } else {
+            ppn
+        };
+        let (entry, _) = self.find_leaf_entry(vpn).expect("leaf entry found above");
+        M::entry_write_ppn_flags(entry, new_ppn, flags | Sv39Flags::W);
+        self.cow_pages.remove(&vpn.0);
+        Ok(true)
+    }
+}
+
+#[derive(Debug)]
+pub struct MapPairs<M> {
+    ans_iter: alloc::vec::IntoIter<(PageLevel, Range<VirtPageNum>)>,
+    mode: M,
+}
+
+impl<M: PageMode> MapPairs<M> {
+    pub fn solve(vpn: VirtPageNum, ppn: PhysPageNum, n: usize, mode: M) -> Self {
+        let mut ans = Vec::new();
+        for &i in M::visit_levels_until(PageLevel::leaf_level()) {
+            let align = M::get_layout_for_level(i).frame_align();
+            if vpn.0.wrapping_sub(ppn.0) % align != 0 || n < align {
+                continue;
+            }
+            let (mut ve_prev, mut vs_prev) = (None, None);
+            for &j in M::visit_levels_from(i) {
+                let align_cur = M::get_layout_for_level(j).frame_align();
                 let ve_cur = align_cur * ((vpn.0 + align_cur - 1) / align_cur); // a * roundup(v / a)
                 let vs_cur = align_cur * ((vpn.0 + n) / align_cur); // a * rounddown((v+n) / a)
                 if let (Some(ve_prev), Some(vs_prev)) = (ve_prev, vs_prev) {
@@ -672,6 +1766,7 @@ impl<M> Iterator for MapPairs<M> {
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 pub(crate) fn test_map_solve() {
     let pairs = MapPairs::solve(VirtPageNum(0x90_000), PhysPageNum(0x50_000), 666666, Sv39).collect::<Vec<_>>();
     assert_eq!(pairs, [
@@ -686,15 +1781,320 @@ pub(crate) fn test_map_solve() {
         (PageLevel(0), VirtPageNum(589825)..VirtPageNum(590336)), 
         (PageLevel(0), VirtPageNum(667136)..VirtPageNum(667602))
     ]);
+    let pairs = MapPairs::solve(VirtPageNum(0), PhysPageNum(0), 512 * 512 * 512 + 512, Sv48).collect::<Vec<_>>();
+    assert_eq!(pairs, [
+        (PageLevel(3), VirtPageNum(0)..VirtPageNum(134217728)),
+        (PageLevel(1), VirtPageNum(134217728)..VirtPageNum(134218240))
+    ]);
+    let pairs = MapPairs::solve(VirtPageNum(0), PhysPageNum(0), 512 * 512 * 512 * 512, Sv57).collect::<Vec<_>>();
+    assert_eq!(pairs, [
+        (PageLevel(4), VirtPageNum(0)..VirtPageNum(68719476736))
+    ]);
     println!("[kernel-map-solve] Map solver test passed");
 }
 
+pub(crate) fn test_map_solve_sv32() {
+    let pairs = MapPairs::solve(VirtPageNum(0x80000), PhysPageNum(0x80000), 1024 + 1, Sv32).collect::<Vec<_>>();
+    assert_eq!(pairs, [
+        (PageLevel(1), VirtPageNum(0x80000)..VirtPageNum(0x80400)),
+        (PageLevel(0), VirtPageNum(0x80400)..VirtPageNum(0x80401))
+    ]);
+    println!("[kernel-map-solve] Sv32 map solver test passed");
+}
+
+#[cfg(target_pointer_width = "64")]
+pub(crate) fn test_unmap<A: FrameAllocator + Clone>(frame_alloc: A) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc.clone()).expect("create address space");
+    // 1024个页，映射为两个2M大页，只需要一个中间页表
+    space.allocate_map(VirtPageNum(0x80000), PhysPageNum(0x80000), 1024, Sv39Flags::R | Sv39Flags::W)
+        .expect("map two huge pages");
+    assert_eq!(space.frames.len(), 1, "two huge pages use one intermediate table");
+    space.unmap(VirtPageNum(0x80100), 16).expect("unmap part of first huge page");
+    assert_eq!(space.frames.len(), 2, "first huge page is split into a new table");
+    space.unmap(VirtPageNum(0x80000), 1024).expect("unmap all pages");
+    assert_eq!(space.frames.len(), 0, "empty intermediate tables are freed");
+    // 叶子等级的页表项没有R、W、X位，看起来指向子页表
+    let ppn = space.allocate_map_frames(VirtPageNum(0x10), 1, Sv39Flags::R).expect("map one page");
+    let (entry, _) = space.find_leaf_entry(VirtPageNum(0x10)).expect("leaf entry");
+    Sv39::entry_write_ppn_flags(entry, ppn, Sv39Flags::V);
+    assert_eq!(space.unmap(VirtPageNum(0x10), 1), Err(UnmapError::BadPageTable(VirtPageNum(0x10))), "corrupt leaf entry");
+    drop(space);
+    // 只剩一个页帧，拆分两个大页需要两个页表，一个都不拆分
+    let block = frame_alloc.allocate_frames(Sv39::get_layout_for_level(PageLevel::leaf_level()), 3).expect("allocate frames");
+    let limited = spin::Mutex::new(StackFrameAllocator::new(block, PhysPageNum(block.0 + 3)));
+    let mut space = PagedAddrSpace::try_new_in(Sv39, &limited).expect("create address space");
+    space.allocate_map(VirtPageNum(0x80000), PhysPageNum(0x80000), 1024, Sv39Flags::R).expect("map two huge pages");
+    assert_eq!(space.unmap(VirtPageNum(0x80100), 512), Err(UnmapError::FrameAlloc(FrameAllocError)), "out of frames");
+    assert_eq!(space.frames.len(), 1, "no table is split");
+    assert_eq!(space.translate(VirtAddr(0x8010_0000)).map(|(_, _, level)| level), Some(PageLevel(1)), "mapping is unchanged");
+    drop(space);
+    drop(limited);
+    frame_alloc.deallocate_frames(block, 3);
+    println!("[kernel-unmap-test] Unmap test passed");
+}
+
+#[cfg(target_pointer_width = "64")]
+pub(crate) fn test_translate<A: FrameAllocator + Clone>(frame_alloc: A) {
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).expect("create address space");
+    let flags = Sv39Flags::R | Sv39Flags::W;
+    // 两个2M大页，加上三个4K页
+    space.allocate_map(VirtPageNum(0x90000), PhysPageNum(0x80000), 1024 + 3, flags)
+        .expect("map pages");
+    assert_eq!(space.translate(VirtAddr(0x9000_1234)), Some((PhysAddr(0x8000_1234), flags | Sv39Flags::V, PageLevel(1))), "inside huge page");
+    assert_eq!(space.translate(VirtAddr(0x9040_2010)), Some((PhysAddr(0x8040_2010), flags | Sv39Flags::V, PageLevel(0))), "inside small page");
+    assert_eq!(space.translate(VirtAddr(0x9040_3000)), None, "not mapped");
+    assert_eq!(space.walk().count(), 5, "walk two huge pages and three small pages");
+    space.unmap(VirtPageNum(0x90001), 1).expect("unmap one page inside huge page");
+    assert_eq!(space.translate(VirtAddr(0x9000_1234)), None, "unmapped page");
+    assert_eq!(space.translate(VirtAddr(0x9000_2000)), Some((PhysAddr(0x8000_2000), flags | Sv39Flags::V, PageLevel(0))), "split huge page");
+    assert_eq!(space.walk().count(), 511 + 1 + 3, "walk after split");
+    // 高半部分的1G大页，和内核的线性映射一样
+    let high = PhysAddr(0x8000_0000).kernel_virt();
+    space.allocate_map(high.page_number::<Sv39>(), PhysPageNum(0x80000), 512 * 512, flags).expect("map giga page");
+    assert_eq!(space.translate(VirtAddr(high.0 + 0x20_1234)), Some((PhysAddr(0x8020_1234), flags | Sv39Flags::V, PageLevel(2))), "higher half giga page");
+    println!("[kernel-translate-test] Translate test passed");
+}
+
+// 虚拟页号可以小于物理页号（比如用户程序的页），也可以大于物理页号（比如内核的线性映射）
+#[cfg(target_pointer_width = "64")]
+pub(crate) fn test_map_offset<A: FrameAllocator + Clone>(frame_alloc: A) {
+    let pairs = MapPairs::solve(VirtPageNum(0x400), PhysPageNum(0x80400), 512 + 1, Sv39).collect::<Vec<_>>();
+    assert_eq!(pairs, [
+        (PageLevel(1), VirtPageNum(0x400)..VirtPageNum(0x600)),
+        (PageLevel(0), VirtPageNum(0x600)..VirtPageNum(0x601))
+    ], "virtual page number below physical page number");
+    let mut space = PagedAddrSpace::try_new_in(Sv39, frame_alloc).expect("create address space");
+    let flags = Sv39Flags::R;
+    space.allocate_map(VirtPageNum(0x400), PhysPageNum(0x80400), 512 + 1, flags).expect("map below physical address");
+    assert_eq!(space.translate(VirtAddr(0x40_1234)), Some((PhysAddr(0x8040_1234), flags | Sv39Flags::V, PageLevel(1))), "low huge page");
+    assert_eq!(space.translate(VirtAddr(0x60_0010)), Some((PhysAddr(0x8060_0010), flags | Sv39Flags::V, PageLevel(0))), "low small page");
+    space.allocate_map(VirtPageNum(0x10_0000), PhysPageNum(0x80000), 512, flags).expect("map above physical address");
+    assert_eq!(space.translate(VirtAddr(0x1_0000_1234)), Some((PhysAddr(0x8000_1234), flags | Sv39Flags::V, PageLevel(1))), "high huge page");
+    println!("[kernel-map-offset-test] Map offset test passed");
+}
+
+#[cfg(target_pointer_width = "64")]
+pub(crate) fn test_clone_cow<A: FrameAllocator + Clone>(frame_alloc: A) {
+    let mut parent = PagedAddrSpace::try_new_in(Sv39, frame_alloc).expect("create address space");
+    let user = Sv39Flags::R | Sv39Flags::W | Sv39Flags::U;
+    let ppn = parent.allocate_map_frames(VirtPageNum(0x10), 1, user).expect("map user page");
+    let kernel_ppn = PhysPageNum(ppn.0 + 0x100);
+    parent.allocate_map(VirtPageNum(0x20), kernel_ppn, 1, Sv39Flags::R | Sv39Flags::W).expect("map kernel page");
+    let pa = ppn.addr_begin::<Sv39>();
+    let ptr = pa.kernel_virt().0 as *mut u8;
+    unsafe { ptr.write_volatile(0x55) };
+    let (mut child, downgraded) = parent.clone_cow().expect("clone address space");
+    assert_eq!(downgraded, alloc::vec![VirtPageNum(0x10)..VirtPageNum(0x11)], "only the user page is downgraded");
+    let shared = Some((pa, (user - Sv39Flags::W) | Sv39Flags::V, PageLevel(0)));
+    assert_eq!(parent.translate(VirtAddr(0x10000)), shared, "parent page becomes read only");
+    assert_eq!(child.translate(VirtAddr(0x10000)), shared, "child shares the same frame");
+    assert_eq!(child.translate(VirtAddr(0x20000)), parent.translate(VirtAddr(0x20000)), "kernel page is shared as is");
+    assert_eq!(child.handle_cow_fault(VirtAddr(0x10008)), Ok(true), "child copies the frame");
+    let (child_pa, child_flags, _) = child.translate(VirtAddr(0x10000)).expect("child page mapped");
+    assert_ne!(child_pa, pa, "child has a private frame");
+    assert!(child_flags.contains(Sv39Flags::W), "child page is writable");
+    let child_ptr = child_pa.kernel_virt().0 as *mut u8;
+    assert_eq!(unsafe { child_ptr.read_volatile() }, 0x55, "content is copied");
+    unsafe { child_ptr.write_volatile(0x66) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 0x55, "parent is not changed");
+    assert_eq!(child.handle_cow_fault(VirtAddr(0x20000)), Ok(false), "not a copy-on-write page");
+    // 子地址空间复制后不再引用原来的页帧，父地址空间不用等它释放
+    assert_eq!(parent.handle_cow_fault(VirtAddr(0x10000)), Ok(true), "parent owns the frame again");
+    assert_eq!(parent.translate(VirtAddr(0x10000)), Some((pa, user | Sv39Flags::V, PageLevel(0))), "parent reuses the frame");
+    assert_eq!(parent.handle_cow_fault(VirtAddr(0x10000)), Ok(false), "fault already handled");
+    drop(child);
+    // 写入2M大页中的一页，只复制这一页
+    let huge_ppn = parent.allocate_map_frames(VirtPageNum(0x200), 512, user).expect("map huge page");
+    let (mut child, _) = parent.clone_cow().expect("clone address space");
+    assert_eq!(child.handle_cow_fault(VirtAddr(0x201000)), Ok(true), "child copies one page");
+    let (child_pa, child_flags, level) = child.translate(VirtAddr(0x201000)).expect("child page mapped");
+    assert_ne!(child_pa, PhysPageNum(huge_ppn.0 + 1).addr_begin::<Sv39>(), "child has a private frame");
+    assert_eq!((child_flags.contains(Sv39Flags::W), level), (true, PageLevel(0)), "huge page is split");
+    let huge_pa = huge_ppn.addr_begin::<Sv39>();
+    assert_eq!(child.translate(VirtAddr(0x202000)), Some((PhysAddr(huge_pa.0 + 0x2000), (user - Sv39Flags::W) | Sv39Flags::V, PageLevel(0))), "other pages are still shared");
+    assert_eq!(parent.translate(VirtAddr(0x201000)).map(|(_, _, level)| level), Some(PageLevel(1)), "parent keeps the huge page");
+    assert_eq!(parent.handle_cow_fault(VirtAddr(0x201000)), Ok(true), "parent writes the page the child copied");
+    assert_eq!(parent.translate(VirtAddr(0x201000)), Some((PhysAddr(huge_pa.0 + 0x1000), user | Sv39Flags::V, PageLevel(0))), "parent reuses the frame without copying");
+    assert_eq!(child.handle_cow_fault(VirtAddr(0x202000)), Ok(true), "split pages are still copy-on-write");
+    println!("[kernel-cow-test] Copy-on-write test passed");
+}
+
+// 得到satp寄存器的值，包括分页模式、地址空间编号和根页表的物理页号
+fn satp_bits<M: PageMode>(root_ppn: PhysPageNum, asid: AddressSpaceId) -> usize {
+    #[cfg(target_pointer_width = "64")]
+    return (M::SATP_MODE << 60) | ((asid.0 as usize) << 44) | root_ppn.0;
+    #[cfg(target_pointer_width = "32")]
+    return (M::SATP_MODE << 31) | ((asid.0 as usize) << 22) | root_ppn.0;
+}
+
 // 切换地址空间，同时需要提供1.地址空间的详细设置 2.地址空间编号
-// 不一定最后的API就是这样的，留个坑
-pub unsafe fn activate_paged_riscv_sv39(root_ppn: PhysPageNum, asid: AddressSpaceId) {
-    use riscv::register::satp::{self, Mode};
-    satp::set(Mode::Sv39, asid.0 as usize, root_ppn.0);
-    asm!("sfence.vma {}", in(reg) asid.0 as usize);
+// 会刷新这个编号的全部页表缓存，只在处理核启动时切换到内核的地址空间使用；进程的地址空间由跳板切换
+pub unsafe fn activate<M: PageMode>(root_ppn: PhysPageNum, asid: AddressSpaceId) {
+    let satp = satp_bits::<M>(root_ppn, asid);
+    asm!("
+        csrw    satp, {satp}
+        sfence.vma zero, {asid}
+    ", satp = in(reg) satp, asid = in(reg) asid.0 as usize);
+}
+
+// 读出当前的satp寄存器。进入用户态之前保存在上下文中，陷入时由跳板切换回来
+pub fn read_satp() -> usize {
+    riscv::register::satp::read().bits()
+}
+
+// 当前处理核的编号。入口把编号放在tp寄存器中；内核不使用线程局部存储，运行用户程序时tp保存在内核栈上
+pub fn hart_id() -> usize {
+    let ans;
+    unsafe { asm!("mv   {}, tp", out(reg) ans) };
+    ans
+}
+
+// 本处理核逐页刷新的最多页数，超过时刷新整个地址空间编号
+const LOCAL_FLUSH_MAX_PAGES: usize = 64;
+
+// 页表缓存击落服务，每个地址空间一个，同时保存地址空间的编号
+//
+// 处理核切换到地址空间后，它的页表缓存就可能有这个地址空间编号的项；离开这个地址空间也不会清除这些项。
+// 所以这里记录的是用过这个编号的处理核，直到地址空间换了新一代的编号。
+// 切换地址空间时不刷新页表缓存：取消映射或者降低权限后，本处理核逐页刷新，其它用过的处理核由SBI远程刷新同样的区间。
+// 一代之中编号不会分配给别的地址空间，换代时所有处理核都会刷新，所以地址空间释放时不需要刷新
+#[derive(Debug)]
+pub struct TlbShootdown {
+    harts: AtomicUsize, // 按处理核编号的位图
+    asid: spin::Mutex<Option<AsidTag>>, // 第一次切换到这个地址空间时才分配编号
+}
+
+impl TlbShootdown {
+    pub const fn new() -> Self {
+        TlbShootdown { harts: AtomicUsize::new(0), asid: spin::Mutex::new(None) }
+    }
+    // 准备在当前处理核上切换到这个地址空间：记录当前处理核，返回要写入satp寄存器的值，由跳板中的代码切换。
+    // 编号不属于这一代时，先分配新的编号；换代后这个处理核第一次切换时，先刷新它的全部页表缓存
+    pub fn satp<M: PageMode>(&self, manager: &spin::Mutex<AsidManager>, root_ppn: PhysPageNum) -> usize {
+        let hartid = hart_id();
+        let mut tag = self.asid.lock();
+        let mut manager = manager.lock();
+        let asid = match *tag {
+            Some(cur) if manager.is_current(cur) => {
+                self.harts.fetch_or(1 << hartid, Ordering::SeqCst);
+                cur.asid
+            },
+            _ => {
+                let new = manager.allocate();
+                *tag = Some(new);
+                // 之前用过旧编号的处理核都会在换代后刷新，不用再记录
+                self.harts.store(1 << hartid, Ordering::SeqCst);
+                new.asid
+            },
+        };
+        if manager.take_flush(hartid) {
+            // 不指定地址空间编号，刷新全部的项；切换前刷新也有效
+            unsafe { asm!("sfence.vma") };
+        }
+        satp_bits::<M>(root_ppn, asid)
+    }
+    // 页表中从vpn开始的n个页取消了映射、降低了权限或者换了页帧，刷新所有用过这个地址空间的处理核
+    pub fn flush<M: PageMode>(&self, vpn: VirtPageNum, n: usize) {
+        let asid = match *self.asid.lock() {
+            Some(tag) => tag.asid,
+            None => return, // 还没有切换过，没有处理核缓存它的页表项
+        };
+        // 页表的修改要在读出位图之前完成，之后才切换到这个地址空间的处理核能看到新的页表
+        let harts = self.harts.load(Ordering::SeqCst);
+        let this_hart = 1 << hart_id();
+        if harts & this_hart != 0 {
+            flush_local::<M>(asid, vpn, n);
+        }
+        let others = harts & !this_hart;
+        if others != 0 {
+            let start = vpn.0 << M::FRAME_SIZE_BITS;
+            sbi::remote_sfence_vma_asid(others, 0, start, n << M::FRAME_SIZE_BITS, asid.0 as usize)
+                .expect("remote sfence.vma");
+        }
+    }
+    // 只刷新本处理核上从vpn开始的n个页。新增映射后使用，否则可能再次产生页异常
+    pub fn flush_local<M: PageMode>(&self, vpn: VirtPageNum, n: usize) {
+        if let Some(tag) = *self.asid.lock() {
+            flush_local::<M>(tag.asid, vpn, n);
+        }
+    }
+}
+
+fn flush_local<M: PageMode>(asid: AddressSpaceId, vpn: VirtPageNum, n: usize) {
+    if n > LOCAL_FLUSH_MAX_PAGES {
+        unsafe { asm!("sfence.vma zero, {}", in(reg) asid.0 as usize) };
+        return
+    }
+    for i in 0..n {
+        let va = (vpn.0 + i) << M::FRAME_SIZE_BITS;
+        unsafe { asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid.0 as usize) };
+    }
+}
+
+// 把内核镜像按段映射到地址空间的高地址，权限来自链接脚本中各段的边界：
+// 代码段可读可执行，只读数据段只读，数据段和.bss段可读可写；都没有用户态权限
+pub fn map_kernel_sections<M, A>(addr_space: &mut PagedAddrSpace<M, A>) -> Result<(), FrameAllocError>
+where
+    M: PageMode<Flags = Sv39Flags>,
+    A: FrameAllocator + Clone,
+{
+    extern "C" { fn stext(); fn etext(); fn srodata(); fn erodata(); fn sdata(); fn ebss(); }
+    let sections = [
+        (stext as usize, etext as usize, Sv39Flags::R | Sv39Flags::X),
+        (srodata as usize, erodata as usize, Sv39Flags::R),
+        (sdata as usize, ebss as usize, Sv39Flags::R | Sv39Flags::W),
+    ];
+    for &(start, end, flags) in sections.iter() {
+        let va = VirtAddr(start);
+        let n = (end - start) >> M::FRAME_SIZE_BITS; // 链接脚本把各段对齐到页
+        addr_space.allocate_map(va.page_number::<M>(), va.kernel_phys().page_number::<M>(), n, flags)?;
+    }
+    Ok(())
+}
+
+// 把跳板页映射到地址空间的虚拟地址va，可读可执行，没有用户态权限。
+// 跳板页是链接脚本在代码段中对齐到页的一段，内核和进程的地址空间把它映射在同一个地址
+pub fn map_trampoline<M, A>(addr_space: &mut PagedAddrSpace<M, A>, va: VirtAddr) -> Result<(), FrameAllocError>
+where
+    M: PageMode<Flags = Sv39Flags>,
+    A: FrameAllocator + Clone,
+{
+    extern "C" { fn strampoline(); }
+    let ppn = VirtAddr(strampoline as usize).kernel_phys().page_number::<M>();
+    addr_space.allocate_map(va.page_number::<M>(), ppn, 1, Sv39Flags::R | Sv39Flags::X)
+}
+
+// 检查当前的处理核是否支持分页模式M
+//
+// 如果写入的MODE不被支持，写satp寄存器不会产生任何效果；所以写入以后再读出来，就能知道是否支持。
+// 如果支持，写入后分页立即生效，所以这里先建立一个地址空间，把内核所在的1G空间映射到内核的高地址，保证写入后内核还能运行。
+// 检查完毕后恢复原来的satp寄存器
+pub fn probe_page_mode<M, A>(page_mode: M, frame_alloc: A) -> bool 
+where
+    M: PageMode<Flags = Sv39Flags>,
+    A: FrameAllocator + Clone,
+{
+    extern "C" { fn skernel(); }
+    let mut space = match PagedAddrSpace::try_new_in(page_mode, frame_alloc) {
+        Ok(space) => space,
+        Err(_) => return false,
+    };
+    let giga_pages = 512 * 512;
+    let kernel_pa = VirtAddr(skernel as usize).kernel_phys();
+    let base_ppn = PhysPageNum(kernel_pa.page_number::<M>().0 / giga_pages * giga_pages);
+    let base_vpn = base_ppn.addr_begin::<M>().kernel_virt().page_number::<M>();
+    if space.allocate_map(base_vpn, base_ppn, giga_pages, Sv39Flags::R | Sv39Flags::W | Sv39Flags::X).is_err() {
+        return false
+    }
+    let satp = satp_bits::<M>(space.root_page_number(), DEFAULT_ASID);
+    let mut val = satp;
+    unsafe { asm!("
+        csrr    {tmp}, satp
+        csrw    satp, {val}
+        csrrw   {val}, satp, {tmp}
+        sfence.vma
+    ", tmp = out(reg) _, val = inlateout(reg) val) };
+    val == satp
 }
 
 // 自身映射地址空间；虚拟地址等于物理地址
@@ -0,0 +1,238 @@
+//! 进程模块
+//!
+//! 每个进程拥有自己的分页地址空间和用户栈，进程释放时，这些资源一起还给各自的分配器。
+//! 地址空间编号由地址空间编号管理器按代分配，第一次运行时才分配。
+//! 进程的地址空间不映射内核，只有最高的两页是内核的：所有地址空间共用的跳板页，和这个进程的陷入上下文
+//!
+//! 用户栈和.bss段中的整页采用按需分配：创建进程时只记录这些区域，第一次访问产生页异常时，再分配清零的页帧。
+//! 复制进程时，父子进程写时复制地共享用户的页帧
+
+use crate::{mm, loader, executor::{self, AccessKind, UserContext}, uaccess::BadAddress};
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// 用户栈的栈顶和最大页数。栈的下方留出一个不映射的保护页，栈溢出时产生页异常，而不会改写程序的其它数据
+pub const USER_STACK_TOP: usize = 0x80000000;
+const USER_STACK_PAGES: usize = 256;
+const PAGE_SIZE: usize = 0x1000;
+
+// 下一个进程编号；编号0留给fork的子进程返回值，不分配给进程
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+// 按需分配的区域，区域中没有映射的页在第一次访问时分配
+#[derive(Debug, Clone)]
+pub struct LazyArea {
+    pub range: Range<usize>,
+    pub flags: mm::Sv39Flags,
+}
+
+// 进程结束的方式，由执行器交给等待它的进程
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExitStatus {
+    Exited(i32),
+    Panicked { line: u32, col: u32 },
+    // 进程出错，被内核结束
+    Killed,
+}
+
+pub type ProcessAddrSpace<'a> = mm::PagedAddrSpace<mm::DefaultPageMode, &'a mm::DefaultFrameAllocator>;
+
+pub struct Process<'a> {
+    pid: usize,
+    addr_space: ProcessAddrSpace<'a>,
+    asid_manager: &'a spin::Mutex<mm::AsidManager>,
+    tlb: mm::TlbShootdown,
+    trap_context: mm::PhysPageNum,
+    entry: usize,
+    lazy_areas: Vec<LazyArea>,
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    FrameAlloc(mm::FrameAllocError),
+    Unmap(mm::UnmapError),
+    Load(loader::LoadError),
+    // 程序的段占用了用户栈或者保护页的位置
+    StackOverlap,
+}
+
+impl From<mm::FrameAllocError> for ProcessError {
+    fn from(src: mm::FrameAllocError) -> ProcessError {
+        ProcessError::FrameAlloc(src)
+    }
+}
+
+impl From<mm::UnmapError> for ProcessError {
+    fn from(src: mm::UnmapError) -> ProcessError {
+        ProcessError::Unmap(src)
+    }
+}
+
+impl From<loader::LoadError> for ProcessError {
+    fn from(src: loader::LoadError) -> ProcessError {
+        ProcessError::Load(src)
+    }
+}
+
+impl<'a> Process<'a> {
+    // 从ELF文件创建进程：加载程序的段，分配用户栈，映射跳板和陷入上下文
+    pub fn try_new_from_elf(
+        app: &loader::App,
+        frame_alloc: &'a mm::DefaultFrameAllocator,
+        asid_manager: &'a spin::Mutex<mm::AsidManager>,
+    ) -> Result<Self, ProcessError> {
+        let mut addr_space = mm::PagedAddrSpace::try_new_in(mm::DefaultPageMode, frame_alloc)?;
+        let loader::LoadedElf { entry, mut lazy_areas } = app.load(&mut addr_space)?;
+        let stack_bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+        let guard_page = stack_bottom - PAGE_SIZE;
+        if (guard_page..USER_STACK_TOP).step_by(PAGE_SIZE).any(|va| addr_space.translate(mm::VirtAddr(va)).is_some())
+            || lazy_areas.iter().any(|area| area.range.start < USER_STACK_TOP && guard_page < area.range.end) {
+            return Err(ProcessError::StackOverlap)
+        }
+        lazy_areas.push(LazyArea {
+            range: stack_bottom..USER_STACK_TOP,
+            flags: mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::U,
+        });
+        mm::map_trampoline(&mut addr_space, mm::VirtAddr(executor::TRAMPOLINE))?;
+        let trap_context = map_trap_context(&mut addr_space)?;
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        Ok(Process { pid, addr_space, asid_manager, tlb: mm::TlbShootdown::new(), trap_context, entry, lazy_areas })
+    }
+    // 复制进程。子进程写时复制地共享父进程的用户内存，有新的进程编号，第一次运行时分配地址空间编号。
+    // 父进程的用户页去掉了可写位，要刷新用过它的处理核，之后的写入才会产生页异常。
+    // 陷入上下文没有用户态权限，clone_cow会直接共享，子进程换成自己的一页，内容和父进程相同
+    pub fn fork(&mut self) -> Result<Process<'a>, ProcessError> {
+        let (mut addr_space, downgraded) = self.addr_space.clone_cow()?;
+        for range in downgraded {
+            self.tlb.flush::<mm::DefaultPageMode>(range.start, range.end.0 - range.start.0);
+        }
+        addr_space.unmap(mm::VirtAddr(executor::TRAP_CONTEXT).page_number::<mm::DefaultPageMode>(), 1)?;
+        let trap_context = map_trap_context(&mut addr_space)?;
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+        let mut child = Process {
+            pid, addr_space,
+            asid_manager: self.asid_manager,
+            tlb: mm::TlbShootdown::new(),
+            trap_context,
+            entry: self.entry,
+            lazy_areas: self.lazy_areas.clone(),
+        };
+        *child.trap_context_mut() = self.trap_context().clone();
+        Ok(child)
+    }
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+    // 程序的入口地址
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+    pub fn user_stack_top(&self) -> usize {
+        USER_STACK_TOP
+    }
+    // 在当前处理核上运行这个进程时，写入satp寄存器的值
+    pub fn satp(&self) -> usize {
+        self.tlb.satp::<mm::DefaultPageMode>(self.asid_manager, self.addr_space.root_page_number())
+    }
+    // 陷入上下文，内核通过线性映射访问
+    pub fn trap_context(&self) -> &UserContext {
+        // note(unsafe): 页帧由地址空间拥有，只有这个进程使用
+        unsafe { &*(self.trap_context.addr_begin::<mm::DefaultPageMode>().kernel_virt().0 as *const UserContext) }
+    }
+    pub fn trap_context_mut(&mut self) -> &mut UserContext {
+        // note(unsafe): 同上
+        unsafe { &mut *(self.trap_context.addr_begin::<mm::DefaultPageMode>().kernel_virt().0 as *mut UserContext) }
+    }
+    // 处理用户程序的页异常。写入写时复制的页时，为进程准备可写的页帧；
+    // 如果出错的地址位于按需分配的区域，而且区域允许这种访问，分配一个清零的页帧映射到这一页。
+    // 处理成功返回true，用户程序可以重新执行出错的指令；否则返回false
+    pub fn handle_page_fault(&mut self, addr: usize, access: AccessKind) -> bool {
+        let vpn = mm::VirtAddr(addr).page_number::<mm::DefaultPageMode>();
+        if access == AccessKind::Store {
+            match self.addr_space.handle_cow_fault(mm::VirtAddr(addr)) {
+                Ok(true) => {
+                    // 页帧可能换成了新复制的，别的处理核缓存的旧页帧也要刷新
+                    self.tlb.flush::<mm::DefaultPageMode>(vpn, 1);
+                    return true
+                },
+                Ok(false) => {},
+                Err(_) => return false,
+            }
+        }
+        let area = match self.lazy_areas.iter().find(|area| area.range.contains(&addr)) {
+            Some(area) => area,
+            None => return false,
+        };
+        let allowed = match access {
+            AccessKind::Load => mm::Sv39Flags::R,
+            AccessKind::Store => mm::Sv39Flags::W,
+            AccessKind::Execute => mm::Sv39Flags::X,
+        };
+        if !area.flags.contains(allowed) {
+            return false
+        }
+        let page = addr & !(PAGE_SIZE - 1);
+        if let Some((_, flags, _)) = self.addr_space.translate(mm::VirtAddr(page)) {
+            if !flags.contains(allowed) {
+                return false // 这一页已经映射，是权限不允许的访问
+            }
+            // 别的处理核映射了这一页，本处理核的页表缓存还是旧的；刷新后重新执行
+            self.tlb.flush_local::<mm::DefaultPageMode>(vpn, 1);
+            return true
+        }
+        if self.addr_space.allocate_map_frames(vpn, 1, area.flags).is_err() {
+            return false
+        }
+        // 新增的映射只需要刷新本处理核，别的处理核缓存了旧的项时，由上面的分支处理
+        self.tlb.flush_local::<mm::DefaultPageMode>(vpn, 1);
+        true
+    }
+    // 用户地址对应的物理地址。内核复制用户内存时，先用prepare_user_access检查权限，再通过线性映射访问
+    pub fn translate(&self, va: usize) -> Option<mm::PhysAddr> {
+        self.addr_space.translate(mm::VirtAddr(va)).map(|(pa, _, _)| pa)
+    }
+    // 准备访问用户地址空间中的[va, va + len)：每一页都要有用户态权限，而且允许这种访问。
+    // 没有映射的按需分配的页先分配出来，要写入的写时复制页先复制，之后复制用户内存时就不会产生页异常
+    pub fn prepare_user_access(&mut self, va: usize, len: usize, access: AccessKind) -> Result<(), BadAddress> {
+        let end = va.checked_add(len).ok_or(BadAddress(va))?;
+        let needed = mm::Sv39Flags::U | match access {
+            AccessKind::Store => mm::Sv39Flags::W,
+            _ => mm::Sv39Flags::R,
+        };
+        let allowed = |space: &ProcessAddrSpace, cur| matches!(
+            space.translate(mm::VirtAddr(cur)), Some((_, flags, _)) if flags.contains(needed)
+        );
+        let mut cur = va;
+        while cur < end {
+            if !allowed(&self.addr_space, cur) && !(self.handle_page_fault(cur, access) && allowed(&self.addr_space, cur)) {
+                return Err(BadAddress(cur))
+            }
+            cur = (cur & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+        }
+        Ok(())
+    }
+}
+
+// 分配进程的陷入上下文页，映射在跳板的下方，没有用户态权限，返回它的物理页号
+fn map_trap_context(addr_space: &mut ProcessAddrSpace) -> Result<mm::PhysPageNum, mm::FrameAllocError> {
+    let vpn = mm::VirtAddr(executor::TRAP_CONTEXT).page_number::<mm::DefaultPageMode>();
+    addr_space.allocate_map_frames(vpn, 1, mm::Sv39Flags::R | mm::Sv39Flags::W)
+}
+
+// 进程的地址空间中，没有用户态权限的页只有跳板和陷入上下文，不映射内核镜像；所有的页都不能同时可写和可执行
+pub(crate) fn test_isolation(app: &loader::App, frame_alloc: &mm::DefaultFrameAllocator, asid_manager: &spin::Mutex<mm::AsidManager>) {
+    let process = Process::try_new_from_elf(app, frame_alloc, asid_manager).expect("load app");
+    let space = &process.addr_space;
+    let (r, w, x, u) = (mm::Sv39Flags::R, mm::Sv39Flags::W, mm::Sv39Flags::X, mm::Sv39Flags::U);
+    assert!(space.walk().all(|(_, _, flags, _)| !flags.contains(w | x)), "no writable and executable page");
+    assert_eq!(space.walk().filter(|(_, _, flags, _)| !flags.contains(u)).count(), 2, "only two kernel pages");
+    extern "C" { fn strampoline(); fn skernel(); }
+    let trampoline = mm::VirtAddr(strampoline as usize).kernel_phys();
+    assert_eq!(space.translate(mm::VirtAddr(executor::TRAMPOLINE)).map(|(pa, flags, _)| (pa, flags)),
+        Some((trampoline, r | x | mm::Sv39Flags::V)), "trampoline page");
+    assert_eq!(space.translate(mm::VirtAddr(executor::TRAP_CONTEXT)).map(|(_, flags, _)| flags),
+        Some(r | w | mm::Sv39Flags::V), "trap context page");
+    assert_eq!(space.translate(mm::VirtAddr(skernel as usize)), None, "kernel image is not mapped");
+    println!("[kernel-isolation-test] Process isolation test passed");
+}
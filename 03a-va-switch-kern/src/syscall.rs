@@ -1,14 +1,24 @@
+use crate::{process::Process, loader::App, executor::AccessKind, uaccess::{BadAddress, UserSlice, UserPtr}};
+use alloc::string::String;
+
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+const FUNCTION_PROCESS_FORK: usize = 0x19260817;
+const FUNCTION_PROCESS_RUN: usize = 0x5201315;
+const FUNCTION_PROCESS_APP_NAME: usize = 0x5201316;
 
 const MODULE_TEST_INTERFACE: usize = 0x233666;
 const FUNCTION_TEST_WRITE: usize = 0x666233;
+const FUNCTION_TEST_READ: usize = 0x666666;
 
 pub enum SyscallOperation {
     Return(SyscallResult),
     Terminate(i32),
-    UserPanic(Option<&'static str>, u32, u32, Option<&'static str>),
+    UserPanic(Option<String>, u32, u32, Option<String>),
+    Fork, // 复制当前进程，需要由执行器创建新的运行时
+    // 运行编号为.0的程序直到它结束，把结束信息写到.1，需要由执行器创建新的运行时
+    Run(usize, UserPtr<[usize; 4]>),
 }
 
 pub struct SyscallResult {
@@ -16,51 +26,126 @@ pub struct SyscallResult {
     pub extra: usize,
 }
 
-pub fn syscall(module: usize, function: usize, args: [usize; 6]) -> SyscallOperation {
-    match module {
-        MODULE_PROCESS => do_process(function, args),
-        MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]]),
-        _ => panic!("Unknown syscall, module: {}, function: {}, args: {:?}", module, function, args),
+// 所有内核和用户库共用的错误码表
+mod error_code {
+    #![allow(dead_code)] // 这个内核只返回其中一部分错误
+    include!("../../syscall_error_code.rs");
+}
+
+// 系统调用的错误。出错时返回给用户的code是错误码，extra是错误的附加信息；成功时code为0。
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SyscallError {
+    // 不支持的模块或者功能
+    NotSupported,
+    // 不支持的文件描述符，附加信息是这个描述符
+    BadFd(usize),
+    // 用户传来的地址不合法，附加信息是出错的地址
+    BadAddress(usize),
+    // 参数不合法，比如字符串不是UTF-8编码
+    InvalidArgument,
+    // 内存或者地址空间编号不足
+    OutOfMemory,
+    // 没有这个名字或者编号的程序
+    NotFound,
+}
+
+impl From<SyscallError> for SyscallResult {
+    fn from(src: SyscallError) -> SyscallResult {
+        let (code, extra) = match src {
+            SyscallError::NotSupported => (error_code::NOT_SUPPORTED, 0),
+            SyscallError::BadFd(fd) => (error_code::BAD_FD, fd),
+            SyscallError::BadAddress(addr) => (error_code::BAD_ADDRESS, addr),
+            SyscallError::InvalidArgument => (error_code::INVALID_ARGUMENT, 0),
+            SyscallError::OutOfMemory => (error_code::OUT_OF_MEMORY, 0),
+            SyscallError::NotFound => (error_code::NOT_FOUND, 0),
+        };
+        SyscallResult { code, extra }
     }
 }
 
-fn do_process(function: usize, args: [usize; 6]) -> SyscallOperation {
+impl From<BadAddress> for SyscallError {
+    fn from(src: BadAddress) -> SyscallError {
+        SyscallError::BadAddress(src.0)
+    }
+}
+
+// 用户传来的指针指向进程的地址空间，需要通过uaccess模块在process中读写；apps是内核中的所有程序
+// 用户的请求有误时返回错误，而不是让内核崩溃
+pub fn syscall(module: usize, function: usize, args: [usize; 6], process: &mut Process, apps: &[App]) -> SyscallOperation {
+    let ans = match module {
+        MODULE_PROCESS => do_process(function, args, process, apps),
+        MODULE_TEST_INTERFACE => do_test_interface(function, [args[0], args[1], args[2]], process),
+        _ => Err(SyscallError::NotSupported),
+    };
+    ans.unwrap_or_else(|e| SyscallOperation::Return(e.into()))
+}
+
+fn do_process(function: usize, args: [usize; 6], process: &mut Process, apps: &[App]) -> Result<SyscallOperation, SyscallError> {
     match function {
-        FUNCTION_PROCESS_EXIT => SyscallOperation::Terminate(args[0] as i32),
+        FUNCTION_PROCESS_EXIT => Ok(SyscallOperation::Terminate(args[0] as i32)),
+        FUNCTION_PROCESS_FORK => Ok(SyscallOperation::Fork),
         FUNCTION_PROCESS_PANIC => { // [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
             let [line, col, f_buf, f_len, m_buf, m_len] = args;
-            let file_name = if f_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(f_buf as *const u8, f_len) };
-                Some(core::str::from_utf8(slice).unwrap())
-            };
-            let msg = if m_buf == 0 {
-                None
-            } else {
-                let slice = unsafe { core::slice::from_raw_parts(m_buf as *const u8, m_len) };
-                Some(core::str::from_utf8(slice).unwrap())
+            // 地址不合法时，当作没有提供这一项
+            let mut read_str = |buf, len| {
+                let slice = UserSlice::new(buf, len);
+                if slice.is_null() {
+                    return None
+                }
+                slice.read_to_vec(process).ok().map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
             };
-            SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg)
+            let file_name = read_str(f_buf, f_len);
+            let msg = read_str(m_buf, m_len);
+            Ok(SyscallOperation::UserPanic(file_name, line as u32, col as u32, msg))
+        },
+        FUNCTION_PROCESS_APP_NAME => { // index: usize, buffer: &mut [u8]，返回第index个程序名字的长度
+            let [index, buf, len] = [args[0], args[1], args[2]];
+            let name = apps.get(index).ok_or(SyscallError::NotFound)?.name().as_bytes();
+            // 缓冲区不够长时只写入名字的开头，用户可以根据返回的长度重新读取
+            let n = core::cmp::min(name.len(), len);
+            UserSlice::new(buf, n).write_from(process, &name[..n])?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: name.len() }))
+        },
+        FUNCTION_PROCESS_RUN => { // name: &str, info: *mut [usize; 4]，返回子进程的编号
+            let [buf, len, info] = [args[0], args[1], args[2]];
+            let name = UserSlice::new(buf, len).read_to_vec(process)?;
+            let name = core::str::from_utf8(&name).map_err(|_| SyscallError::InvalidArgument)?;
+            let index = apps.iter().position(|app| app.name() == name).ok_or(SyscallError::NotFound)?;
+            Ok(SyscallOperation::Run(index, UserPtr::new(info)))
         },
-        _ => panic!("Unknown syscall PROCESS, function: {}, args: {:?}", function, args),
+        _ => Err(SyscallError::NotSupported),
     }
 }
 
-fn do_test_interface(function: usize, args: [usize; 3]) -> SyscallOperation {
+fn do_test_interface(function: usize, args: [usize; 3], process: &mut Process) -> Result<SyscallOperation, SyscallError> {
     match function {
         FUNCTION_TEST_WRITE => { // fd: usize, buffer: &[u8] fd, buffer.as_ptr() as usize, buffer.len()
             const STDOUT: usize = 1;
             let [fd, buf, len] = args;
-            if fd == STDOUT {
-                let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
-                let str = core::str::from_utf8(slice).unwrap();
-                print!("{}", str);
-                SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize })
-            } else {
-                panic!("Unsupported fd {}", fd);
+            if fd != STDOUT {
+                return Err(SyscallError::BadFd(fd));
+            }
+            let bytes = UserSlice::new(buf, len).read_to_vec(process)?;
+            print!("{}", String::from_utf8_lossy(&bytes));
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: len as usize }))
+        },
+        FUNCTION_TEST_READ => { // fd: usize, buffer: &mut [u8]，没有输入时等待用户输入一行，返回读到的字节数
+            const STDIN: usize = 0;
+            let [fd, buf, len] = args;
+            if fd != STDIN {
+                return Err(SyscallError::BadFd(fd));
+            }
+            let mut kernel_buf = [0u8; 64];
+            let want = core::cmp::min(len, kernel_buf.len());
+            if want == 0 {
+                return Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: 0 }));
             }
+            // 先检查缓冲区，地址不合法时不会读走用户的输入
+            process.prepare_user_access(buf, want, AccessKind::Store)?;
+            let n = crate::console::read(&mut kernel_buf[..want]);
+            UserSlice::new(buf, n).write_from(process, &kernel_buf[..n])?;
+            Ok(SyscallOperation::Return(SyscallResult { code: 0, extra: n }))
         },
-        _ => panic!("Unknown syscall TEST_INTERFACE,function: {}, arg: {:?}", function, args),
+        _ => Err(SyscallError::NotSupported),
     }
 }
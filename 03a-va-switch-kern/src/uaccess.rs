@@ -0,0 +1,121 @@
+//! 用户内存访问模块
+//!
+//! 系统调用的参数里，用户传来的指针只是一个数字，不能直接当作内核的引用使用。
+//! 这里先用软件遍历进程的页表，检查要访问的范围都有用户态的权限，必要时分配按需分配的页、复制写时复制的页；
+//! 然后逐页翻译出物理地址，通过内核的线性映射复制。
+//!
+//! 进程的地址空间不映射内核，内核也不映射用户的内存，所以复制时不切换地址空间，也不会产生页异常
+
+use crate::{executor::AccessKind, process::Process};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+// 用户传来的地址不合法：没有映射，或者没有用户态权限。包含出错的用户地址
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BadAddress(pub usize);
+
+// 用户地址空间中的一段字节
+#[derive(Copy, Clone, Debug)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Self {
+        UserSlice { addr, len }
+    }
+    // 用户传来空指针，通常表示没有这个参数
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+    // 把这段内容复制到内核
+    pub fn read_to_vec(&self, process: &mut Process) -> Result<Vec<u8>, BadAddress> {
+        let mut ans = alloc::vec![0u8; self.len];
+        copy_from_user(process, &mut ans, self.addr)?;
+        Ok(ans)
+    }
+    // 把src写到这段内存的开头，src不能比这段内存长
+    pub fn write_from(&self, process: &mut Process, src: &[u8]) -> Result<(), BadAddress> {
+        if src.len() > self.len {
+            return Err(BadAddress(self.addr.wrapping_add(self.len)))
+        }
+        copy_to_user(process, self.addr, src)
+    }
+}
+
+// 指向用户地址空间中一个T类型值的指针
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+#[allow(unused)] // 目前的系统调用只写入用户的值
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        UserPtr { addr, _marker: PhantomData }
+    }
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+    pub fn read(&self, process: &mut Process) -> Result<T, BadAddress> {
+        self.check_align()?;
+        let mut ans = core::mem::MaybeUninit::<T>::uninit();
+        // note(unsafe): 复制成功后，ans的每个字节都已经写入；T是Copy的，按字节复制得到的值合法由调用者约定
+        let dst = unsafe { core::slice::from_raw_parts_mut(ans.as_mut_ptr() as *mut u8, core::mem::size_of::<T>()) };
+        copy_from_user(process, dst, self.addr)?;
+        Ok(unsafe { ans.assume_init() })
+    }
+    pub fn write(&self, process: &mut Process, value: T) -> Result<(), BadAddress> {
+        self.check_align()?;
+        let src = unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, core::mem::size_of::<T>()) };
+        copy_to_user(process, self.addr, src)
+    }
+    fn check_align(&self) -> Result<(), BadAddress> {
+        if self.addr % core::mem::align_of::<T>() != 0 {
+            return Err(BadAddress(self.addr))
+        }
+        Ok(())
+    }
+}
+
+const PAGE_SIZE: usize = 0x1000;
+
+// 从用户地址src复制dst.len()个字节到dst
+pub fn copy_from_user(process: &mut Process, dst: &mut [u8], src: usize) -> Result<(), BadAddress> {
+    process.prepare_user_access(src, dst.len(), AccessKind::Load)?;
+    for_each_user_page(process, src, dst.len(), |user, offset, len| {
+        // note(unsafe): 用户的范围检查过了，页帧通过线性映射访问
+        unsafe { core::ptr::copy_nonoverlapping(user as *const u8, dst[offset..].as_mut_ptr(), len) }
+    })
+}
+
+// 把src复制到用户地址dst
+pub fn copy_to_user(process: &mut Process, dst: usize, src: &[u8]) -> Result<(), BadAddress> {
+    process.prepare_user_access(dst, src.len(), AccessKind::Store)?;
+    for_each_user_page(process, dst, src.len(), |user, offset, len| {
+        // note(unsafe): 同上
+        unsafe { core::ptr::copy_nonoverlapping(src[offset..].as_ptr(), user, len) }
+    })
+}
+
+// 按页访问用户地址空间中的[va, va + len)。f的参数为这一页中的部分在线性映射中的地址、相对va的偏移和长度
+fn for_each_user_page(process: &Process, va: usize, len: usize, mut f: impl FnMut(*mut u8, usize, usize)) -> Result<(), BadAddress> {
+    let mut cur = va;
+    while cur < va + len {
+        let next = core::cmp::min((cur & !(PAGE_SIZE - 1)) + PAGE_SIZE, va + len);
+        let pa = process.translate(cur).ok_or(BadAddress(cur))?;
+        f(pa.kernel_virt().0 as *mut u8, cur - va, next - cur);
+        cur = next;
+    }
+    Ok(())
+}
@@ -0,0 +1,20 @@
+// 系统调用的错误码表。内核和用户库都用include!引入这个文件，两边的错误码不会不一致。
+// 系统调用返回时，a0是错误码，a1是附加信息；错误码为0表示成功，这时a1是返回值。
+// 每个内核只返回它用得到的错误，用户库遇到不认识的错误码时当作未知的错误。
+//
+// 错误码  名字               附加信息        含义
+// 1       NOT_SUPPORTED      无             不支持的模块或者功能
+// 2       BAD_FD             文件描述符      不支持的文件描述符
+// 3       BAD_ADDRESS        出错的地址      用户传来的地址不合法
+// 4       INVALID_ARGUMENT   无             参数不合法，比如字符串不是UTF-8编码
+// 5       OUT_OF_MEMORY      无             内存或者地址空间编号不足
+// 6       NOT_FOUND          无             没有这个名字或者编号的程序，或者没有可以等待的子任务
+// 7       BROKEN_PIPE        无             向读端已经全部关闭的管道写入
+
+pub const NOT_SUPPORTED: usize = 1;
+pub const BAD_FD: usize = 2;
+pub const BAD_ADDRESS: usize = 3;
+pub const INVALID_ARGUMENT: usize = 4;
+pub const OUT_OF_MEMORY: usize = 5;
+pub const NOT_FOUND: usize = 6;
+pub const BROKEN_PIPE: usize = 7;